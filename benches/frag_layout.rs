@@ -0,0 +1,446 @@
+//Memory and runtime of the fragment representation on simulated long reads, for the current
+//parallel-array Frag and the hash-map Frag it replaced (module baseline). Run with
+//  cargo bench --bench frag_layout
+extern crate flopp;
+use flopp::types_structs::{build_frag, Frag, HapNode, PackedSeq};
+use flopp::utils_frags;
+use fxhash::{FxHashMap, FxHashSet};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+struct CountingAlloc;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+const NUM_SNPS: usize = 50000;
+const NUM_FRAGS: usize = 20000;
+const READ_LEN: usize = 10000;
+//About one SNP every 100 bases.
+const SNPS_PER_READ: usize = 100;
+
+//The hash-map fragment layout the parallel arrays replaced, with the helpers the bench times copied
+//from it.
+mod baseline {
+    use fxhash::{FxHashMap, FxHashSet};
+    use std::hash::{Hash, Hasher};
+
+    #[allow(dead_code)]
+    pub struct Frag {
+        pub id: String,
+        pub counter_id: usize,
+        pub seq_dict: FxHashMap<usize, usize>,
+        pub qual_dict: FxHashMap<usize, u8>,
+        pub positions: FxHashSet<usize>,
+        pub first_position: usize,
+        pub last_position: usize,
+        pub seq_string: Vec<Vec<u8>>,
+        pub qual_string: Vec<Vec<u8>>,
+        pub is_paired: bool,
+        pub snp_pos_to_seq_pos: FxHashMap<usize, (u8, usize)>,
+    }
+
+    impl Hash for Frag {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.counter_id.hash(state);
+        }
+    }
+
+    impl PartialEq for Frag {
+        fn eq(&self, other: &Self) -> bool {
+            self.counter_id == other.counter_id
+        }
+    }
+
+    impl Eq for Frag {}
+
+    pub fn build_frag(id: String, counter_id: usize, is_paired: bool) -> Frag {
+        Frag {
+            id: id,
+            counter_id: counter_id,
+            seq_dict: FxHashMap::default(),
+            qual_dict: FxHashMap::default(),
+            positions: FxHashSet::default(),
+            first_position: usize::MAX,
+            last_position: 0,
+            seq_string: vec![vec![]; 2],
+            qual_string: vec![vec![]; 2],
+            is_paired: is_paired,
+            snp_pos_to_seq_pos: FxHashMap::default(),
+        }
+    }
+
+    pub fn add_call(frag: &mut Frag, snp: usize, allele: usize, qual: u8, mate: u8, seq_offset: usize) {
+        frag.seq_dict.insert(snp, allele);
+        frag.qual_dict.insert(snp, qual);
+        frag.positions.insert(snp);
+        frag.snp_pos_to_seq_pos.insert(snp, (mate, seq_offset));
+        frag.first_position = frag.first_position.min(snp);
+        frag.last_position = frag.last_position.max(snp);
+    }
+
+    pub fn set_to_seq_dict(frag_set: &FxHashSet<&Frag>) -> FxHashMap<usize, FxHashMap<usize, usize>> {
+        let mut hap_map = FxHashMap::default();
+        for frag in frag_set.iter() {
+            for pos in frag.positions.iter() {
+                let var_at_pos = frag.seq_dict.get(pos).unwrap();
+                let sites = hap_map.entry(*pos).or_insert(FxHashMap::default());
+                let site_counter = sites.entry(*var_at_pos).or_insert(0);
+                *site_counter += 1;
+            }
+        }
+        hap_map
+    }
+
+    pub fn distance_read_haplo(
+        r1: &Frag,
+        hap: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    ) -> (usize, usize) {
+        let mut diff = 0;
+        let mut same = 0;
+        for pos in r1.positions.iter() {
+            if !hap.contains_key(pos) {
+                continue;
+            }
+            let frag_var = r1.seq_dict.get(pos).unwrap();
+            let consensus_var = hap
+                .get(pos)
+                .unwrap()
+                .iter()
+                .max_by_key(|entry| entry.1)
+                .unwrap()
+                .0;
+            if *frag_var == *consensus_var {
+                same += 1;
+            } else {
+                let frag_var_count = hap.get(pos).unwrap().get(frag_var);
+                if let Some(count) = frag_var_count {
+                    if count == hap.get(pos).unwrap().get(consensus_var).unwrap() {
+                        same += 1;
+                        continue;
+                    }
+                }
+                diff += 1;
+            }
+        }
+        (same, diff)
+    }
+
+    pub fn distance_read_haplo_epsilon_empty(
+        r: &Frag,
+        hap: &FxHashMap<usize, FxHashMap<usize, usize>>,
+        epsilon: f64,
+    ) -> (f64, f64) {
+        let mut diff = 0.0;
+        let mut same = 0.0;
+        for pos in r.positions.iter() {
+            if !hap.contains_key(pos) {
+                diff += epsilon;
+                continue;
+            }
+            let frag_var = r.seq_dict.get(pos).unwrap();
+            let consensus_var = hap
+                .get(pos)
+                .unwrap()
+                .iter()
+                .max_by_key(|entry| entry.1)
+                .unwrap()
+                .0;
+            if *frag_var == *consensus_var {
+                same += 1.0;
+            } else {
+                let frag_var_count = hap.get(pos).unwrap().get(frag_var);
+                if let Some(count) = frag_var_count {
+                    if count == hap.get(pos).unwrap().get(consensus_var).unwrap() {
+                        same += 1.0;
+                        continue;
+                    }
+                }
+                diff += 1.0;
+            }
+        }
+        (same, diff)
+    }
+
+    pub fn check_overlap(r1: &Frag, r2: &Frag) -> bool {
+        if r1.last_position < r2.first_position {
+            return false;
+        }
+        if r2.last_position < r1.first_position {
+            return false;
+        }
+        let t: Vec<_> = r1.positions.intersection(&r2.positions).collect();
+        t.len() != 0
+    }
+
+    //The allele counts and coverage HapNode::new computed.
+    pub fn hap_node(frag_set: &FxHashSet<&Frag>) -> (FxHashMap<usize, FxHashMap<usize, usize>>, f64) {
+        let mut hap_map = FxHashMap::default();
+        for frag in frag_set.iter() {
+            for pos in frag.positions.iter() {
+                let var_at_pos = frag.seq_dict.get(pos).unwrap();
+                let sites = hap_map.entry(*pos).or_insert(FxHashMap::default());
+                let site_counter = sites.entry(*var_at_pos).or_insert(0);
+                *site_counter += 1;
+            }
+        }
+        let mut allele_cov_list = vec![];
+        for values1 in hap_map.values() {
+            for allele_count in values1.values() {
+                allele_cov_list.push(*allele_count as f64);
+            }
+        }
+        allele_cov_list.sort_by(|a: &f64, b| a.partial_cmp(b).unwrap());
+        let cov = *allele_cov_list.last().unwrap_or(&0.);
+        (hap_map, cov)
+    }
+}
+
+//Allele calls (SNP, allele, quality, read offset) and bases of one simulated read.
+struct SimRead {
+    calls: Vec<(usize, usize, u8, usize)>,
+    seq: Vec<u8>,
+}
+
+fn simulate_reads() -> Vec<SimRead> {
+    let mut rng = Pcg64::seed_from_u64(0);
+    let bases = b"ACGT";
+    let mut reads = vec![];
+    for i in 0..NUM_FRAGS {
+        let start = rng.gen_range(1..NUM_SNPS - SNPS_PER_READ);
+        let calls = (0..SNPS_PER_READ)
+            .map(|j| {
+                let allele = if rng.gen_bool(0.05) { 1 } else { i % 2 };
+                (start + j, allele, rng.gen_range(10..40), j * 100)
+            })
+            .collect();
+        let seq = (0..READ_LEN).map(|_| bases[rng.gen_range(0..4)]).collect();
+        reads.push(SimRead { calls, seq });
+    }
+    reads
+}
+
+fn heap() -> usize {
+    ALLOCATED.load(Ordering::Relaxed)
+}
+
+fn print_heap(calls_bytes: usize, seq_bytes: usize, num_calls: usize, build_time: f64) {
+    println!(
+        "  build: {:.3}s, heap {:.1} MiB ({:.1} bytes per call, {:.1} MiB of sequences)",
+        build_time,
+        (calls_bytes + seq_bytes) as f64 / (1 << 20) as f64,
+        calls_bytes as f64 / num_calls as f64,
+        seq_bytes as f64 / (1 << 20) as f64
+    );
+}
+
+fn bench_current(reads: &[SimRead]) {
+    println!("parallel arrays (types_structs::Frag)");
+    let heap_before = heap();
+    let start = Instant::now();
+    let mut frags = vec![];
+    for (i, read) in reads.iter().enumerate() {
+        let mut frag = build_frag(format!("read{}", i), i, false, 0);
+        for (snp, allele, qual, seq_offset) in read.calls.iter() {
+            frag.add_call(*snp, *allele, *qual, 0, *seq_offset);
+        }
+        frags.push(frag);
+    }
+    let calls_bytes = heap() - heap_before;
+    for (frag, read) in frags.iter_mut().zip(reads.iter()) {
+        frag.seq_string[0] = PackedSeq::from_bytes(&read.seq);
+        frag.qual_string[0] = vec![b'5'; READ_LEN];
+    }
+    let build_time = start.elapsed().as_secs_f64();
+    let num_calls: usize = frags.iter().map(|frag| frag.num_calls()).sum();
+    print_heap(calls_bytes, heap() - heap_before - calls_bytes, num_calls, build_time);
+
+    let halves: Vec<FxHashSet<&Frag>> = (0..2)
+        .map(|k| frags.iter().filter(|frag| frag.counter_id % 2 == k).collect())
+        .collect();
+
+    let start = Instant::now();
+    let haps: Vec<_> = halves.iter().map(|set| utils_frags::set_to_seq_dict(set)).collect();
+    println!("  set_to_seq_dict: {:.3}s", start.elapsed().as_secs_f64());
+
+    let start = Instant::now();
+    let mut total = (0, 0);
+    for frag in frags.iter() {
+        for hap in haps.iter() {
+            let (same, diff) = utils_frags::distance_read_haplo(frag, hap);
+            total.0 += same;
+            total.1 += diff;
+        }
+    }
+    println!(
+        "  distance_read_haplo: {:.3}s ({} same, {} diff)",
+        start.elapsed().as_secs_f64(),
+        total.0,
+        total.1
+    );
+
+    let start = Instant::now();
+    let mut total = (0.0, 0.0);
+    for frag in frags.iter() {
+        for hap in haps.iter() {
+            let (same, diff) = utils_frags::distance_read_haplo_epsilon_empty(frag, hap, 0.04, false);
+            total.0 += same;
+            total.1 += diff;
+        }
+    }
+    println!(
+        "  distance_read_haplo_epsilon_empty: {:.3}s ({:.0} same, {:.0} diff)",
+        start.elapsed().as_secs_f64(),
+        total.0,
+        total.1
+    );
+
+    let start = Instant::now();
+    let nodes: Vec<HapNode> = halves
+        .into_iter()
+        .map(|set| HapNode::new(set, (1, NUM_SNPS)))
+        .collect();
+    println!(
+        "  HapNode::new: {:.3}s (coverage {}, {})",
+        start.elapsed().as_secs_f64(),
+        nodes[0].cov(),
+        nodes[1].cov()
+    );
+
+    let start = Instant::now();
+    let mut overlaps = 0;
+    for i in 0..frags.len().min(2000) {
+        for frag in frags.iter() {
+            if utils_frags::check_overlap(&frags[i], frag) {
+                overlaps += 1;
+            }
+        }
+    }
+    println!(
+        "  check_overlap: {:.3}s ({} overlaps)",
+        start.elapsed().as_secs_f64(),
+        overlaps
+    );
+}
+
+fn bench_baseline(reads: &[SimRead]) {
+    println!("hash maps (baseline::Frag)");
+    let heap_before = heap();
+    let start = Instant::now();
+    let mut frags = vec![];
+    for (i, read) in reads.iter().enumerate() {
+        let mut frag = baseline::build_frag(format!("read{}", i), i, false);
+        for (snp, allele, qual, seq_offset) in read.calls.iter() {
+            baseline::add_call(&mut frag, *snp, *allele, *qual, 0, *seq_offset);
+        }
+        frags.push(frag);
+    }
+    let calls_bytes = heap() - heap_before;
+    for (frag, read) in frags.iter_mut().zip(reads.iter()) {
+        frag.seq_string[0] = read.seq.clone();
+        frag.qual_string[0] = vec![b'5'; READ_LEN];
+    }
+    let build_time = start.elapsed().as_secs_f64();
+    let num_calls: usize = frags.iter().map(|frag| frag.positions.len()).sum();
+    print_heap(calls_bytes, heap() - heap_before - calls_bytes, num_calls, build_time);
+
+    let halves: Vec<FxHashSet<&baseline::Frag>> = (0..2)
+        .map(|k| frags.iter().filter(|frag| frag.counter_id % 2 == k).collect())
+        .collect();
+
+    let start = Instant::now();
+    let haps: Vec<FxHashMap<usize, FxHashMap<usize, usize>>> =
+        halves.iter().map(|set| baseline::set_to_seq_dict(set)).collect();
+    println!("  set_to_seq_dict: {:.3}s", start.elapsed().as_secs_f64());
+
+    let start = Instant::now();
+    let mut total = (0, 0);
+    for frag in frags.iter() {
+        for hap in haps.iter() {
+            let (same, diff) = baseline::distance_read_haplo(frag, hap);
+            total.0 += same;
+            total.1 += diff;
+        }
+    }
+    println!(
+        "  distance_read_haplo: {:.3}s ({} same, {} diff)",
+        start.elapsed().as_secs_f64(),
+        total.0,
+        total.1
+    );
+
+    let start = Instant::now();
+    let mut total = (0.0, 0.0);
+    for frag in frags.iter() {
+        for hap in haps.iter() {
+            let (same, diff) = baseline::distance_read_haplo_epsilon_empty(frag, hap, 0.04);
+            total.0 += same;
+            total.1 += diff;
+        }
+    }
+    println!(
+        "  distance_read_haplo_epsilon_empty: {:.3}s ({:.0} same, {:.0} diff)",
+        start.elapsed().as_secs_f64(),
+        total.0,
+        total.1
+    );
+
+    let start = Instant::now();
+    let nodes: Vec<_> = halves.iter().map(|set| baseline::hap_node(set)).collect();
+    println!(
+        "  HapNode::new: {:.3}s (coverage {}, {})",
+        start.elapsed().as_secs_f64(),
+        nodes[0].1,
+        nodes[1].1
+    );
+
+    let start = Instant::now();
+    let mut overlaps = 0;
+    for i in 0..frags.len().min(2000) {
+        for frag in frags.iter() {
+            if baseline::check_overlap(&frags[i], frag) {
+                overlaps += 1;
+            }
+        }
+    }
+    println!(
+        "  check_overlap: {:.3}s ({} overlaps)",
+        start.elapsed().as_secs_f64(),
+        overlaps
+    );
+}
+
+fn main() {
+    let reads = simulate_reads();
+    println!(
+        "{} fragments, {} allele calls, {} bases per read",
+        reads.len(),
+        reads.len() * SNPS_PER_READ,
+        READ_LEN
+    );
+    bench_baseline(&reads);
+    bench_current(&reads);
+}
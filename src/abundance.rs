@@ -0,0 +1,236 @@
+use crate::types_structs::{Frag, HaplotigAbundance};
+use crate::utils_frags;
+use fxhash::{FxHashMap, FxHashSet};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
+use rayon::prelude::*;
+
+//Relative abundance of each haplotig: the fraction of the reads overlapping the haplotig's SNP
+//range which come from it. A read comes from one of the haplotigs overlapping it with probability
+//proportional to their abundances and how well its alleles match them, so the abundances are
+//fitted by EM. Confidence intervals come from rerunning the EM on reads resampled with
+//replacement.
+
+#[derive(Debug, Clone)]
+pub struct AbundanceSettings {
+    pub num_bootstraps: usize,
+    pub max_iters: usize,
+    //Stop once no abundance changes by more than this in an iteration.
+    pub tolerance: f64,
+}
+
+impl Default for AbundanceSettings {
+    fn default() -> AbundanceSettings {
+        AbundanceSettings {
+            num_bootstraps: 100,
+            max_iters: 1000,
+            tolerance: 1e-6,
+        }
+    }
+}
+
+fn ranges_overlap(r1: (usize, usize), r2: (usize, usize)) -> bool {
+    r1.0 <= r2.1 && r2.0 <= r1.1
+}
+
+//For each read in a haplotig, the log likelihood of the read under each haplotig overlapping it:
+//(1 - epsilon)^same * epsilon^diff against the haplotig's consensus alleles. The consensus of a
+//haplotig containing the read is taken without the read's own alleles, which would otherwise make
+//the read look more likely under its own haplotig.
+pub fn get_read_log_likelihoods(
+    haplotigs: &Vec<FxHashSet<&Frag>>,
+    snp_endpoints: &Vec<(usize, usize)>,
+    use_qual: bool,
+) -> Vec<Vec<(usize, f64)>> {
+    let mut hap_block = utils_frags::hap_block_from_partition(haplotigs);
+    let mut reads: Vec<&Frag> = haplotigs
+        .iter()
+        .flat_map(|set| set.iter().copied())
+        .collect::<FxHashSet<&Frag>>()
+        .into_iter()
+        .collect();
+    reads.sort_by_key(|frag| frag.counter_id);
+
+    let mut read_log_likelihoods = vec![];
+    for frag in reads {
+        let epsilon = frag.epsilon;
+        let log_eps = epsilon.max(1e-6).ln();
+        let own_haplotigs: Vec<usize> = (0..haplotigs.len())
+            .filter(|i| haplotigs[*i].contains(frag))
+            .collect();
+        for i in own_haplotigs.iter() {
+            remove_read_alleles(&mut hap_block.blocks[*i], frag);
+        }
+        let mut log_likelihoods = vec![];
+        for (i, endpoints) in snp_endpoints.iter().enumerate() {
+            if !ranges_overlap(*endpoints, (frag.first_position, frag.last_position)) {
+                continue;
+            }
+            let (same, diff) = utils_frags::distance_read_haplo_epsilon_empty(
+                frag,
+                &hap_block.blocks[i],
+                epsilon,
+                use_qual,
+            );
+            log_likelihoods.push((i, same * (1. - epsilon).ln() + diff * log_eps));
+        }
+        for i in own_haplotigs.iter() {
+            utils_frags::add_read_to_block(&mut hap_block, frag, *i);
+        }
+        read_log_likelihoods.push(log_likelihoods);
+    }
+    read_log_likelihoods
+}
+
+//Take frag's alleles out of the allele counts of a haplotig. SNPs left without any alleles are
+//removed so that they count as not covered by the haplotig.
+fn remove_read_alleles(hap_map: &mut FxHashMap<usize, FxHashMap<usize, usize>>, frag: &Frag) {
+    for (pos, allele) in frag.calls() {
+        let mut now_empty = false;
+        if let Some(allele_counts) = hap_map.get_mut(&pos) {
+            if let Some(count) = allele_counts.get_mut(&allele) {
+                *count -= 1;
+                if *count == 0 {
+                    allele_counts.remove(&allele);
+                }
+            }
+            now_empty = allele_counts.is_empty();
+        }
+        if now_empty {
+            hap_map.remove(&pos);
+        }
+    }
+}
+
+//EM for the abundances, where read i counts read_weights[i] times and the EM starts from
+//abundances.
+pub fn em_abundances(
+    read_log_likelihoods: &Vec<Vec<(usize, f64)>>,
+    read_weights: &Vec<f64>,
+    mut abundances: Vec<f64>,
+    settings: &AbundanceSettings,
+) -> Vec<f64> {
+    let num_haplotigs = abundances.len();
+    let mut overlapping_weights = vec![0.; num_haplotigs];
+    for (i, log_likelihoods) in read_log_likelihoods.iter().enumerate() {
+        for (hap, _) in log_likelihoods.iter() {
+            overlapping_weights[*hap] += read_weights[i];
+        }
+    }
+
+    for _ in 0..settings.max_iters {
+        let mut new_abundances = vec![0.; num_haplotigs];
+        for (i, log_likelihoods) in read_log_likelihoods.iter().enumerate() {
+            if log_likelihoods.is_empty() || read_weights[i] == 0. {
+                continue;
+            }
+            //Clamped so that a haplotig with abundance 0 doesn't give -inf for every haplotig.
+            let log_probs: Vec<f64> = log_likelihoods
+                .iter()
+                .map(|(hap, log_likelihood)| log_likelihood + abundances[*hap].max(1e-12).ln())
+                .collect();
+            let log_total = utils_frags::log_sum_exp(&log_probs);
+            for (j, (hap, _)) in log_likelihoods.iter().enumerate() {
+                new_abundances[*hap] += read_weights[i] * (log_probs[j] - log_total).exp();
+            }
+        }
+        let mut max_change: f64 = 0.;
+        for hap in 0..num_haplotigs {
+            if overlapping_weights[hap] > 0. {
+                new_abundances[hap] /= overlapping_weights[hap];
+            }
+            max_change = max_change.max((new_abundances[hap] - abundances[hap]).abs());
+        }
+        abundances = new_abundances;
+        if max_change < settings.tolerance {
+            break;
+        }
+    }
+    abundances
+}
+
+//Abundance and 95% bootstrap confidence interval of each haplotig.
+pub fn get_abundances_with_cis(
+    read_log_likelihoods: &Vec<Vec<(usize, f64)>>,
+    num_haplotigs: usize,
+    settings: &AbundanceSettings,
+) -> Vec<(f64, (f64, f64))> {
+    let num_reads = read_log_likelihoods.len();
+    let abundances = em_abundances(
+        read_log_likelihoods,
+        &vec![1.; num_reads],
+        vec![1.; num_haplotigs],
+        settings,
+    );
+    if num_reads == 0 || settings.num_bootstraps == 0 {
+        return abundances.into_iter().map(|x| (x, (x, x))).collect();
+    }
+
+    //Each bootstrap has its own seed so that the intervals don't depend on the number of threads.
+    let bootstraps: Vec<Vec<f64>> = (0..settings.num_bootstraps)
+        .into_par_iter()
+        .map(|b| {
+            let mut rng = Pcg64::seed_from_u64(b as u64);
+            let mut read_weights = vec![0.; num_reads];
+            for _ in 0..num_reads {
+                read_weights[rng.gen_range(0..num_reads)] += 1.;
+            }
+            em_abundances(
+                read_log_likelihoods,
+                &read_weights,
+                abundances.clone(),
+                settings,
+            )
+        })
+        .collect();
+
+    let lower = (0.025 * (settings.num_bootstraps - 1) as f64).round() as usize;
+    let upper = (0.975 * (settings.num_bootstraps - 1) as f64).round() as usize;
+    let mut abundances_with_cis = vec![];
+    for hap in 0..num_haplotigs {
+        let mut samples: Vec<f64> = bootstraps.iter().map(|x| x[hap]).collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        abundances_with_cis.push((abundances[hap], (samples[lower], samples[upper])));
+    }
+    abundances_with_cis
+}
+
+//Abundance estimates of each haplotig. path_flows and path_coverages are the fitted flows of the
+//edges of each haplotig's path through the haplotype graph and the coverages of its nodes.
+pub fn get_haplotig_abundances(
+    haplotigs: &Vec<FxHashSet<&Frag>>,
+    snp_endpoints: &Vec<(usize, usize)>,
+    path_flows: &Vec<Vec<f64>>,
+    path_coverages: &Vec<Vec<f64>>,
+    use_qual: bool,
+    settings: &AbundanceSettings,
+) -> Vec<HaplotigAbundance> {
+    let read_log_likelihoods = get_read_log_likelihoods(haplotigs, snp_endpoints, use_qual);
+    let abundances_with_cis =
+        get_abundances_with_cis(&read_log_likelihoods, haplotigs.len(), settings);
+
+    let mut haplotig_abundances = vec![];
+    for (i, (abundance, abundance_ci)) in abundances_with_cis.into_iter().enumerate() {
+        let (mean_flow, flow_variance) = mean_and_variance(&path_flows[i]);
+        let (coverage, _) = mean_and_variance(&path_coverages[i]);
+        haplotig_abundances.push(HaplotigAbundance {
+            mean_flow,
+            flow_variance,
+            coverage,
+            num_reads: haplotigs[i].len(),
+            abundance,
+            abundance_ci,
+        });
+    }
+    haplotig_abundances
+}
+
+fn mean_and_variance(values: &Vec<f64>) -> (f64, f64) {
+    if values.is_empty() {
+        return (0., 0.);
+    }
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+    (mean, variance)
+}
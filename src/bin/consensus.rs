@@ -1,5 +1,6 @@
 extern crate time;
 use clap::{App, AppSettings, Arg};
+use flopp::cli_args;
 use flopp::file_reader;
 use flopp::local_clustering;
 use flopp::types_structs::Frag;
@@ -22,7 +23,14 @@ fn main() {
                 .short("b")
                 .multiple(true)
                 .value_name("BAMFILE1 BAMFILE2 ...")
-                .help("Input bam files.")
+                .help("Input BAM or CRAM files.")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reference")
+                .long("reference")
+                .value_name("FILE")
+                .help("Reference FASTA. Required for CRAM input.")
                 .takes_value(true),
         )
         .arg(
@@ -32,6 +40,9 @@ fn main() {
                 .value_name("OUTPUT")
                 .takes_value(true),
         )
+        .args(&cli_args::alignment_filter_args())
+        .args(&cli_args::molecule_grouping_args())
+        .args(&cli_args::site_filter_args())
         .get_matches();
 
     //If the user is getting frag files from BAM and VCF.
@@ -72,13 +83,26 @@ fn main() {
         panic!("No VCF file input found");
     }
 
+    let reference = matches.value_of("reference");
+    //consensus uses (filtered) supplementary alignments.
+    let mut alignment_filter = cli_args::get_alignment_filter(&matches);
+    alignment_filter.use_supplementary = true;
+    let molecule_grouping = cli_args::get_molecule_grouping(&matches);
+    let site_filter = cli_args::get_site_filter(&matches);
     let part_out_dir = matches.value_of("output").unwrap_or("glopp_out_dir").to_string();
+    //Take contig headers missing from the VCF from the BAM header.
+    let vcf_file = file_reader::add_missing_contig_headers(
+        vcf_file,
+        bam_files[0],
+        reference,
+        &format!("{}/vcf_with_contigs.vcf.gz", part_out_dir),
+    );
     let mut snp_to_genome_pos_map: FxHashMap<String, Vec<usize>> = FxHashMap::default();
 
 
     if vcf{
         let (snp_to_genome_pos_t, _genotype_dict_t, vcf_ploidy) =
-            file_reader::get_genotypes_from_vcf_hts(vcf_file);
+            file_reader::get_genotypes_from_vcf_hts(&vcf_file, &vec![], &site_filter);
         snp_to_genome_pos_map = snp_to_genome_pos_t;
 
         //If the VCF file is misformatted or has weird genotyping call we can catch that here.
@@ -91,7 +115,16 @@ fn main() {
     let mut length_gn = 0;
     let mut contig_n = String::from("");
     for bam_file in bam_files {
-        let all_frags_map = file_reader::get_frags_from_bamvcf(vcf_file, bam_file, true, true);
+        let all_frags_map = file_reader::get_frags_from_bamvcf(
+            vcf_file.as_str(),
+            &[bam_file],
+            &alignment_filter,
+            &molecule_grouping,
+            &vec![],
+            &site_filter,
+            reference,
+            false,
+        );
         for (contig, bam_fragments) in all_frags_map.iter() {
             let length_gn_bam = utils_frags::get_length_gn(&bam_fragments);
             if length_gn_bam > length_gn {
@@ -1,5 +1,6 @@
 extern crate time;
 use clap::{App, AppSettings, Arg};
+use flopp::cli_args;
 use flopp::file_reader;
 use std::time::Instant;
 
@@ -11,7 +12,7 @@ fn main() {
                           .arg(Arg::with_name("bam")
                               .short("b")
                               .value_name("BAMFILE")
-                               .help("Input a bam file.")
+                               .help("Input a BAM or CRAM file.")
                                 .takes_value(true)
                                 .required(true))
                           .arg(Arg::with_name("vcf")
@@ -20,33 +21,123 @@ fn main() {
                                .value_name("VCFFILE")
                                .takes_value(true)
                                .required(true))
+                          .arg(Arg::with_name("reference")
+                              .long("reference")
+                              .value_name("FILE")
+                              .help("Reference FASTA. Required for CRAM input.")
+                              .takes_value(true))
                           .arg(Arg::with_name("output")
                               .short("o")
                               .help("Name of output file (default : flopp_out.txt)")
                               .value_name("OUTPUT")
                               .takes_value(true)
                               .required(true))
+                          .arg(Arg::with_name("range")
+                              .short("r")
+                              .long("region")
+                              .multiple(true)
+                              .number_of_values(1)
+                              .value_name("CONTIG:START-END")
+                              .help("Only output fragments for the variants in this region (1-based, inclusive) or contig. Can be given multiple times. Requires indexed BAM and VCF (bgzipped with .tbi/.csi) files.")
+                              .takes_value(true))
+                          .arg(Arg::with_name("bed")
+                              .long("bed")
+                              .value_name("FILE")
+                              .help("Only output fragments for the variants in the regions of this BED file. Requires indexed BAM and VCF (bgzipped with .tbi/.csi) files.")
+                              .takes_value(true))
+                          .arg(Arg::with_name("format")
+                              .long("format")
+                              .value_name("FORMAT")
+                              .possible_values(&["glopp", "hapcut2", "hpop"])
+                              .help("Fragment file format: glopp (contigs, multi-digit alleles, pairs, linked reads and genome positions), hapcut2 (HapCUT2 extended format) or hpop (H-PoP). (default: glopp)")
+                              .takes_value(true))
+                          .args(&cli_args::alignment_filter_args())
+                          .args(&cli_args::molecule_grouping_args())
+                          .args(&cli_args::site_filter_args())
                           .get_matches();
 
     let bam_file = matches.value_of("bam").unwrap();
 
     //Whether or not we polish using genotyping information from VCF.
     let vcf_file = matches.value_of("vcf").unwrap();
+    let reference = matches.value_of("reference");
+    //frag-dump uses (filtered) supplementary alignments.
+    let mut alignment_filter = cli_args::get_alignment_filter(&matches);
+    alignment_filter.use_supplementary = true;
+    let molecule_grouping = cli_args::get_molecule_grouping(&matches);
+    let site_filter = cli_args::get_site_filter(&matches);
     let start_t = Instant::now();
     let output_frag_str = matches.value_of("output").unwrap_or("flopp_frags.txt");
+    let input_vcf_file = vcf_file;
+    //Take contig headers missing from the VCF from the BAM header.
+    let vcf_file = file_reader::add_missing_contig_headers(
+        vcf_file,
+        bam_file,
+        reference,
+        &format!("{}.vcf_with_contigs.vcf.gz", output_frag_str),
+    );
 
-    //CONSTANTS - Constants which users probably should not change.
+    //Only output fragments in certain regions.
+    let mut regions = vec![];
+    if let Some(range_strings) = matches.values_of("range") {
+        for range_string in range_strings {
+            regions.push(file_reader::parse_region_string(range_string));
+        }
+    }
+    if let Some(bed_file) = matches.value_of("bed") {
+        regions.extend(file_reader::get_regions_from_bed(bed_file));
+    }
+    let regions = file_reader::merge_regions(regions);
 
     println!("Reading frags/contigs.");
-    let all_frags_map = file_reader::get_frags_from_bamvcf(vcf_file, bam_file, true, true);
-    let mut all_frags = Vec::new();
-    for (_id, vect) in all_frags_map.into_iter() {
-        all_frags = vect;
-    }
+    let mut all_frags_map = file_reader::get_frags_from_bamvcf(
+        vcf_file.as_str(),
+        &[bam_file],
+        &alignment_filter,
+        &molecule_grouping,
+        &regions,
+        &site_filter,
+        reference,
+        false,
+    );
 
     //We need frags sorted by first position to make indexing easier.
-    all_frags.sort_by(|a, b| a.first_position.cmp(&b.first_position));
-    file_reader::write_frags_file(all_frags, output_frag_str.to_string());
+    for all_frags in all_frags_map.values_mut() {
+        all_frags.sort_by(|a, b| a.first_position.cmp(&b.first_position));
+    }
+    let (snp_to_genome_pos_map, _, _) =
+        file_reader::get_genotypes_from_vcf_hts(vcf_file.as_str(), &regions, &site_filter);
+    match matches.value_of("format").unwrap_or("glopp") {
+        "hapcut2" => {
+            //Numbered by the records of the VCF given, which HapCUT2 is run with.
+            let record_indices = file_reader::get_vcf_record_indices(
+                input_vcf_file,
+                &snp_to_genome_pos_map,
+                &site_filter,
+            );
+            file_reader::write_hapcut2_frags_file(&all_frags_map, &record_indices, output_frag_str);
+        }
+        "hpop" => {
+            //H-PoP files have no contig column, so each contig gets its own file if there are
+            //several.
+            let num_contigs = all_frags_map.len();
+            for (contig, all_frags) in all_frags_map.into_iter() {
+                let out_file = if num_contigs > 1 {
+                    format!("{}.{}", output_frag_str, contig)
+                } else {
+                    output_frag_str.to_string()
+                };
+                file_reader::write_frags_file(all_frags, out_file);
+            }
+        }
+        _ => {
+            file_reader::write_glopp_frags_file(
+                &all_frags_map,
+                &snp_to_genome_pos_map,
+                output_frag_str,
+            );
+        }
+    }
     println!(
         "Time taken reading and writing fragments to {} : {:?}",
         output_frag_str,
@@ -1,12 +1,18 @@
 extern crate time;
 use clap::{App, AppSettings, Arg};
+use flopp::abundance::AbundanceSettings;
+use flopp::cli_args;
 use flopp::file_reader;
+use flopp::flow_solver;
+use flopp::frag_cache;
 use flopp::global_clustering;
 use flopp::graph_processing;
 use flopp::local_clustering;
-use flopp::types_structs::Frag;
+use flopp::scaffolding;
+use flopp::types_structs::{ExactPathsSettings, Frag, MoleculeGrouping};
 use flopp::utils_frags;
 use fxhash::{FxHashMap};
+use rayon::prelude::*;
 use std::path::Path;
 use std::sync::Mutex;
 use std::time::Instant;
@@ -29,8 +35,9 @@ fn main() {
                                 .takes_value(true))
                           .arg(Arg::with_name("bam")
                               .short("b")
-                              .value_name("FILE")
-                               .help("Input a bam file.")
+                              .multiple(true)
+                              .value_name("FILE1 FILE2 ...")
+                               .help("Input BAM or CRAM files. Reads from all files are phased together, e.g. from different flowcells or technologies.")
                                 .takes_value(true))
                           .arg(Arg::with_name("vcf")
                                .short("v")
@@ -58,7 +65,7 @@ fn main() {
                               .short("e")
                               .takes_value(true)
                               .value_name("FLOAT")
-                              .help("Estimated allele call error rate. (default: 0.04. If using short reads, make sure to adjust this)"))
+                              .help("Estimated allele call error rate. With multiple BAM files, give one error rate per file separated by commas (e.g. -e 0.04,0.005 for ONT and Illumina). (default: 0.04. If using short reads, make sure to adjust this)"))
                           .arg(Arg::with_name("max_number_solns")
                               .short("n")
                               .takes_value(true)
@@ -84,14 +91,112 @@ fn main() {
                               .hidden(true)
                               .help("Use reference bias adjustment (in progress)."))
                           .arg(Arg::with_name("verbose")
-                              .short("r")
+                              .long("verbose")
                               .help("Verbose output."))
+                          .arg(Arg::with_name("range")
+                              .short("r")
+                              .long("region")
+                              .value_name("CONTIG:START-END")
+                              .help("Only phase variants in this region (1-based, inclusive). Requires indexed BAM and VCF (bgzipped with .tbi/.csi) files.")
+                              .takes_value(true))
+                          .arg(Arg::with_name("bed")
+                              .long("bed")
+                              .value_name("FILE")
+                              .help("Only phase variants in the regions of this BED file. Requires indexed BAM and VCF (bgzipped with .tbi/.csi) files.")
+                              .takes_value(true))
                           .arg(Arg::with_name("dont_filter_supplementary")
                               .short("S")
                               .help("Use all supplementary alignments from the BAM file without filtering (filtering by default if using supp alignments)."))
                           .arg(Arg::with_name("use_supplementary")
                               .short("X")
                               .help("Use supplementary alignments (default: don't use; have not tested fully yet)."))
+                          .arg(Arg::with_name("reference")
+                              .long("reference")
+                              .value_name("FILE")
+                              .help("Reference FASTA. Required for CRAM input; also used for realigning indels (see --use-indels).")
+                              .takes_value(true))
+                          .arg(Arg::with_name("use_indels")
+                              .long("use-indels")
+                              .help("Also phase indels and MNPs in the VCF (default: only use SNPs)."))
+                          .arg(Arg::with_name("use_qual")
+                              .long("use-qual")
+                              .help("Weight each allele call by its base quality when assigning reads to haplotypes (default: every allele counts as one call with error rate -e)."))
+                          .arg(Arg::with_name("share_nodes")
+                              .long("share-nodes")
+                              .help("Let several haplotigs share a node of the haplotype graph where strains are identical: each haplotig's flow is subtracted from the graph instead of removing its nodes. Reads in shared nodes are assigned to one of the haplotigs at random, weighted by flow and allele matches."))
+                          .arg(Arg::with_name("edge_posteriors")
+                              .long("edge-posteriors")
+                              .help("Weight the haplotype graph's edges by each read's posterior probability of belonging to each node instead of counting the reads which match one node best. Keeps the edges of low-coverage strains; --flow-solver l2 also uses the weights' variances."))
+                          .arg(Arg::with_name("flow_solver")
+                              .long("flow-solver")
+                              .value_name("SOLVER")
+                              .possible_values(&flow_solver::FLOW_SOLVERS)
+                              .help("How flows are fitted to the read counts of the haplotype graph's edges: l1 (linear program minimising absolute differences), l2 (least squares weighted by the counts) or min-cost-flow (the l1 fit without an LP solver). (default: l1)")
+                              .takes_value(true))
+                          .arg(Arg::with_name("exact_paths")
+                              .long("exact-paths")
+                              .help("Find the fewest haplotigs which explain the flows of the haplotype graph by solving a MIP instead of extracting paths greedily. Falls back to the greedy paths if the MIP takes longer than --exact-paths-time-limit."))
+                          .arg(Arg::with_name("exact_paths_time_limit")
+                              .long("exact-paths-time-limit")
+                              .value_name("SECONDS")
+                              .help("Time limit of --exact-paths for each contig. (default: 60)")
+                              .takes_value(true))
+                          .arg(Arg::with_name("exact_paths_tolerance")
+                              .long("exact-paths-tolerance")
+                              .value_name("FLOAT")
+                              .help("Fraction by which the haplotigs' flow through an edge may differ from the edge's flow with --exact-paths. (default: 0.1)")
+                              .takes_value(true))
+                          .arg(Arg::with_name("abundance_bootstraps")
+                              .long("abundance-bootstraps")
+                              .value_name("INT")
+                              .help("Number of bootstrap resamplings of the reads for the confidence intervals in abundances.tsv; 0 disables them. (default: 100)")
+                              .takes_value(true))
+                          .arg(Arg::with_name("polyploid_vcf")
+                              .long("polyploid-vcf")
+                              .help("Write one polyploid sample in the phased VCF, joining overlapping haplotigs with '|' (default: one haploid sample column per haplotig)."))
+                          .arg(Arg::with_name("haplotag_bam")
+                              .long("haplotag-bam")
+                              .help("Write a BAM for each contig where reads in haplotigs are tagged with HP:i (haplotig) and PS:i (phase set)."))
+                          .arg(Arg::with_name("split_bam")
+                              .long("split-bam")
+                              .help("Also write one BAM per haplotig. Implies --haplotag-bam."))
+                          .arg(Arg::with_name("no_read_output")
+                              .long("no-read-output")
+                              .help("Don't write the reads of each haplotig to long_reads/short_reads. Read sequences are then not kept in memory."))
+                          .arg(Arg::with_name("frag_cache")
+                              .long("frag-cache")
+                              .value_name("FILE")
+                              .help("Binary cache of the fragments read from the BAM/VCF files. Loaded if it was made from the same inputs and filter settings; otherwise the BAM files are read and the cache is (re)written.")
+                              .takes_value(true))
+                          .arg(Arg::with_name("frag_cache_checksum")
+                              .long("frag-cache-checksum")
+                              .help("Also check the fragment cache against checksums of the whole BAM/VCF/reference files instead of only their paths, sizes, modification times and headers. Slow for large BAM files."))
+                          .arg(Arg::with_name("stream")
+                              .long("stream")
+                              .help("Read, phase and write one contig at a time so that only one contig's fragments are in memory. Requires indexed BAM and VCF (bgzipped with .tbi/.csi) files."))
+                          .arg(Arg::with_name("parallel_contigs")
+                              .long("parallel-contigs")
+                              .help("Phase several contigs at the same time on the thread pool. With --stream, up to -t contigs' fragments are in memory at once."))
+                          .arg(Arg::with_name("hic_bam")
+                              .long("hic-bam")
+                              .multiple(true)
+                              .number_of_values(1)
+                              .value_name("FILE")
+                              .help("Hi-C BAM or CRAM file. Read pairs linking haplotigs are used to join them into scaffolds with a shared phase set.")
+                              .takes_value(true))
+                          .arg(Arg::with_name("hic_min_links")
+                              .long("hic-min-links")
+                              .value_name("INT")
+                              .help("Minimum number of Hi-C read pairs linking two haplotigs to join them. (default: 5)")
+                              .takes_value(true))
+                          .arg(Arg::with_name("hic_min_confidence")
+                              .long("hic-min-confidence")
+                              .value_name("FLOAT")
+                              .help("Minimum fraction of Hi-C links supporting a join over the other haplotypes in the region. (default: 0.8)")
+                              .takes_value(true))
+                          .args(&cli_args::alignment_filter_args())
+                          .args(&cli_args::molecule_grouping_args())
+                          .args(&cli_args::site_filter_args())
                           .get_matches();
 
     //Parse command line args.
@@ -116,8 +221,60 @@ fn main() {
     //    let use_mec = matches.is_present("use_mec");
     let use_mec = true;
     let use_ref_bias = matches.is_present("use_ref_bias");
-    let filter_supplementary = !matches.is_present("dont_filter_supplementary");
-    let use_supplementary = matches.is_present("use_supplementary");
+    let mut alignment_filter = cli_args::get_alignment_filter(&matches);
+    alignment_filter.filter_supplementary = !matches.is_present("dont_filter_supplementary");
+    alignment_filter.use_supplementary = matches.is_present("use_supplementary");
+    let molecule_grouping = cli_args::get_molecule_grouping(&matches);
+    let store_reads = !matches.is_present("no_read_output");
+    let polyploid_vcf = matches.is_present("polyploid_vcf");
+    let mut site_filter = cli_args::get_site_filter(&matches);
+    site_filter.use_indels = matches.is_present("use_indels");
+    let use_qual = matches.is_present("use_qual");
+    let share_nodes = matches.is_present("share_nodes");
+    let edge_posteriors = matches.is_present("edge_posteriors");
+    let flow_cutoff = graph_processing::get_flow_cutoff(edge_posteriors);
+    let flow_solver = flow_solver::get_flow_solver(matches.value_of("flow_solver").unwrap_or("l1"));
+    let mut exact_paths = None;
+    if matches.is_present("exact_paths") {
+        let mut settings = ExactPathsSettings::default();
+        if let Some(value) = matches.value_of("exact_paths_time_limit") {
+            settings.time_limit = value
+                .parse::<f64>()
+                .expect("Exact paths time limit must be a number");
+        }
+        if let Some(value) = matches.value_of("exact_paths_tolerance") {
+            settings.tolerance = value
+                .parse::<f64>()
+                .expect("Exact paths tolerance must be a number");
+        }
+        exact_paths = Some(settings);
+    }
+    let mut abundance_settings = AbundanceSettings::default();
+    if let Some(value) = matches.value_of("abundance_bootstraps") {
+        abundance_settings.num_bootstraps = value
+            .parse::<usize>()
+            .expect("Number of abundance bootstraps must be a non-negative integer");
+    }
+    let reference = matches.value_of("reference");
+    let split_bam = matches.is_present("split_bam");
+    let haplotag_bam = matches.is_present("haplotag_bam") || split_bam;
+    let frag_cache_file = matches.value_of("frag_cache");
+    let stream = matches.is_present("stream");
+    let parallel_contigs = matches.is_present("parallel_contigs");
+    let hic_bam_files: Vec<&str> = match matches.values_of("hic_bam") {
+        None => vec![],
+        Some(hic_bam_files) => hic_bam_files.collect(),
+    };
+    let hic_min_links = matches
+        .value_of("hic_min_links")
+        .unwrap_or("5")
+        .parse::<usize>()
+        .expect("Minimum number of Hi-C links must be an integer");
+    let hic_min_confidence = matches
+        .value_of("hic_min_confidence")
+        .unwrap_or("0.8")
+        .parse::<f64>()
+        .expect("Minimum Hi-C confidence must be a number");
 
     // Set up our logger if the user passed the debug flag
     if matches.is_present("verbose") {
@@ -142,14 +299,14 @@ fn main() {
 
     //If the user is getting frag files from BAM and VCF.
     let bam;
-    let bam_file = match matches.value_of("bam") {
+    let bam_files: Vec<&str> = match matches.values_of("bam") {
         None => {
             bam = false;
-            "_"
+            vec![]
         }
-        Some(bam_file) => {
+        Some(bam_files) => {
             bam = true;
-            bam_file
+            bam_files.collect()
         }
     };
 
@@ -205,18 +362,15 @@ fn main() {
         panic!("Must input a BAM file.")
     }
 
-    //Only haplotype variants in a certain range : TODO not implemented yet
-    let _range;
-    let _range_string = match matches.value_of("range") {
-        None => {
-            _range = false;
-            "_"
-        }
-        Some(_range_string) => {
-            _range = true;
-            _range_string
-        }
-    };
+    //Only haplotype variants in certain regions.
+    let mut regions = vec![];
+    if let Some(range_string) = matches.value_of("range") {
+        regions.push(file_reader::parse_region_string(range_string));
+    }
+    if let Some(bed_file) = matches.value_of("bed") {
+        regions.extend(file_reader::get_regions_from_bed(bed_file));
+    }
+    let regions = file_reader::merge_regions(regions);
 
     if bam && frag {
         panic!("If using frag as input, BAM file should not be specified")
@@ -226,6 +380,29 @@ fn main() {
         panic!("Must input VCF file if using BAM file");
     }
 
+    if stream && !bam {
+        panic!("--stream requires BAM input.");
+    }
+
+    if stream && frag_cache_file.is_some() {
+        panic!("--frag-cache can't be used with --stream; fragments are read one contig at a time.");
+    }
+
+    //The cache is keyed on the VCF given by the user, not the copy with contig headers.
+    let input_vcf_file = vcf_file;
+
+    //Callers like LoFreq don't write contig headers; take them from the BAM header instead.
+    let vcf_file_with_contigs;
+    if bam {
+        vcf_file_with_contigs = file_reader::add_missing_contig_headers(
+            vcf_file,
+            bam_files[0],
+            reference,
+            &format!("{}/vcf_with_contigs.vcf.gz", part_out_dir),
+        );
+        vcf_file = &vcf_file_with_contigs;
+    }
+
     rayon::ThreadPoolBuilder::new()
         .num_threads(num_t)
         .build_global()
@@ -237,23 +414,89 @@ fn main() {
 
     println!("Reading inputs (BAM/VCF/frags).");
     let start_t = Instant::now();
-    let mut all_frags_map;
-    if bam {
-        all_frags_map = file_reader::get_frags_from_bamvcf(
+    let mut all_frags_map = FxHashMap::default();
+    let mut snp_to_genome_pos_map: FxHashMap<String, Vec<usize>> = FxHashMap::default();
+    if stream {
+        //Fragments are read for each contig below.
+    } else if bam {
+        let cache_key = frag_cache_file.map(|_| {
+            frag_cache::get_cache_key(
+                input_vcf_file,
+                &bam_files,
+                reference,
+                &alignment_filter,
+                &molecule_grouping,
+                &site_filter,
+                &regions,
+                store_reads,
+                matches.is_present("frag_cache_checksum"),
+            )
+        });
+        let cached_frags_map = match (frag_cache_file, &cache_key) {
+            (Some(frag_cache_file), Some(cache_key)) => {
+                frag_cache::read_frag_cache(cache_key, frag_cache_file)
+            }
+            _ => None,
+        };
+        match cached_frags_map {
+            Some(cached_frags_map) => {
+                println!("Loaded fragments from cache {}.", frag_cache_file.unwrap());
+                all_frags_map = cached_frags_map;
+            }
+            None => {
+                all_frags_map = file_reader::get_frags_from_bamvcf(
+                    vcf_file,
+                    &bam_files,
+                    &alignment_filter,
+                    &molecule_grouping,
+                    &regions,
+                    &site_filter,
+                    reference,
+                    store_reads,
+                );
+                if let (Some(frag_cache_file), Some(cache_key)) = (frag_cache_file, &cache_key) {
+                    println!("Writing fragment cache {}.", frag_cache_file);
+                    frag_cache::write_frag_cache(&all_frags_map, cache_key, frag_cache_file);
+                }
+            }
+        }
+    } else {
+        let (frags_map, frags_snp_to_genome_pos_map) =
+            file_reader::get_frags_and_snp_to_genome_pos(frag_file);
+        all_frags_map = frags_map;
+        snp_to_genome_pos_map = frags_snp_to_genome_pos_map;
+    }
+    if !hic_bam_files.is_empty() && !bam {
+        panic!("Hi-C BAM files can only be used with BAM input.");
+    }
+    //Hi-C pairs are not molecules; each pair is one fragment.
+    let hic_molecule_grouping = MoleculeGrouping::default();
+    let mut hic_frags_map = FxHashMap::default();
+    if !hic_bam_files.is_empty() && !stream {
+        hic_frags_map = file_reader::get_frags_from_bamvcf(
             vcf_file,
-            bam_file,
-            filter_supplementary,
-            use_supplementary,
+            &hic_bam_files,
+            &alignment_filter,
+            &hic_molecule_grouping,
+            &regions,
+            &site_filter,
+            reference,
+            false,
         );
-    } else {
-        all_frags_map = file_reader::get_frags_container(frag_file);
     }
     println!("Time taken reading inputs {:?}", Instant::now() - start_t);
+    if bam {
+        file_reader::write_filtered_sites(
+            vcf_file,
+            &regions,
+            &site_filter,
+            &format!("{}/filtered_sites.tsv", part_out_dir),
+        );
+    }
 
-    let mut snp_to_genome_pos_map: FxHashMap<String, Vec<usize>> = FxHashMap::default();
     if vcf || vcf_nopolish {
         let (snp_to_genome_pos_t, _genotype_dict_t, vcf_ploidy) =
-            file_reader::get_genotypes_from_vcf_hts(vcf_file);
+            file_reader::get_genotypes_from_vcf_hts(vcf_file, &regions, &site_filter);
         snp_to_genome_pos_map = snp_to_genome_pos_t;
 
         //If the VCF file is misformatted or has weird genotyping call we can catch that here.
@@ -266,19 +509,33 @@ fn main() {
 
     let first_iter = true;
 
-    for (contig, all_frags) in all_frags_map.iter_mut() {
+    let mut error_rates = vec![0.04];
+    if let Some(value) = matches.value_of("epsilon") {
+        error_rates = value
+            .split(',')
+            .map(|x| x.parse::<f64>().expect("Error rate must be a number"))
+            .collect();
+    }
+    if error_rates.len() != 1 && error_rates.len() != bam_files.len() {
+        panic!("Give one error rate (-e) for all BAM files or one error rate per BAM file.");
+    }
+    let num_locs_string = matches.value_of("num_iters_ploidy_est").unwrap_or("10");
+    let num_locs = num_locs_string.parse::<usize>().unwrap();
+
+    //Phase one contig and write its output.
+    let phase_contig = |contig: &String, all_frags: &mut Vec<Frag>, hic_frags: Option<&Vec<Frag>>| {
         if all_frags.len() == 0 {
             println!("Contig {} has no fragments", contig);
-            continue;
+            return;
         }
 
-        println!("Number of fragments {}", all_frags.len());
+        println!("{}: Number of fragments {}", contig, all_frags.len());
         if snp_to_genome_pos_map.contains_key(contig) || bam == false {
             let contig_out_dir = format!("{}/{}", part_out_dir, contig);
             let mut snp_to_genome_pos: &Vec<usize> = &Vec::new();
 
-            if bam == true {
-                snp_to_genome_pos = snp_to_genome_pos_map.get(contig).unwrap();
+            if let Some(snp_to_genome_pos_contig) = snp_to_genome_pos_map.get(contig) {
+                snp_to_genome_pos = snp_to_genome_pos_contig;
             }
 
             //We need frags sorted by first position to make indexing easier. We want the
@@ -288,9 +545,11 @@ fn main() {
                 frag.counter_id = i;
             }
 
+            utils_frags::set_library_error_rates(all_frags, &error_rates);
+
             //We use the median # bases spanned by fragments as the length of blocks.
             let avg_read_length = utils_frags::get_avg_length(&all_frags, 0.5);
-            println!("Median read length is {} SNPs", avg_read_length);
+            println!("{}: Median read length is {} SNPs", contig, avg_read_length);
 
             let block_len_quant = 0.33;
 
@@ -310,49 +569,113 @@ fn main() {
 
             //Get last SNP on the genome covered over all fragments.
             let length_gn = utils_frags::get_length_gn(&all_frags);
-            println!("Length of genome is {} SNPs", length_gn);
-            println!("Length of each block is {} bases", block_length);
-            let mut epsilon = 0.04;
-
-            if epsilon < 0.01 {
-                epsilon = 0.010;
-            }
-
-            match matches.value_of("epsilon") {
-                None => {}
-                Some(value) => {
-                    epsilon = value.parse::<f64>().unwrap();
-                }
-            };
+            println!("{}: Length of genome is {} SNPs", contig, length_gn);
+            println!("{}: Length of each block is {} bases", contig, block_length);
+            //Mean error rate of all fragments; fragments keep their library's error rate.
+            let epsilon = utils_frags::mean_error_rate(all_frags.iter());
 
-            println!("Epsilon is {}", epsilon);
+            println!("{}: Epsilon is {}", contig, epsilon);
 
             if estimate_ploidy {
-                let num_locs_string = matches.value_of("num_iters_ploidy_est").unwrap_or("10");
-                let num_locs = num_locs_string.parse::<usize>().unwrap();
                 let mut hap_graph = graph_processing::generate_hap_graph(
                     length_gn,
                     num_locs,
                     &all_frags,
-                    epsilon,
                     &snp_to_genome_pos,
                     max_number_solns,
                     block_length,
                     contig_out_dir.to_string(),
+                    use_qual,
+                    edge_posteriors,
                 );
-                let flow_up_vec = graph_processing::solve_lp_graph(&hap_graph, contig_out_dir.to_string());
-                graph_processing::get_disjoint_paths_rewrite(
-                    &mut hap_graph,
-                    flow_up_vec,
-                    epsilon,
+                let flow_start_t = Instant::now();
+                let flow_up_vec = graph_processing::solve_flow_graph(
+                    &hap_graph,
+                    flow_solver.as_ref(),
                     contig_out_dir.to_string(),
+                    flow_cutoff,
+                );
+                println!(
+                    "{}: Flow fit finished in {:?}",
+                    contig,
+                    Instant::now() - flow_start_t
+                );
+                let (haplotigs, haplotig_snp_endpoints) =
+                    graph_processing::get_disjoint_paths_rewrite(
+                        &mut hap_graph,
+                        flow_up_vec,
+                        contig_out_dir.to_string(),
+                        contig,
+                        &snp_to_genome_pos,
+                        use_qual,
+                        share_nodes,
+                        exact_paths.as_ref(),
+                        flow_cutoff,
+                        &abundance_settings,
+                    );
+                let mut joins = vec![];
+                if let Some(hic_frags) = hic_frags {
+                    let spans =
+                        scaffolding::get_haplotig_spans(&haplotigs, &haplotig_snp_endpoints);
+                    let links = scaffolding::get_hic_links(&haplotigs, &spans, hic_frags);
+                    joins = scaffolding::get_haplotig_joins(
+                        &spans,
+                        &links,
+                        hic_min_links,
+                        hic_min_confidence,
+                    );
+                    println!(
+                        "{}: Number of Hi-C joins: {}",
+                        contig,
+                        joins.iter().filter(|join| join.joined).count()
+                    );
+                }
+                let scaffolds = scaffolding::get_scaffolds(&haplotig_snp_endpoints, &joins);
+                let phase_sets = scaffolding::get_phase_sets(
+                    &haplotig_snp_endpoints,
+                    &scaffolds,
                     &snp_to_genome_pos,
                 );
+                if !hic_bam_files.is_empty() {
+                    file_reader::write_hic_scaffolds(
+                        &contig_out_dir,
+                        &haplotig_snp_endpoints,
+                        &joins,
+                        &scaffolds,
+                        &phase_sets,
+                    );
+                }
+                if bam {
+                    file_reader::write_phased_vcf(
+                        vcf_file,
+                        &haplotigs,
+                        &haplotig_snp_endpoints,
+                        &contig_out_dir,
+                        contig,
+                        &snp_to_genome_pos,
+                        &phase_sets,
+                        polyploid_vcf,
+                        &regions,
+                        &site_filter,
+                    );
+                    if haplotag_bam {
+                        file_reader::write_haplotagged_bam(
+                            &bam_files,
+                            &haplotigs,
+                            &contig_out_dir,
+                            contig,
+                            &phase_sets,
+                            split_bam,
+                            &alignment_filter,
+                            reference,
+                        );
+                    }
+                }
 
             } 
             //We don't actually use this code path anymore, but it can be useful for testing purposes. 
             else {
-                println!("Ploidy is {}", ploidy);
+                println!("{}: Ploidy is {}", contig, ploidy);
                 //Phasing occurs here
                 let start_t = Instant::now();
                 let initial_part;
@@ -368,21 +691,21 @@ fn main() {
                 let (break_positions, final_part) = global_clustering::beam_search_phasing(
                     initial_part,
                     &all_frags_refs,
-                    epsilon,
                     binom_factor,
                     cutoff_value,
                     max_number_solns,
                     use_mec,
                     use_ref_bias,
+                    use_qual,
                 );
-                println!("Time taken for phasing {:?}", Instant::now() - start_t);
+                println!("{}: Time taken for phasing {:?}", contig, Instant::now() - start_t);
 
                 let final_block_unpolish = utils_frags::hap_block_from_partition(&final_part);
                 let (f_binom_vec, f_freq_vec) =
                     local_clustering::get_partition_stats(&final_part, &final_block_unpolish);
                 let final_score =
                     -1.0 * local_clustering::get_mec_score(&f_binom_vec, &f_freq_vec, 0.0, 0.0);
-                println!("Final MEC score for the partition is {:?}.", final_score);
+                println!("{}: Final MEC score for the partition is {:?}.", contig, final_score);
 
                 file_reader::write_output_partition_to_file(
                     &final_part,
@@ -404,5 +727,58 @@ fn main() {
                 );
             }
         }
+    };
+
+    if stream {
+        //Contigs with sites, in the order of the VCF header. Each contig's reads are fetched from
+        //the index, phased and written before its memory is released.
+        let contigs: Vec<String> = file_reader::get_vcf_contigs(vcf_file)
+            .into_iter()
+            .filter(|contig| snp_to_genome_pos_map.contains_key(contig))
+            .collect();
+        let stream_contig = |contig: &String| {
+            let contig_regions = file_reader::get_contig_regions(&regions, contig);
+            println!("Reading fragments for contig {}.", contig);
+            let mut all_frags = file_reader::get_frags_from_bamvcf(
+                vcf_file,
+                &bam_files,
+                &alignment_filter,
+                &molecule_grouping,
+                &contig_regions,
+                &site_filter,
+                reference,
+                store_reads,
+            )
+            .remove(contig)
+            .unwrap_or_default();
+            let mut hic_frags = None;
+            if !hic_bam_files.is_empty() {
+                hic_frags = file_reader::get_frags_from_bamvcf(
+                    vcf_file,
+                    &hic_bam_files,
+                    &alignment_filter,
+                    &hic_molecule_grouping,
+                    &contig_regions,
+                    &site_filter,
+                    reference,
+                    false,
+                )
+                .remove(contig);
+            }
+            phase_contig(contig, &mut all_frags, hic_frags.as_ref());
+        };
+        if parallel_contigs {
+            contigs.par_iter().for_each(|contig| stream_contig(contig));
+        } else {
+            contigs.iter().for_each(|contig| stream_contig(contig));
+        }
+    } else if parallel_contigs {
+        all_frags_map
+            .par_iter_mut()
+            .for_each(|(contig, all_frags)| phase_contig(contig, all_frags, hic_frags_map.get(contig)));
+    } else {
+        for (contig, all_frags) in all_frags_map.iter_mut() {
+            phase_contig(contig, all_frags, hic_frags_map.get(contig));
+        }
     }
 }
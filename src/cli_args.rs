@@ -0,0 +1,204 @@
+use crate::file_reader;
+use crate::types_structs::{AlignmentFilter, MoleculeGrouping, SiteFilter};
+use clap::{Arg, ArgMatches};
+
+//Command line options shared by all binaries.
+
+//Options for which alignments are used; parse with get_alignment_filter.
+pub fn alignment_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("min_mapq")
+            .long("min-mapq")
+            .value_name("INT")
+            .help("Minimum MAPQ of alignments. (default: 15)")
+            .takes_value(true),
+        Arg::with_name("min_mapq_supp")
+            .long("min-mapq-supp")
+            .value_name("INT")
+            .help("Minimum MAPQ of supplementary alignments when filtering them. (default: 59)")
+            .takes_value(true),
+        Arg::with_name("min_base_qual")
+            .long("min-base-qual")
+            .value_name("INT")
+            .help("Minimum base quality of allele calls. (default: 0)")
+            .takes_value(true),
+        Arg::with_name("include_flags")
+            .long("include-flags")
+            .value_name("FLAGS")
+            .help("Only use alignments with all of these SAM flags set, e.g. 0x2. (default: 0)")
+            .takes_value(true),
+        Arg::with_name("exclude_flags")
+            .long("exclude-flags")
+            .value_name("FLAGS")
+            .help("Skip alignments with any of these SAM flags set. (default: 0x304; unmapped, secondary, QC fail)")
+            .takes_value(true),
+        Arg::with_name("keep_duplicates")
+            .long("keep-duplicates")
+            .help("Use alignments flagged as duplicates (default: skip duplicates)."),
+        Arg::with_name("max_soft_clip_frac")
+            .long("max-soft-clip-frac")
+            .value_name("FLOAT")
+            .help("Maximum fraction of soft clipped bases in an alignment. (default: 1.0)")
+            .takes_value(true),
+        Arg::with_name("min_aligned_length")
+            .long("min-aligned-length")
+            .value_name("INT")
+            .help("Minimum number of reference bases spanned by an alignment. (default: 0)")
+            .takes_value(true),
+    ]
+}
+
+//Options for grouping linked reads into molecules; parse with get_molecule_grouping.
+pub fn molecule_grouping_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("barcode_tag")
+            .long("barcode-tag")
+            .value_name("TAG")
+            .help("Merge alignments with the same value of this tag (e.g. BX for linked reads) into one molecule fragment.")
+            .takes_value(true),
+        Arg::with_name("max_molecule_gap")
+            .long("max-molecule-gap")
+            .value_name("INT")
+            .help("Split a barcode into a new molecule after a gap of more than this many bases. (default: 50000)")
+            .takes_value(true),
+    ]
+}
+
+//Flags can be given in decimal or in hex with a 0x prefix.
+fn parse_flags(value: &str) -> u16 {
+    let parsed = if value.starts_with("0x") || value.starts_with("0X") {
+        u16::from_str_radix(&value[2..], 16)
+    } else {
+        value.parse::<u16>()
+    };
+    match parsed {
+        Ok(flags) => flags,
+        Err(_) => panic!("SAM flags must be an integer, e.g. 1796 or 0x704"),
+    }
+}
+
+pub fn get_alignment_filter(matches: &ArgMatches) -> AlignmentFilter {
+    let mut alignment_filter = AlignmentFilter::default();
+    if let Some(value) = matches.value_of("min_mapq") {
+        alignment_filter.min_mapq = value
+            .parse::<u8>()
+            .expect("MAPQ must be an integer between 0 and 255");
+    }
+    if let Some(value) = matches.value_of("min_mapq_supp") {
+        alignment_filter.min_mapq_supplementary = value
+            .parse::<u8>()
+            .expect("MAPQ must be an integer between 0 and 255");
+    }
+    if let Some(value) = matches.value_of("min_base_qual") {
+        alignment_filter.min_base_qual = value
+            .parse::<u8>()
+            .expect("Base quality must be an integer between 0 and 255");
+    }
+    if let Some(value) = matches.value_of("include_flags") {
+        alignment_filter.include_flags = parse_flags(value);
+    }
+    if let Some(value) = matches.value_of("exclude_flags") {
+        alignment_filter.exclude_flags = parse_flags(value);
+    }
+    alignment_filter.keep_duplicates = matches.is_present("keep_duplicates");
+    if let Some(value) = matches.value_of("max_soft_clip_frac") {
+        alignment_filter.max_soft_clip_frac = value
+            .parse::<f64>()
+            .expect("Soft clip fraction must be a number");
+    }
+    if let Some(value) = matches.value_of("min_aligned_length") {
+        alignment_filter.min_aligned_length = value
+            .parse::<i64>()
+            .expect("Aligned length must be an integer");
+    }
+    alignment_filter
+}
+
+pub fn get_molecule_grouping(matches: &ArgMatches) -> MoleculeGrouping {
+    let mut molecule_grouping = MoleculeGrouping::default();
+    if let Some(value) = matches.value_of("barcode_tag") {
+        if value.len() != 2 {
+            panic!("Barcode tag must be a two character SAM tag, e.g. BX");
+        }
+        molecule_grouping.barcode_tag = Some(value.to_string());
+    }
+    if let Some(value) = matches.value_of("max_molecule_gap") {
+        molecule_grouping.max_molecule_gap = value
+            .parse::<i64>()
+            .expect("Molecule gap must be an integer");
+    }
+    molecule_grouping
+}
+
+//Options for which VCF records are used; parse with get_site_filter.
+pub fn site_filter_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("sample")
+            .long("sample")
+            .value_name("NAME")
+            .help("Sample in the VCF file to use for genotypes. Required for multi-sample VCF files.")
+            .takes_value(true),
+        Arg::with_name("skip_hom_ref")
+            .long("skip-hom-ref")
+            .help("Ignore sites where the sample is homozygous reference or has a missing genotype."),
+        Arg::with_name("pass_only")
+            .long("pass-only")
+            .help("Only use sites with FILTER PASS (or missing)."),
+        Arg::with_name("min_qual")
+            .long("min-qual")
+            .value_name("FLOAT")
+            .help("Minimum QUAL of sites. (default: 0)")
+            .takes_value(true),
+        Arg::with_name("min_depth")
+            .long("min-depth")
+            .value_name("INT")
+            .help("Minimum INFO/DP of sites. (default: no minimum)")
+            .takes_value(true),
+        Arg::with_name("max_depth")
+            .long("max-depth")
+            .value_name("INT")
+            .help("Maximum INFO/DP of sites. (default: no maximum)")
+            .takes_value(true),
+        Arg::with_name("min_allele_frac")
+            .long("min-allele-frac")
+            .value_name("FLOAT")
+            .help("Minimum fraction of non-reference reads from the sample's FORMAT/AD. (default: 0)")
+            .takes_value(true),
+        Arg::with_name("exclude_bed")
+            .long("exclude-bed")
+            .value_name("FILE")
+            .help("Ignore sites overlapping the regions in this BED file, e.g. repeats. Can be given multiple times.")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+    ]
+}
+
+pub fn get_site_filter(matches: &ArgMatches) -> SiteFilter {
+    let mut site_filter = SiteFilter::default();
+    site_filter.sample = matches.value_of("sample").map(|x| x.to_string());
+    site_filter.skip_hom_ref = matches.is_present("skip_hom_ref");
+    site_filter.pass_only = matches.is_present("pass_only");
+    if let Some(value) = matches.value_of("min_qual") {
+        site_filter.min_qual = value.parse::<f32>().expect("QUAL must be a number");
+    }
+    if let Some(value) = matches.value_of("min_depth") {
+        site_filter.min_depth = value.parse::<i32>().expect("Depth must be an integer");
+    }
+    if let Some(value) = matches.value_of("max_depth") {
+        site_filter.max_depth = value.parse::<i32>().expect("Depth must be an integer");
+    }
+    if let Some(value) = matches.value_of("min_allele_frac") {
+        site_filter.min_allele_frac = value
+            .parse::<f64>()
+            .expect("Allele fraction must be a number");
+    }
+    if let Some(bed_files) = matches.values_of("exclude_bed") {
+        let mut exclude_regions = vec![];
+        for bed_file in bed_files {
+            exclude_regions.extend(file_reader::get_regions_from_bed(bed_file));
+        }
+        site_filter.exclude_regions = file_reader::merge_regions(exclude_regions);
+    }
+    site_filter
+}
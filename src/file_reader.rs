@@ -1,15 +1,23 @@
-use crate::types_structs::{build_frag, update_frag, Frag, HapBlock};
+use crate::types_structs::{
+    build_frag, update_frag, AlignmentFilter, Frag, GenomeRegion, HapBlock, HaplotigAbundance,
+    HaplotigJoin, MoleculeGrouping, SiteFilter,
+};
 use crate::utils_frags;
+use bio::alignment::pairwise::Aligner;
 use bio::alphabets::dna::revcomp;
+use bio::io::fasta;
 use bio::io::fastq;
 use bio::io::fastq::Writer;
 use fxhash::{FxHashMap, FxHashSet};
 use rust_htslib::bam::header::Header;
+use rust_htslib::bam::record::Cigar;
 use rust_htslib::bam::HeaderView as HeaderViewBam;
 use rust_htslib::bcf::record::GenotypeAllele;
+use rust_htslib::bcf::record::Numeric;
 use rust_htslib::{bam, bam::Read as DUMMY_NAME1};
 use rust_htslib::{bcf, bcf::Read as DUMMY_NAME2};
-use std::collections::BTreeMap;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::fs;
 use std::fs::File;
 use std::fs::OpenOptions;
@@ -31,80 +39,204 @@ where
     Ok(io::BufReader::new(file).lines())
 }
 
-// Given a frags.txt file specified as in H-PoP, we return a collection
-// (vector) of fragments after processing it.
+//Version of the fragment format written by write_glopp_frags_file.
+pub const FRAG_FORMAT_VERSION: usize = 1;
+
+// Given a fragment file, we return the fragments of each contig. glopp's own format (see
+// write_glopp_frags_file) has contig names. H-PoP style files and HapCUT2's extended format don't,
+// so their fragments are all put under "frag_contig".
 //
 pub fn get_frags_container<P>(filename: P) -> FxHashMap<String, Vec<Frag>>
 where
     P: AsRef<Path>,
 {
-    let mut all_frags = Vec::new();
+    read_frags_file(filename).0
+}
+
+//SNP index -> genome position of each contig, from the genome positions in a glopp fragment file.
+//Contigs where some SNP has no known position are left out, in which case SNP indices are used
+//instead of genome positions in the output.
+pub fn get_snp_to_genome_pos_from_frags_file<P>(filename: P) -> FxHashMap<String, Vec<usize>>
+where
+    P: AsRef<Path>,
+{
+    get_frags_and_snp_to_genome_pos(filename).1
+}
+
+//Both get_frags_container and get_snp_to_genome_pos_from_frags_file from one read of the file.
+pub fn get_frags_and_snp_to_genome_pos<P>(
+    filename: P,
+) -> (
+    FxHashMap<String, Vec<Frag>>,
+    FxHashMap<String, Vec<usize>>,
+)
+where
+    P: AsRef<Path>,
+{
+    let (frags_map, snp_to_pos_map) = read_frags_file(filename);
+    let mut snp_to_genome_pos_map = FxHashMap::default();
+    for (contig, snp_to_pos) in snp_to_pos_map {
+        let num_snps = *snp_to_pos.keys().max().unwrap_or(&0);
+        if num_snps == 0 || snp_to_pos.len() != num_snps {
+            continue;
+        }
+        let snp_to_genome_pos: Vec<usize> = (1..num_snps + 1).map(|snp| snp_to_pos[&snp]).collect();
+        snp_to_genome_pos_map.insert(contig, snp_to_genome_pos);
+    }
+    (frags_map, snp_to_genome_pos_map)
+}
+
+fn read_frags_file<P>(
+    filename: P,
+) -> (
+    FxHashMap<String, Vec<Frag>>,
+    FxHashMap<String, FxHashMap<usize, usize>>,
+)
+where
+    P: AsRef<Path>,
+{
+    let mut frags_map = FxHashMap::default();
+    let mut snp_to_pos_map = FxHashMap::default();
     let mut counter = 0;
+    let mut glopp_format = false;
 
     //Make sure file is able to be read
     if let Ok(lines) = read_lines(filename) {
-        for line in lines {
+        for (line_number, line) in lines.enumerate() {
             if let Ok(l) = line {
-                let v: Vec<&str> = l.split('\t').collect();
-
-                //First column is the # of blocks
-                if let Ok(num_blocks) = v[0].parse::<i32>() {
-                    //                    println!("{}",num_blocks);
-                    let mut seqs = FxHashMap::default();
-                    let mut quals = FxHashMap::default();
-                    let mut positions = FxHashSet::default();
-                    let mut list_of_positions = Vec::new();
-                    let mut first_position = 1;
-                    let mut last_position = 1;
-
-                    // For each block, read it into a dictionary with corresp. base
-                    for i in 0..num_blocks {
-                        let index = i as usize;
-                        let start_pos = v[2 * index + 2].parse::<usize>().unwrap();
-                        if i == 0 {
-                            first_position = start_pos;
-                        }
-                        for (j, c) in v[2 * index + 3].chars().enumerate() {
-                            seqs.insert(start_pos + j, c.to_digit(10).unwrap() as usize);
-                            list_of_positions.push(start_pos + j);
-                            positions.insert(start_pos + j);
-                            last_position = start_pos + j
-                        }
-                    }
-
-                    let qual_string = v.last().unwrap().as_bytes();
-                    for (i, key) in list_of_positions.iter().enumerate() {
-                        //We usually have a 33 offset for phred qualities. Rust should throw an
-                        //error here if this result is negative.
-                        quals.insert(*key, qual_string[i] - 33);
+                if line_number == 0 && l.starts_with("##glopp-frags-version=") {
+                    let version = l["##glopp-frags-version=".len()..]
+                        .trim()
+                        .parse::<usize>()
+                        .expect("Fragment file version must be an integer");
+                    if version > FRAG_FORMAT_VERSION {
+                        panic!(
+                            "Fragment file version {} is newer than the supported version {}",
+                            version, FRAG_FORMAT_VERSION
+                        );
                     }
-
-                    let new_frag = Frag {
-                        id: v[1].to_string(),
-                        counter_id: counter,
-                        seq_dict: seqs,
-                        qual_dict: quals,
-                        positions: positions,
-                        first_position: first_position,
-                        last_position: last_position,
-                        seq_string: vec![vec![]; 2],
-                        qual_string: vec![vec![]; 2],
-                        is_paired: false,
-                        snp_pos_to_seq_pos: FxHashMap::default(),
+                    glopp_format = true;
+                    continue;
+                }
+                if glopp_format && l.starts_with("##sites=") {
+                    let (contig, sites) = match l["##sites=".len()..].split_once('\t') {
+                        Some(contig_sites) => contig_sites,
+                        None => panic!("Sites line must be ##sites=contig<TAB>positions: {}", l),
                     };
-
-                    all_frags.push(new_frag);
-                    counter += 1
-                } else {
-                    panic!("Not a number found in first column");
+                    let snp_to_pos = snp_to_pos_map
+                        .entry(contig.to_string())
+                        .or_insert(FxHashMap::default());
+                    for (i, pos) in sites.split(',').enumerate() {
+                        let pos = pos.parse::<usize>().expect("Genome position must be an integer");
+                        snp_to_pos.insert(i + 1, pos);
+                    }
+                    continue;
                 }
+                if l.starts_with('#') || l.is_empty() {
+                    continue;
+                }
+                let (contig, new_frag) = if glopp_format {
+                    let (contig, new_frag, genome_positions) = parse_glopp_frag_line(&l, counter);
+                    let snp_to_pos = snp_to_pos_map
+                        .entry(contig.clone())
+                        .or_insert(FxHashMap::default());
+                    for (snp, pos) in genome_positions {
+                        snp_to_pos.insert(snp, pos);
+                    }
+                    (contig, new_frag)
+                } else {
+                    (String::from("frag_contig"), parse_block_frag_line(&l, counter))
+                };
+                frags_map.entry(contig).or_insert(vec![]).push(new_frag);
+                counter += 1
             }
         }
     }
 
-    let mut frags_map = FxHashMap::default();
-    frags_map.insert(String::from("frag_contig"), all_frags);
-    frags_map
+    if frags_map.is_empty() {
+        frags_map.insert(String::from("frag_contig"), vec![]);
+    }
+    (frags_map, snp_to_pos_map)
+}
+
+//A line of an H-PoP style fragment file:
+//  num_blocks id (start alleles)... quals
+//or of HapCUT2's extended format, which has three more columns before the blocks:
+//  num_blocks id data_type mate2_index barcode (start alleles)... quals
+//Alleles are single digits.
+fn parse_block_frag_line(l: &str, counter: usize) -> Frag {
+    let v: Vec<&str> = l.split('\t').collect();
+
+    //First column is the # of blocks
+    let num_blocks = match v[0].parse::<usize>() {
+        Ok(num_blocks) => num_blocks,
+        Err(_) => panic!("Not a number found in first column"),
+    };
+    let offset = if v.len() == 2 * num_blocks + 3 {
+        2
+    } else if v.len() == 2 * num_blocks + 6 {
+        5
+    } else {
+        panic!("Wrong number of columns in fragment file line: {}", l);
+    };
+    let is_paired = offset == 5 && v[3] != "-1";
+
+    //    println!("{}",num_blocks);
+    let mut alleles = Vec::new();
+
+    // For each block, read it into a dictionary with corresp. base
+    for index in 0..num_blocks {
+        let start_pos = v[2 * index + offset].parse::<usize>().unwrap();
+        for (j, c) in v[2 * index + offset + 1].chars().enumerate() {
+            alleles.push((start_pos + j, c.to_digit(10).unwrap() as usize));
+        }
+    }
+
+    let mut frag = build_frag(v[1].to_string(), counter, is_paired, 0);
+    let qual_string = v.last().unwrap().as_bytes();
+    for (i, (snp, allele)) in alleles.into_iter().enumerate() {
+        //We usually have a 33 offset for phred qualities. Rust should throw an
+        //error here if this result is negative.
+        frag.add_call(snp, allele, qual_string[i] - 33, 0, 0);
+    }
+
+    frag
+}
+
+//A line of a glopp fragment file; see write_glopp_frags_file. Returns the contig, the fragment and
+//the known (SNP, genome position) pairs.
+fn parse_glopp_frag_line(l: &str, counter: usize) -> (String, Frag, Vec<(usize, usize)>) {
+    let v: Vec<&str> = l.split('\t').collect();
+    if v.len() != 6 {
+        panic!("Wrong number of columns in fragment file line: {}", l);
+    }
+    let source_file = v[2]
+        .parse::<usize>()
+        .expect("Source file column must be an integer");
+    let mut new_frag = build_frag(v[1].to_string(), counter, v[3] == "1", source_file);
+    if v[4] != "." {
+        new_frag.molecule_reads = v[4].split(',').map(|x| x.to_string()).collect();
+    }
+    let mut genome_positions = vec![];
+    for call in v[5].split(',') {
+        let fields: Vec<&str> = call.split(':').collect();
+        if fields.len() != 5 {
+            panic!("Allele calls must be snp:genome_pos:allele:qual:mate, found {}", call);
+        }
+        let snp = fields[0].parse::<usize>().expect("SNP index must be an integer");
+        let allele = fields[2].parse::<usize>().expect("Allele must be an integer");
+        let qual = fields[3].parse::<u8>().expect("Quality must be an integer between 0 and 255");
+        let mate = fields[4].parse::<u8>().expect("Mate must be 0 or 1");
+        if fields[1] != "." {
+            let pos = fields[1]
+                .parse::<usize>()
+                .expect("Genome position must be an integer");
+            genome_positions.push((snp, pos));
+        }
+        //Only the mate is known; read sequences aren't stored in fragment files.
+        new_frag.add_call(snp, allele, qual, mate, 0);
+    }
+    (v[0].to_string(), new_frag, genome_positions)
 }
 
 //Write a vector of blocks into a file.
@@ -192,17 +324,28 @@ pub fn write_blocks_to_file<P>(
 }
 
 //Given a vcf file and a bam file, we get a vector of frags.
+//If regions is non-empty, only the variants and reads in the regions are used; this requires
+//indexed VCF and BAM files. Regions should be merged with merge_regions beforehand. The VCF sites
+//used are given by site_filter. The reference FASTA is needed for CRAM files and is used for
+//realigning indels if given. Fragments from all BAM files are merged per contig; see
+//Frag.source_file. Linked reads are merged into molecules according to molecule_grouping. The
+//read sequences and qualities are only kept with store_reads, for writing the reads of each
+//haplotig.
 pub fn get_frags_from_bamvcf<P>(
     vcf_file: P,
-    bam_file: P,
-    filter_supplementary: bool,
-    use_supplementary: bool,
+    bam_files: &[P],
+    alignment_filter: &AlignmentFilter,
+    molecule_grouping: &MoleculeGrouping,
+    regions: &Vec<GenomeRegion>,
+    site_filter: &SiteFilter,
+    reference: Option<&str>,
+    store_reads: bool,
 ) -> FxHashMap<String, Vec<Frag>>
 where
     P: AsRef<Path>,
 {
     //Get which SNPS correspond to which positions on the genome.
-    let mut vcf = match bcf::Reader::from_path(vcf_file) {
+    let vcf = match bcf::Reader::from_path(&vcf_file) {
         Ok(vcf) => vcf,
         Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
     };
@@ -210,22 +353,31 @@ where
     let mut vcf_set_of_pos = FxHashMap::default();
     let mut vcf_pos_allele_map = FxHashMap::default();
     let mut vcf_pos_to_snp_counter_map = FxHashMap::default();
+    let mut vcf_pos_flank_map = FxHashMap::default();
     let mut all_set_of_pos = FxHashSet::default();
+    let mut ref_reader = reference.map(|reference| get_reference_reader(reference));
+    let ref_contig_lens: FxHashMap<String, u64> = match ref_reader.as_ref() {
+        Some(ref_reader) => ref_reader
+            .index
+            .sequences()
+            .into_iter()
+            .map(|seq| (seq.name, seq.len))
+            .collect(),
+        None => FxHashMap::default(),
+    };
     //    let mut set_of_pos = FxHashSet::default();
     //    let mut pos_allele_map = FxHashMap::default();
     //    let mut pos_to_snp_counter_map = FxHashMap::default();
     let vcf_header = vcf.header().clone();
+    let sample_index = get_sample_index(&vcf_header, site_filter);
 
     //    if header.contig_count() > 1 {
     //        panic!("More than 1 contig detected in header of vcf file; please use only 1 contig/reference per vcf file.");
     //    }
 
     let mut last_ref_chrom: &[u8] = &[];
-    for rec in vcf.records() {
-        let unr = rec.unwrap();
+    visit_vcf_records(&vcf_file, regions, |unr| {
         let alleles = unr.alleles();
-        let mut al_vec = Vec::new();
-        let mut is_snp = true;
 
         let record_rid = unr.rid().unwrap();
         let ref_chrom_vcf = vcf_header.rid2name(record_rid).unwrap();
@@ -244,20 +396,33 @@ where
             .entry(ref_chrom_vcf)
             .or_insert(FxHashMap::default());
 
-        for allele in alleles.iter() {
-            if allele.len() > 1 {
-                is_snp = false;
-                break;
-            }
-            al_vec.push(allele[0]);
-        }
-
-        if !is_snp {
+        if !site_passed_check(unr, sample_index, site_filter) {
             //            println!(
             //                "BAM : Variant at position {} is not a snp. Ignoring.",
             //                unr.pos()
             //            );
-            continue;
+            return;
+        }
+        let al_vec: Vec<Vec<u8>> = alleles.iter().map(|allele| allele.to_vec()).collect();
+        if let Some(ref_reader) = ref_reader.as_mut() {
+            if al_vec.iter().any(|allele| allele.len() > 1) {
+                let contig = str::from_utf8(ref_chrom_vcf).unwrap();
+                let contig_len = match ref_contig_lens.get(contig) {
+                    Some(contig_len) => *contig_len,
+                    None => panic!("Contig {} not found in the reference FASTA.", contig),
+                };
+                let flanks = get_reference_flanks(
+                    ref_reader,
+                    contig,
+                    contig_len,
+                    unr.pos() as u64,
+                    al_vec[0].len() as u64,
+                );
+                vcf_pos_flank_map
+                    .entry(ref_chrom_vcf)
+                    .or_insert(FxHashMap::default())
+                    .insert(unr.pos(), flanks);
+            }
         }
 
         set_of_pos.insert(unr.pos());
@@ -265,143 +430,183 @@ where
         pos_to_snp_counter_map.insert(unr.pos(), snp_counter);
         snp_counter += 1;
         pos_allele_map.insert(unr.pos(), al_vec);
-    }
-
-    //    let mut bam = bam::Reader::from_path(bam_file).unwrap();
-    let mut bam = match bam::Reader::from_path(bam_file) {
-        Ok(bam) => bam,
-        Err(_) => panic!("rust_htslib had an error while reading the BAM file. Exiting"),
-    };
-
-    //Check the headers to see how many references there are.
-    let header = Header::from_template(bam.header());
-    let bam_header_view = HeaderViewBam::from_header(&header);
-
-    //This may be important : We assume that distinct reads have different names. I can see this
-    //being a problem in some weird bad cases, so be careful.
-    let mut ref_id_to_frag = FxHashMap::default();
+    });
+
+    //This may be important : We assume that distinct reads in a BAM file have different names.
+    //I can see this being a problem in some weird bad cases, so be careful. Reads are keyed by
+    //(file, name) so that reads from different files are never merged.
+    let mut ref_id_to_frag: FxHashMap<Vec<u8>, FxHashMap<(usize, String), Frag>> =
+        FxHashMap::default();
+    //Names of the reads of each molecule fragment; a set so that adding a read is O(1).
+    let mut ref_id_to_molecule_reads: FxHashMap<Vec<u8>, FxHashMap<(usize, String), FxHashSet<String>>> =
+        FxHashMap::default();
     let mut counter_id = 0;
 
-    //Scan the pileup table for every position on the genome which contains a SNP to get the aligned reads corresponding to the SNP. TODO : There should be a way to index into the bam.pileup() object so we don't have to iterate through positions which we already know are not SNPs.
-    let errors_mask = 1796;
-    let first_in_pair_mask = 64;
-    let second_in_pair_mask = 128;
-    let secondary_mask = 256;
-    let supplementary_mask = 2048;
-    let mapq_supp_cutoff = 59;
-    let mapq_normal_cutoff = 15;
-    for p in bam.pileup() {
-        let pileup = p.unwrap();
-        let pos_genome = pileup.pos();
-
-        if !all_set_of_pos.contains(&(pos_genome as i64)) {
-            continue;
-        }
+    for (source_file, bam_file) in bam_files.iter().enumerate() {
+        //    let mut bam = bam::Reader::from_path(bam_file).unwrap();
+        //With regions only the indexed reader is opened, so that a CRAM's reference is set up once.
+        let (mut bam, mut indexed_bam) = if regions.is_empty() {
+            (Some(get_bam_reader(&bam_file, reference)), None)
+        } else {
+            match get_indexed_bam_reader(&bam_file, reference) {
+                Some(indexed_bam) => (None, Some(indexed_bam)),
+                None => panic!("rust_htslib had an error while reading the BAM index. An indexed BAM/CRAM (.bai/.csi/.crai) is required when specifying regions. Exiting"),
+            }
+        };
+
+        //Check the headers to see how many references there are.
+        let header = match (&bam, &indexed_bam) {
+            (Some(bam), _) => Header::from_template(bam.header()),
+            (_, Some(indexed_bam)) => Header::from_template(indexed_bam.header()),
+            _ => unreachable!(),
+        };
+        let bam_header_view = HeaderViewBam::from_header(&header);
+
+        //Scan the pileup table for every position on the genome which contains a SNP to get the aligned reads corresponding to the SNP. TODO : There should be a way to index into the bam.pileup() object so we don't have to iterate through positions which we already know are not SNPs.
+        let first_in_pair_mask = 64;
+        let second_in_pair_mask = 128;
+        //Linked reads: barcode -> (tid, end of the current molecule, molecule number), and the
+        //molecule each read was put in so that all of its alignments end up in the same fragment.
+        //Reads are forgotten once the pileup passes the end of their molecule; see
+        //evict_molecule_reads.
+        let mut barcode_to_molecule: FxHashMap<Vec<u8>, (i32, i64, usize)> = FxHashMap::default();
+        let mut read_to_molecule: FxHashMap<Vec<u8>, String> = FxHashMap::default();
+        let mut molecule_read_ends = BinaryHeap::new();
+        let mut aligner = get_indel_aligner();
+        let mut process_pileup = |pileup: bam::pileup::Pileup, start: u64, end: u64| {
+            let pos_genome = pileup.pos();
+
+            if !all_set_of_pos.contains(&(pos_genome as i64)) {
+                return;
+            }
+            if molecule_grouping.barcode_tag.is_some() {
+                evict_molecule_reads(
+                    &mut read_to_molecule,
+                    &mut molecule_read_ends,
+                    pileup.tid() as i32,
+                    pos_genome as i64,
+                );
+            }
+            //Reads fetched for a region can extend past the region.
+            if (pos_genome as u64) < start || (pos_genome as u64) >= end {
+                return;
+            }
 
-        for alignment in pileup.alignments() {
-            if !alignment.is_del() && !alignment.is_refskip() {
-                let aln_record = alignment.record();
-                let flags = aln_record.flags();
-                let mapq = aln_record.mapq();
-                let is_paired;
-                let mut pair_number = 0;
+            for alignment in pileup.alignments() {
+                //Reads with a deletion at the site can still support an indel allele.
+                if !alignment.is_refskip() {
+                    let aln_record = alignment.record();
+                    let flags = aln_record.flags();
+                    let is_paired;
+                    let mut pair_number = 0;
+
+                    if flags & first_in_pair_mask > 0 {
+                        is_paired = true;
+                    } else if flags & second_in_pair_mask > 0 {
+                        is_paired = true;
+                        pair_number = 1;
+                    } else {
+                        is_paired = false;
+                    }
 
-                if flags & first_in_pair_mask > 0 {
-                    is_paired = true;
-                } else if flags & second_in_pair_mask > 0 {
-                    is_paired = true;
-                    pair_number = 1;
-                } else {
-                    is_paired = false;
-                }
+                    let (passed, is_supp) = alignment_passed_check(&aln_record, alignment_filter);
+                    if !passed {
+                        continue;
+                    }
 
-                if mapq < mapq_normal_cutoff {
-                    continue;
-                }
+                    //                println!("{}-{}-{}",&alignment.record().seq().len(), flags , &id_string);
+                    //
 
-                //Erroneous alignment, skip
-                if flags & errors_mask > 0 {
-                    //dbg!(&flags,&id_string);
-                    continue;
-                }
+                    let tid = aln_record.tid();
+                    let ref_chrom = bam_header_view.tid2name(tid as u32);
+                    //dbg!(String::from_utf8_lossy(ref_chrom));
+                    let get_ref_chrom = vcf_pos_to_snp_counter_map.get(ref_chrom);
 
-                //Secondary alignment, skip
-                if flags & secondary_mask > 0 {
-                    //dbg!(&flags,&id_string);
-                    continue;
-                }
+                    let pos_to_snp_counter_map = match get_ref_chrom {
+                        Some(pos_to_snp_counter_map) => pos_to_snp_counter_map,
+                        None => continue,
+                    };
 
-                let is_supp;
-                if flags & supplementary_mask > 0 {
-                    is_supp = true;
-                    if !use_supplementary {
+                    if pos_to_snp_counter_map.contains_key(&(pos_genome as i64)) == false {
                         continue;
                     }
-                    if filter_supplementary {
-                        if mapq < mapq_supp_cutoff {
-                            continue;
-                        }
-                    }
-                } else {
-                    is_supp = false;
-                }
-
-                //                println!("{}-{}-{}",&alignment.record().seq().len(), flags , &id_string);
-                //
-
-                let tid = aln_record.tid();
-                let ref_chrom = bam_header_view.tid2name(tid as u32);
-                //dbg!(String::from_utf8_lossy(ref_chrom));
-                let get_ref_chrom = vcf_pos_to_snp_counter_map.get(ref_chrom);
+                    let read_name = String::from_utf8(aln_record.qname().to_vec()).unwrap();
+                    let molecule_id = get_molecule_id(
+                        &aln_record,
+                        molecule_grouping,
+                        &mut barcode_to_molecule,
+                        &mut read_to_molecule,
+                        &mut molecule_read_ends,
+                    );
+                    let is_molecule = molecule_id.is_some();
+                    let id_string = molecule_id.unwrap_or(read_name.clone());
+                    let id_to_frag = ref_id_to_frag
+                        .entry(ref_chrom.to_vec())
+                        .or_insert(FxHashMap::default());
 
-                let pos_to_snp_counter_map = match get_ref_chrom {
-                    Some(pos_to_snp_counter_map) => pos_to_snp_counter_map,
-                    None => continue,
-                };
+                    let snp_id = pos_to_snp_counter_map.get(&(pos_genome as i64)).unwrap();
+                    let id_string2 = (source_file, id_string.clone());
 
-                if pos_to_snp_counter_map.contains_key(&(pos_genome as i64)) == false {
-                    continue;
-                }
-                let id_string = String::from_utf8(aln_record.qname().to_vec()).unwrap();
-                let id_to_frag = ref_id_to_frag
-                    .entry(ref_chrom)
-                    .or_insert(FxHashMap::default());
-
-                let snp_id = pos_to_snp_counter_map.get(&(pos_genome as i64)).unwrap();
-                let id_string2 = id_string.clone();
+                    if !id_to_frag.contains_key(&id_string2) {
+                        counter_id += 1;
+                    }
 
-                if !id_to_frag.contains_key(&id_string) {
-                    counter_id += 1;
-                }
+                    let site_alleles = vcf_pos_allele_map
+                        .get(ref_chrom)
+                        .unwrap()
+                        .get(&(pos_genome as i64))
+                        .unwrap();
 
-                let readbase = alignment.record().seq()[alignment.qpos().unwrap()];
-                let qualbase = alignment.record().qual()[alignment.qpos().unwrap()];
+                    //Only build the frag if the read has one of the alleles.
+                    let allele_call;
+                    if site_alleles.iter().all(|allele| allele.len() == 1) {
+                        allele_call = match alignment.qpos() {
+                            Some(qpos) => {
+                                let readbase = aln_record.seq()[qpos];
+                                let qualbase = aln_record.qual()[qpos];
+                                site_alleles
+                                    .iter()
+                                    .position(|allele| allele[0] == readbase)
+                                    .map(|i| (i, qualbase, qpos))
+                            }
+                            None => None,
+                        };
+                    } else {
+                        let flanks = vcf_pos_flank_map
+                            .get(ref_chrom)
+                            .and_then(|flank_map| flank_map.get(&(pos_genome as i64)));
+                        allele_call = call_variant_allele(
+                            &aln_record,
+                            pos_genome as i64,
+                            site_alleles,
+                            flanks,
+                            &mut aligner,
+                        );
+                    }
 
-                for (i, allele) in vcf_pos_allele_map
-                    .get(ref_chrom)
-                    .unwrap()
-                    .get(&(pos_genome as i64))
-                    .unwrap()
-                    .iter()
-                    .enumerate()
-                {
-                    //Only build the frag if the base is one of the SNP alleles.
-                    if readbase == *allele {
+                    if let Some((i, qualbase, qpos)) = allele_call {
+                        if qualbase < alignment_filter.min_base_qual {
+                            continue;
+                        }
                         let mut frag;
                         if id_to_frag.contains_key(&id_string2) {
                             frag = id_to_frag.get_mut(&id_string2).unwrap();
                         } else {
-                            frag = id_to_frag
-                                .entry(id_string2)
-                                .or_insert(build_frag(id_string, counter_id, is_paired));
+                            frag = id_to_frag.entry(id_string2).or_insert(build_frag(
+                                id_string,
+                                counter_id,
+                                is_paired && !is_molecule,
+                                source_file,
+                            ));
+                        }
+                        if is_molecule {
+                            ref_id_to_molecule_reads
+                                .entry(ref_chrom.to_vec())
+                                .or_insert(FxHashMap::default())
+                                .entry((source_file, frag.id.clone()))
+                                .or_insert(FxHashSet::default())
+                                .insert(read_name);
                         }
-                        //                        let mut frag = id_to_frag.entry(id_string2).or_insert(build_frag(
-                        //                            id_string,
-                        //                            counter_id,
-                        //                            supp_aln,
-                        //                            alignment.record().seq().as_bytes(),
-                        //                            alignment.record().qual().to_vec(),
-                        //                        ));
                         update_frag(
                             &mut frag,
                             i,
@@ -410,12 +615,35 @@ where
                             pair_number,
                             is_supp,
                             &aln_record,
-                            alignment.qpos().unwrap(),
+                            qpos,
+                            store_reads,
                         );
-                        break;
                     }
                 }
             }
+        };
+
+        if let Some(bam) = bam.as_mut() {
+            for p in bam.pileup() {
+                process_pileup(p.unwrap(), 0, u64::MAX);
+            }
+        } else if let Some(bam) = indexed_bam.as_mut() {
+            for region in regions.iter() {
+                let tid = match bam.header().tid(region.contig.as_bytes()) {
+                    Some(tid) => tid,
+                    None => {
+                        log::warn!("Region contig {} not found in BAM header. Skipping.", region.contig);
+                        continue;
+                    }
+                };
+                let end = region
+                    .end
+                    .unwrap_or(bam.header().target_len(tid).unwrap());
+                bam.fetch((tid, region.start as i64, end as i64)).unwrap();
+                for p in bam.pileup() {
+                    process_pileup(p.unwrap(), region.start, end);
+                }
+            }
         }
     }
 
@@ -429,14 +657,21 @@ where
         let vec_frags = ref_vec_frags
             .get_mut(&String::from_utf8(ref_chrom.to_vec()).unwrap())
             .unwrap();
-        let id_to_frag = ref_id_to_frag.get_mut(ref_chrom).unwrap();
+        let id_to_frag = ref_id_to_frag.get_mut(&ref_chrom).unwrap();
         let id_to_frag = mem::replace(id_to_frag, FxHashMap::default());
-        for (_id, frag) in id_to_frag.into_iter() {
+        let mut id_to_molecule_reads = ref_id_to_molecule_reads
+            .remove(&ref_chrom)
+            .unwrap_or_default();
+        for (id, mut frag) in id_to_frag.into_iter() {
+            if let Some(molecule_reads) = id_to_molecule_reads.remove(&id) {
+                frag.molecule_reads = molecule_reads.into_iter().collect();
+                frag.molecule_reads.sort();
+            }
             //IMPORTANT: I'm turning this off for metagenomics because some fragments may only
             //index one read. However, this is still useful because we don't know ploidy info.
             let mut prev_pos = frag.first_position;
             for pos in frag.first_position + 1..frag.last_position {
-                if !frag.positions.contains(&pos) && (pos - prev_pos < 100) {
+                if !frag.contains(pos) && (pos - prev_pos < 100) {
                     //random number. TODO TESTING GAPS IN FRAGMENTS
                     //                                        frag.seq_dict.insert(pos, 9);
                     //                                        frag.qual_dict.insert(pos, 7);
@@ -444,33 +679,657 @@ where
                 }
                 prev_pos = pos;
             }
-            if frag.positions.len() > 0 {
+            //A molecule is made of many reads, so there is no single read sequence to write out.
+            if !frag.molecule_reads.is_empty() {
+                frag.clear_seqs();
+            }
+            if frag.num_calls() > 0 {
                 vec_frags.push(frag);
             }
         }
     }
 
-    //Think this was for debugging?
-    for vec in ref_vec_frags.values() {
-        for frag in vec.iter() {
-            let mut vec_snp_seq: Vec<(usize, usize)> = frag
-                .snp_pos_to_seq_pos
-                .iter()
-                .map(|(x, y)| (*x, y.1))
-                .collect();
-            vec_snp_seq.sort();
-            let mut prev_seq_pos = 0;
-            for item in vec_snp_seq.iter() {
-                if prev_seq_pos > item.1 {
-                    //dbg!(&vec_snp_seq, &frag.id);
-                    //                    panic!();
-                }
-                prev_seq_pos = item.1;
+    //Think this was for debugging?
+    for vec in ref_vec_frags.values() {
+        for frag in vec.iter() {
+            let vec_snp_seq: Vec<(usize, usize)> = frag
+                .positions()
+                .zip(frag.seq_offsets.iter())
+                .map(|(x, y)| (x, *y as usize))
+                .collect();
+            let mut prev_seq_pos = 0;
+            for item in vec_snp_seq.iter() {
+                if prev_seq_pos > item.1 {
+                    //dbg!(&vec_snp_seq, &frag.id);
+                    //                    panic!();
+                }
+                prev_seq_pos = item.1;
+            }
+        }
+    }
+
+    ref_vec_frags
+}
+
+//Molecule fragment id for a linked read, or None if no barcode tag is used or the alignment has
+//no barcode. Reads of a barcode are put in a new molecule when they start more than
+//max_molecule_gap bases after the end of the barcode's current molecule; this assumes the
+//alignments are visited in roughly coordinate order, as they are in a pileup. The read is
+//remembered with the molecule's current end in molecule_read_ends.
+fn get_molecule_id(
+    record: &bam::Record,
+    molecule_grouping: &MoleculeGrouping,
+    barcode_to_molecule: &mut FxHashMap<Vec<u8>, (i32, i64, usize)>,
+    read_to_molecule: &mut FxHashMap<Vec<u8>, String>,
+    molecule_read_ends: &mut BinaryHeap<Reverse<(i32, i64, Vec<u8>)>>,
+) -> Option<String> {
+    let tag = molecule_grouping.barcode_tag.as_ref()?;
+    if let Some(molecule_id) = read_to_molecule.get(record.qname()) {
+        return Some(molecule_id.clone());
+    }
+    let barcode = match record.aux(tag.as_bytes()) {
+        Ok(bam::record::Aux::String(barcode)) => barcode.as_bytes().to_vec(),
+        _ => return None,
+    };
+    let start = record.pos();
+    let end = record.cigar().end_pos();
+    let molecule = barcode_to_molecule
+        .entry(barcode.clone())
+        .or_insert((record.tid(), end, 0));
+    if molecule.0 != record.tid() || start - molecule.1 > molecule_grouping.max_molecule_gap {
+        *molecule = (record.tid(), end, molecule.2 + 1);
+    } else if end > molecule.1 {
+        molecule.1 = end;
+    }
+    let molecule_id = format!("{}_mol{}", String::from_utf8_lossy(&barcode), molecule.2);
+    read_to_molecule.insert(record.qname().to_vec(), molecule_id.clone());
+    molecule_read_ends.push(Reverse((molecule.0, molecule.1, record.qname().to_vec())));
+    Some(molecule_id)
+}
+
+//Forget the reads whose molecule ended before position pos of contig tid, or which are on another
+//contig. Later alignments of such a read are put in a molecule by their barcode again, which is
+//the read's molecule unless the barcode's molecule was split in between.
+fn evict_molecule_reads(
+    read_to_molecule: &mut FxHashMap<Vec<u8>, String>,
+    molecule_read_ends: &mut BinaryHeap<Reverse<(i32, i64, Vec<u8>)>>,
+    tid: i32,
+    pos: i64,
+) {
+    while let Some(Reverse((read_tid, end, _))) = molecule_read_ends.peek() {
+        if *read_tid == tid && *end >= pos {
+            break;
+        }
+        let Reverse((_, _, read_name)) = molecule_read_ends.pop().unwrap();
+        read_to_molecule.remove(&read_name);
+    }
+}
+
+//Open a BAM or CRAM file. CRAM files need the reference FASTA to be decoded.
+fn get_bam_reader<P>(bam_file: P, reference: Option<&str>) -> bam::Reader
+where
+    P: AsRef<Path>,
+{
+    let mut bam = match bam::Reader::from_path(bam_file) {
+        Ok(bam) => bam,
+        Err(_) => panic!("rust_htslib had an error while reading the BAM file. Exiting"),
+    };
+    if let Some(reference) = reference {
+        if bam.set_reference(reference).is_err() {
+            panic!("rust_htslib had an error while setting the reference FASTA. Exiting");
+        }
+    }
+    bam
+}
+
+//Open an indexed BAM or CRAM file. Returns None if there is no index.
+fn get_indexed_bam_reader<P>(bam_file: P, reference: Option<&str>) -> Option<bam::IndexedReader>
+where
+    P: AsRef<Path>,
+{
+    let mut bam = bam::IndexedReader::from_path(bam_file).ok()?;
+    if let Some(reference) = reference {
+        if bam.set_reference(reference).is_err() {
+            panic!("rust_htslib had an error while setting the reference FASTA. Exiting");
+        }
+    }
+    Some(bam)
+}
+
+//Open an indexed reference FASTA, building the .fai index if it doesn't exist.
+fn get_reference_reader(reference: &str) -> fasta::IndexedReader<File> {
+    if let Ok(reader) = fasta::IndexedReader::from_file(&reference) {
+        return reader;
+    }
+    //htslib builds the index when loading a FASTA without one.
+    if rust_htslib::faidx::Reader::from_path(reference).is_err() {
+        panic!("Could not read or index the reference FASTA. Exiting.");
+    }
+    match fasta::IndexedReader::from_file(&reference) {
+        Ok(reader) => reader,
+        Err(_) => panic!("Could not read the reference FASTA index. Exiting."),
+    }
+}
+
+//Get the reference sequence flanking a variant at (0-based) pos with reference allele length
+//ref_len on a contig of length contig_len. The flanks are truncated at the ends of the contig.
+fn get_reference_flanks(
+    ref_reader: &mut fasta::IndexedReader<File>,
+    contig: &str,
+    contig_len: u64,
+    pos: u64,
+    ref_len: u64,
+) -> (Vec<u8>, Vec<u8>) {
+    let flank_len = 10;
+    let mut fetch = |start: u64, end: u64| {
+        let mut seq = vec![];
+        if start < end {
+            ref_reader.fetch(contig, start, end).unwrap();
+            ref_reader.read(&mut seq).unwrap();
+        }
+        seq.make_ascii_uppercase();
+        seq
+    };
+    let left_flank = fetch(pos.saturating_sub(flank_len), pos);
+    let right_start = u64::min(pos + ref_len, contig_len);
+    let right_flank = fetch(right_start, u64::min(right_start + flank_len, contig_len));
+    (left_flank, right_flank)
+}
+
+//Whether a VCF record is used for phasing. SNPs are always used; indels and MNPs are used if
+//use_indels is true and all alleles are sequences (i.e. not symbolic alleles like <DEL> or *).
+fn is_phasable_site(alleles: &[&[u8]], use_indels: bool) -> bool {
+    if alleles.iter().all(|allele| allele.len() == 1) {
+        return true;
+    }
+    if !use_indels {
+        return false;
+    }
+    alleles.iter().all(|allele| {
+        !allele.is_empty()
+            && allele
+                .iter()
+                .all(|x| matches!(x.to_ascii_uppercase(), b'A' | b'C' | b'G' | b'T' | b'N'))
+    })
+}
+
+//Get the column of the sample given by site_filter. If no sample is given, the VCF must have at
+//most one sample. Returns None for VCFs without samples.
+fn get_sample_index(header: &bcf::header::HeaderView, site_filter: &SiteFilter) -> Option<usize> {
+    let samples = header.samples();
+    match &site_filter.sample {
+        Some(sample) => match samples.iter().position(|x| *x == sample.as_bytes()) {
+            Some(index) => Some(index),
+            None => panic!("Sample {} not found in the VCF file.", sample),
+        },
+        None => {
+            if samples.len() > 1 {
+                panic!("More than 1 sample detected in header of vcf file; please choose a sample with --sample");
+            }
+            if samples.is_empty() {
+                None
+            } else {
+                Some(0)
+            }
+        }
+    }
+}
+
+//Whether the genotype of the sample at a record is homozygous reference or missing.
+fn is_hom_ref_or_missing(rec: &bcf::Record, sample_index: usize) -> bool {
+    let genotypes = match rec.genotypes() {
+        Ok(genotypes) => genotypes,
+        Err(_) => return false,
+    };
+    genotypes.get(sample_index).iter().all(|allele| match allele {
+        GenotypeAllele::Unphased(x) | GenotypeAllele::Phased(x) => *x == 0,
+        GenotypeAllele::UnphasedMissing | GenotypeAllele::PhasedMissing => true,
+    })
+}
+
+//Whether a VCF record is used as a site for phasing.
+fn site_passed_check(
+    rec: &bcf::Record,
+    sample_index: Option<usize>,
+    site_filter: &SiteFilter,
+) -> bool {
+    site_filter_reason(rec, sample_index, site_filter).is_none()
+}
+
+//Get the reason a VCF record is not used as a site for phasing, or None if it is used. Sites
+//missing the INFO/DP or FORMAT/AD needed for a filter are filtered.
+pub fn site_filter_reason(
+    rec: &bcf::Record,
+    sample_index: Option<usize>,
+    site_filter: &SiteFilter,
+) -> Option<&'static str> {
+    if !is_phasable_site(&rec.alleles(), site_filter.use_indels) {
+        if site_filter.use_indels {
+            return Some("unsupported_alleles");
+        } else {
+            return Some("not_snp");
+        }
+    }
+    if site_filter.pass_only && !rec.has_filter("PASS".as_bytes()) {
+        return Some("not_pass");
+    }
+    if site_filter.min_qual > 0.0 && (rec.qual().is_missing() || rec.qual() < site_filter.min_qual)
+    {
+        return Some("low_qual");
+    }
+    if site_filter.min_depth > 0 || site_filter.max_depth < i32::MAX {
+        let depth = match rec.info(b"DP").integer() {
+            Ok(Some(depth)) if !depth.is_empty() && !depth[0].is_missing() => depth[0],
+            _ => return Some("missing_depth"),
+        };
+        if depth < site_filter.min_depth {
+            return Some("low_depth");
+        }
+        if depth > site_filter.max_depth {
+            return Some("high_depth");
+        }
+    }
+    if site_filter.min_allele_frac > 0.0 {
+        let allele_frac = sample_index.and_then(|sample_index| {
+            let ad = rec.format(b"AD").integer().ok()?;
+            //Missing values and the vector end marker are negative.
+            let counts: Vec<i32> = ad[sample_index].iter().map(|x| i32::max(*x, 0)).collect();
+            let total: i32 = counts.iter().sum();
+            if total == 0 {
+                return None;
+            }
+            Some((total - counts[0]) as f64 / total as f64)
+        });
+        match allele_frac {
+            Some(allele_frac) if allele_frac >= site_filter.min_allele_frac => {}
+            Some(_) => return Some("low_allele_frac"),
+            None => return Some("missing_ad"),
+        }
+    }
+    if !site_filter.exclude_regions.is_empty() {
+        let contig = str::from_utf8(rec.header().rid2name(rec.rid().unwrap()).unwrap()).unwrap();
+        let start = rec.pos() as u64;
+        let end = start + rec.alleles()[0].len() as u64;
+        //Regions are merged, so only the last region starting before the site can overlap it.
+        let regions = &site_filter.exclude_regions;
+        let index = regions.partition_point(|region| {
+            (region.contig.as_str(), region.start) < (contig, end)
+        });
+        if index > 0 {
+            let region = &regions[index - 1];
+            if region.contig == contig && region.end.map_or(true, |region_end| region_end > start) {
+                return Some("excluded_region");
+            }
+        }
+    }
+    if site_filter.skip_hom_ref {
+        if let Some(sample_index) = sample_index {
+            if is_hom_ref_or_missing(rec, sample_index) {
+                return Some("hom_ref_or_missing");
+            }
+        }
+    }
+    None
+}
+
+//Get the interval [start, end) on the read sequence which is aligned to the reference interval
+//[ref_start, ref_end), including insertions. Returns None if the alignment doesn't span the
+//interval.
+fn get_read_interval(record: &bam::Record, ref_start: i64, ref_end: i64) -> Option<(usize, usize)> {
+    let mut ref_pos = record.pos();
+    let mut read_pos = 0;
+    let mut read_start = None;
+    let mut read_end = None;
+    for op in record.cigar().iter() {
+        match op {
+            Cigar::Match(l) | Cigar::Equal(l) | Cigar::Diff(l) => {
+                let l = *l as i64;
+                if read_start.is_none() && ref_start >= ref_pos && ref_start < ref_pos + l {
+                    read_start = Some(read_pos + (ref_start - ref_pos) as usize);
+                }
+                if ref_end >= ref_pos && ref_end < ref_pos + l {
+                    read_end = Some(read_pos + (ref_end - ref_pos) as usize);
+                    break;
+                }
+                ref_pos += l;
+                read_pos += l as usize;
+            }
+            Cigar::Del(l) | Cigar::RefSkip(l) => {
+                let l = *l as i64;
+                if read_start.is_none() && ref_start >= ref_pos && ref_start < ref_pos + l {
+                    read_start = Some(read_pos);
+                }
+                if ref_end >= ref_pos && ref_end < ref_pos + l {
+                    read_end = Some(read_pos);
+                    break;
+                }
+                ref_pos += l;
+            }
+            Cigar::Ins(l) | Cigar::SoftClip(l) => {
+                read_pos += *l as usize;
+            }
+            Cigar::HardClip(_) | Cigar::Pad(_) => {}
+        }
+    }
+    match (read_start, read_end) {
+        (Some(start), Some(end)) if start <= end => Some((start, end)),
+        _ => None,
+    }
+}
+
+type IndelAligner = Aligner<fn(u8, u8) -> i32>;
+
+fn indel_score(a: u8, b: u8) -> i32 {
+    if a == b.to_ascii_uppercase() {
+        1
+    } else {
+        -1
+    }
+}
+
+//Aligner for call_variant_allele. It is reused for all reads of a BAM file so that its buffers
+//aren't allocated for every call.
+fn get_indel_aligner() -> IndelAligner {
+    Aligner::new(-5, -1, indel_score as fn(u8, u8) -> i32)
+}
+
+//Call the allele of an indel/MNP for an alignment by realigning the part of the read over the
+//variant against each allele. Returns (allele, quality, position on read) if a single allele has
+//the best alignment score. The quality is the mean base quality of the read over the variant and
+//the position is where the variant starts on the read, with or without flanks.
+//
+//If the reference flanks of the variant are given and the read spans them, the read is realigned
+//against flank + allele + flank instead so that the call doesn't depend on where the aligner
+//placed the indel.
+fn call_variant_allele(
+    record: &bam::Record,
+    var_pos: i64,
+    alleles: &Vec<Vec<u8>>,
+    flanks: Option<&(Vec<u8>, Vec<u8>)>,
+    aligner: &mut IndelAligner,
+) -> Option<(usize, u8, usize)> {
+    let ref_len = alleles[0].len() as i64;
+    let (var_start, var_end) = get_read_interval(record, var_pos, var_pos + ref_len)?;
+    let mut left_flank: &[u8] = &[];
+    let mut right_flank: &[u8] = &[];
+    let (mut read_start, mut read_end) = (var_start, var_end);
+    if let Some((left, right)) = flanks {
+        if let Some(interval) = get_read_interval(
+            record,
+            var_pos - left.len() as i64,
+            var_pos + ref_len + right.len() as i64,
+        ) {
+            read_start = interval.0;
+            read_end = interval.1;
+            left_flank = left;
+            right_flank = right;
+        }
+    }
+    //Only the part of the read over the variant is decoded.
+    let seq = record.seq();
+    if seq.len() < read_end || seq.is_empty() {
+        return None;
+    }
+    let read_seg: Vec<u8> = (read_start..read_end).map(|i| seq[i]).collect();
+
+    let mut best_allele = 0;
+    let mut best_score = i32::MIN;
+    let mut second_best_score = i32::MIN;
+    for (i, allele) in alleles.iter().enumerate() {
+        let haplotype = [left_flank, allele.as_slice(), right_flank].concat();
+        let aln_score = aligner.global(&read_seg, &haplotype).score;
+        if aln_score > best_score {
+            second_best_score = best_score;
+            best_score = aln_score;
+            best_allele = i;
+        } else if aln_score > second_best_score {
+            second_best_score = aln_score;
+        }
+    }
+    if best_score == second_best_score {
+        return None;
+    }
+
+    let quals = record.qual();
+    let qual = if var_start == var_end {
+        quals[usize::min(var_start, quals.len() - 1)]
+    } else {
+        (quals[var_start..var_end]
+            .iter()
+            .map(|x| *x as usize)
+            .sum::<usize>()
+            / (var_end - var_start)) as u8
+    };
+    Some((best_allele, qual, var_start))
+}
+
+//Parse a region of the form contig, contig:start or contig:start-end. Coordinates are 1-based and
+//inclusive as in samtools.
+pub fn parse_region_string(region_string: &str) -> GenomeRegion {
+    let region_string = region_string.replace(",", "");
+    if let Some((contig, range)) = region_string.rsplit_once(':') {
+        let mut split = range.split('-');
+        let start = split.next().unwrap().parse::<u64>();
+        let end = match split.next() {
+            Some(end) => end.parse::<u64>().map(Some),
+            None => Ok(None),
+        };
+        if let (Ok(start), Ok(end)) = (start, end) {
+            if start == 0 || end.map_or(false, |end| end < start) {
+                panic!("Region {} is not a valid region.", region_string);
+            }
+            return GenomeRegion {
+                contig: contig.to_string(),
+                start: start - 1,
+                end,
+            };
+        }
+    }
+    GenomeRegion {
+        contig: region_string.to_string(),
+        start: 0,
+        end: None,
+    }
+}
+
+//Read regions from a BED file (0-based, end exclusive).
+pub fn get_regions_from_bed<P>(bed_file: P) -> Vec<GenomeRegion>
+where
+    P: AsRef<Path>,
+{
+    let mut regions = vec![];
+    let lines = match read_lines(bed_file) {
+        Ok(lines) => lines,
+        Err(_) => panic!("Could not read BED file. Exiting."),
+    };
+    for line in lines {
+        let line = line.unwrap();
+        if line.is_empty()
+            || line.starts_with('#')
+            || line.starts_with("track")
+            || line.starts_with("browser")
+        {
+            continue;
+        }
+        let split: Vec<&str> = line.split('\t').collect();
+        if split.len() < 3 {
+            panic!("BED line {} has less than 3 columns.", line);
+        }
+        let start = split[1].parse::<u64>().expect("BED start must be an integer");
+        let end = split[2].parse::<u64>().expect("BED end must be an integer");
+        regions.push(GenomeRegion {
+            contig: split[0].to_string(),
+            start,
+            end: Some(end),
+        });
+    }
+    regions
+}
+
+//Sort and merge overlapping regions so that every variant is visited once, in order.
+pub fn merge_regions(mut regions: Vec<GenomeRegion>) -> Vec<GenomeRegion> {
+    regions.sort_by(|a, b| {
+        a.contig
+            .cmp(&b.contig)
+            .then(a.start.cmp(&b.start))
+    });
+    let mut merged_regions: Vec<GenomeRegion> = vec![];
+    for region in regions {
+        if let Some(last) = merged_regions.last_mut() {
+            if last.contig == region.contig && last.end.map_or(true, |end| region.start <= end) {
+                last.end = match (last.end, region.end) {
+                    (Some(x), Some(y)) => Some(u64::max(x, y)),
+                    _ => None,
+                };
+                continue;
+            }
+        }
+        merged_regions.push(region);
+    }
+    merged_regions
+}
+
+//The regions on a contig, or the whole contig if no regions were given.
+pub fn get_contig_regions(regions: &Vec<GenomeRegion>, contig: &str) -> Vec<GenomeRegion> {
+    if regions.is_empty() {
+        return vec![GenomeRegion {
+            contig: contig.to_string(),
+            start: 0,
+            end: None,
+        }];
+    }
+    regions
+        .iter()
+        .filter(|region| region.contig == contig)
+        .cloned()
+        .collect()
+}
+
+//Some variant callers (e.g. LoFreq) don't write ##contig header lines, which we need to resolve
+//the contigs of records. If the VCF header has no contig lines at all, write a copy of the VCF to
+//out_file (as an indexed, bgzipped VCF) with contig lines taken from the BAM header and return
+//out_file. Otherwise the VCF is used as is; BAM contigs that aren't in the VCF header (e.g. decoy
+//or unplaced contigs) have no variants to phase.
+pub fn add_missing_contig_headers(
+    vcf_file: &str,
+    bam_file: &str,
+    reference: Option<&str>,
+    out_file: &str,
+) -> String {
+    let mut vcf = match bcf::Reader::from_path(vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+    };
+    let has_contigs = vcf
+        .header()
+        .header_records()
+        .iter()
+        .any(|record| matches!(record, bcf::header::HeaderRecord::Contig { .. }));
+    if has_contigs {
+        return vcf_file.to_string();
+    }
+
+    let bam = get_bam_reader(bam_file, reference);
+    let bam_header = bam.header();
+    let mut header = bcf::Header::from_template(vcf.header());
+    for (tid, name) in bam_header.target_names().iter().enumerate() {
+        let name = String::from_utf8_lossy(name).to_string();
+        let length = bam_header.target_len(tid as u32).unwrap();
+        header.push_record(format!("##contig=<ID={},length={}>", name, length).as_bytes());
+    }
+    log::info!(
+        "Adding {} contig header lines from the BAM header to the VCF; writing {}.",
+        bam_header.target_count(),
+        out_file
+    );
+
+    if let Some(parent) = Path::new(out_file).parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    {
+        let mut writer =
+            match bcf::Writer::from_path(out_file, &header, false, bcf::Format::Vcf) {
+                Ok(writer) => writer,
+                Err(_) => panic!("rust_htslib had an error while writing the VCF with contig headers. Exiting."),
+            };
+        let mut num_skipped = 0;
+        for rec in vcf.records() {
+            let mut rec = rec.unwrap();
+            //htslib adds contigs which aren't in the header to the reader's header as it goes, so
+            //the record's contig is looked up by name in the new header.
+            let contig = rec.header().rid2name(rec.rid().unwrap()).unwrap().to_vec();
+            match writer.header().name2rid(&contig) {
+                Ok(rid) => rec.set_rid(Some(rid)),
+                Err(_) => {
+                    num_skipped += 1;
+                    continue;
+                }
+            }
+            writer.write(&rec).unwrap();
+        }
+        if num_skipped > 0 {
+            log::warn!(
+                "{} VCF records on contigs not in the BAM header were skipped.",
+                num_skipped
+            );
+        }
+    }
+
+    //Index so that regions can be fetched.
+    let c_out_file = std::ffi::CString::new(out_file).unwrap();
+    if unsafe { rust_htslib::htslib::bcf_index_build(c_out_file.as_ptr(), 14) } != 0 {
+        panic!("rust_htslib had an error while indexing the VCF with contig headers. Exiting.");
+    }
+    out_file.to_string()
+}
+
+//Call f on every VCF record, or only on the records in the regions if regions is non-empty. The
+//latter requires an indexed (.tbi/.csi) VCF.
+fn visit_vcf_records<P, F>(vcf_file: P, regions: &Vec<GenomeRegion>, mut f: F)
+where
+    P: AsRef<Path>,
+    F: FnMut(&mut bcf::Record),
+{
+    if regions.is_empty() {
+        let mut vcf = match bcf::Reader::from_path(vcf_file) {
+            Ok(vcf) => vcf,
+            Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+        };
+        for rec in vcf.records() {
+            let mut rec = rec.unwrap();
+            f(&mut rec);
+        }
+        return;
+    }
+
+    let mut vcf = match bcf::IndexedReader::from_path(vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF index. A bgzipped and indexed VCF (.tbi/.csi) is required when specifying regions. Exiting."),
+    };
+    for region in regions.iter() {
+        let rid = match vcf.header().name2rid(region.contig.as_bytes()) {
+            Ok(rid) => rid,
+            Err(_) => {
+                log::warn!("Region contig {} not found in VCF header. Skipping.", region.contig);
+                continue;
+            }
+        };
+        if region.end == Some(0) {
+            continue;
+        }
+        //The end for fetch is inclusive.
+        if vcf.fetch(rid, region.start, region.end.map(|end| end - 1)).is_err() {
+            continue;
+        }
+        for rec in vcf.records() {
+            let mut rec = rec.unwrap();
+            let pos = rec.pos() as u64;
+            if pos < region.start || region.end.map_or(false, |end| pos >= end) {
+                continue;
             }
+            f(&mut rec);
         }
     }
-
-    ref_vec_frags
 }
 
 //Read a vcf file to get the genotypes. We read genotypes into a dictionary of keypairs where the
@@ -478,6 +1337,8 @@ where
 //1 1 0 0 at position 5 would be (5,{1 : 2, 0 : 2}).
 pub fn get_genotypes_from_vcf_hts<P>(
     vcf_file: P,
+    regions: &Vec<GenomeRegion>,
+    site_filter: &SiteFilter,
 ) -> (
     FxHashMap<String, Vec<usize>>,
     FxHashMap<String, FxHashMap<usize, FxHashMap<usize, usize>>>,
@@ -486,7 +1347,7 @@ pub fn get_genotypes_from_vcf_hts<P>(
 where
     P: AsRef<Path>,
 {
-    let mut vcf = match bcf::Reader::from_path(vcf_file) {
+    let vcf = match bcf::Reader::from_path(&vcf_file) {
         Ok(vcf) => vcf,
         Err(_) => panic!("rust_htslib had an error while reading the BAM file. Exiting."),
     };
@@ -498,9 +1359,7 @@ where
     let mut snp_counter = 1;
     let mut vcf_ploidy = 0;
 
-    if header.sample_count() > 1 {
-        panic!("More than 1 sample detected in header of vcf file; please use only 1 sample");
-    }
+    let sample_index = get_sample_index(&header, site_filter);
 
     //    if header.contig_count() > 1 {
     //        panic!("More than 1 contig detected in header of vcf file; please use only 1 contig/reference per vcf file.");
@@ -508,10 +1367,7 @@ where
 
     let mut last_ref_chrom: &[u8] = &[];
 
-    for rec in vcf.records() {
-        let unr = rec.unwrap();
-        let alleles = unr.alleles();
-        let mut is_snp = true;
+    visit_vcf_records(&vcf_file, regions, |unr| {
         let record_rid = unr.rid().unwrap();
         let ref_chrom_vcf = header.rid2name(record_rid).unwrap();
         if last_ref_chrom != ref_chrom_vcf {
@@ -519,23 +1375,16 @@ where
             snp_counter = 1;
         }
 
-        for allele in alleles.iter() {
-            if allele.len() > 1 {
-                is_snp = false;
-                break;
-            }
-        }
-
-        if !is_snp {
+        if !site_passed_check(unr, sample_index, site_filter) {
             //            println!(
             //                "VCF : Variant at position {} is not a snp. Ignoring.",
             //                unr.pos()
             //            );
-            continue;
+            return;
         }
 
-        if let Ok(_) = unr.genotypes() {
-            let genotypes = unr.genotypes().unwrap().get(0);
+        if let (Ok(_), Some(sample_index)) = (unr.genotypes(), sample_index) {
+            let genotypes = unr.genotypes().unwrap().get(sample_index);
             vcf_ploidy = genotypes.len();
             let mut genotype_counter = FxHashMap::default();
             for allele in genotypes.iter() {
@@ -574,40 +1423,82 @@ where
         //+1 because htslib is 0 index by default
         positions_vec.push(unr.pos() as usize + 1);
         snp_counter += 1;
-    }
+    });
 
     (map_positions_vec, map_genotype_dict, vcf_ploidy)
 }
 
+//Write the VCF records which are not used for phasing to a tab separated file with the reason
+//they were filtered; see site_filter_reason. Positions are 1-based.
+pub fn write_filtered_sites<P>(
+    vcf_file: P,
+    regions: &Vec<GenomeRegion>,
+    site_filter: &SiteFilter,
+    out_file: &str,
+) where
+    P: AsRef<Path>,
+{
+    let vcf = match bcf::Reader::from_path(&vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+    };
+    let sample_index = get_sample_index(vcf.header(), site_filter);
+    if let Some(parent) = Path::new(out_file).parent() {
+        fs::create_dir_all(parent).unwrap();
+    }
+    let file = File::create(out_file).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+    writeln!(file, "#contig\tpos\tref\talt\treason").unwrap();
+    visit_vcf_records(&vcf_file, regions, |rec| {
+        let reason = match site_filter_reason(rec, sample_index, site_filter) {
+            Some(reason) => reason,
+            None => return,
+        };
+        let contig = rec.header().rid2name(rec.rid().unwrap()).unwrap().to_vec();
+        let alleles: Vec<String> = rec
+            .alleles()
+            .iter()
+            .map(|allele| String::from_utf8_lossy(allele).to_string())
+            .collect();
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}",
+            String::from_utf8_lossy(&contig),
+            rec.pos() + 1,
+            alleles[0],
+            alleles[1..].join(","),
+            reason
+        )
+        .unwrap();
+    });
+}
+
 //Convert a fragment which stores sequences in a dictionary format to a block format which makes
 //writing to frag files easier.
-fn convert_dict_to_block(frag: Frag) -> (Vec<usize>, Vec<Vec<usize>>, Vec<u8>) {
-    let d = frag.seq_dict;
-    let vec_d: BTreeMap<usize, usize> = d.into_iter().collect();
-    let vec_q: BTreeMap<usize, u8> = frag.qual_dict.into_iter().collect();
+fn convert_dict_to_block(frag: &Frag) -> (Vec<usize>, Vec<Vec<usize>>, Vec<u8>) {
     let mut prev_pos = 0;
     let mut block_start_pos = Vec::new();
     let mut blocks = Vec::new();
     let mut block = Vec::new();
     let mut qual_block = Vec::new();
 
-    for (pos, var) in &vec_d {
+    for (pos, var) in frag.calls() {
         if prev_pos == 0 {
-            prev_pos = *pos;
-            block.push(*var);
-            block_start_pos.push(*pos);
+            prev_pos = pos;
+            block.push(var);
+            block_start_pos.push(pos);
         } else if pos - prev_pos > 1 {
             blocks.push(block);
-            block = vec![*var];
-            block_start_pos.push(*pos);
-            prev_pos = *pos;
+            block = vec![var];
+            block_start_pos.push(pos);
+            prev_pos = pos;
         } else if pos - prev_pos == 1 {
-            block.push(*var);
-            prev_pos = *pos;
+            block.push(var);
+            prev_pos = pos;
         }
     }
 
-    for (_pos, q) in &vec_q {
+    for q in frag.quals.iter() {
         qual_block.push(*q);
     }
 
@@ -615,39 +1506,298 @@ fn convert_dict_to_block(frag: Frag) -> (Vec<usize>, Vec<Vec<usize>>, Vec<u8>) {
     (block_start_pos, blocks, qual_block)
 }
 
+//Write the Hi-C scaffolds of a contig: hic_scaffolds.tsv lists the haplotigs of each scaffold with
+//their SNP range and phase set, and hic_joins.tsv lists every candidate join with its number of
+//links, confidence and whether it was made.
+pub fn write_hic_scaffolds(
+    out_dir: &str,
+    snp_range_parts_vec: &Vec<(usize, usize)>,
+    joins: &Vec<HaplotigJoin>,
+    scaffolds: &Vec<usize>,
+    phase_sets: &Vec<i32>,
+) {
+    fs::create_dir_all(out_dir).unwrap();
+    let file = File::create(format!("{}/hic_scaffolds.tsv", out_dir)).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+    writeln!(file, "#scaffold\thaplotig\tfirst_snp\tlast_snp\tphase_set").unwrap();
+    let mut order: Vec<usize> = (0..scaffolds.len()).collect();
+    order.sort_by_key(|i| (scaffolds[*i], snp_range_parts_vec[*i], *i));
+    for i in order {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{}",
+            scaffolds[i], i, snp_range_parts_vec[i].0, snp_range_parts_vec[i].1, phase_sets[i]
+        )
+        .unwrap();
+    }
+
+    let file = File::create(format!("{}/hic_joins.tsv", out_dir)).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+    writeln!(file, "#left\tright\tlinks\tconfidence\tjoined").unwrap();
+    for join in joins.iter() {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{:.3}\t{}",
+            join.left, join.right, join.links, join.confidence, join.joined
+        )
+        .unwrap();
+    }
+}
+
+//Write the abundance estimates of a contig's haplotigs to abundances.tsv; see
+//abundance::get_haplotig_abundances.
+pub fn write_haplotig_abundances(
+    out_dir: &str,
+    snp_range_parts_vec: &Vec<(usize, usize)>,
+    abundances: &Vec<HaplotigAbundance>,
+) {
+    fs::create_dir_all(out_dir).unwrap();
+    let file = File::create(format!("{}/abundances.tsv", out_dir)).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+    writeln!(
+        file,
+        "#haplotig\tfirst_snp\tlast_snp\tnum_reads\tmean_flow\tflow_variance\tcoverage\tabundance\tabundance_ci_low\tabundance_ci_high"
+    )
+    .unwrap();
+    for (i, abundance) in abundances.iter().enumerate() {
+        writeln!(
+            file,
+            "{}\t{}\t{}\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.4}\t{:.4}\t{:.4}",
+            i,
+            snp_range_parts_vec[i].0,
+            snp_range_parts_vec[i].1,
+            abundance.num_reads,
+            abundance.mean_flow,
+            abundance.flow_variance,
+            abundance.coverage,
+            abundance.abundance,
+            abundance.abundance_ci.0,
+            abundance.abundance_ci.1
+        )
+        .unwrap();
+    }
+}
+
 //Write a vector of sorted fragment files by first position (no guarantees on end position) to a
 //file in the same format as H-PoP and other haplotypers.
 pub fn write_frags_file(frags: Vec<Frag>, filename: String) {
     let file = File::create(filename).expect("Can't create file");
     let mut file = LineWriter::new(file);
-    for frag in frags.into_iter() {
-        let frag_id = frag.id.clone();
-        let (start_vec, blocks, qual_block) = convert_dict_to_block(frag);
-        if start_vec.len() != blocks.len() {
-            dbg!(start_vec.len(), blocks.len());
-            panic!("Block length diff");
-        }
-
-        write!(file, "{}\t", blocks.len()).unwrap();
-        write!(file, "{}\t", frag_id).unwrap();
-        for i in 0..blocks.len() {
-            write!(file, "{}\t", start_vec[i]).unwrap();
-            for var in blocks[i].iter() {
-                write!(file, "{}", *var).unwrap();
+    for frag in frags.iter() {
+        write_block_frag_line(&mut file, frag, None);
+    }
+}
+
+//Write the fragments of every contig in HapCUT2's extended format. HapCUT2 numbers variants by
+//their record in the whole VCF, so SNP i of a contig is written as vcf_record_indices[contig][i-1];
+//see get_vcf_record_indices.
+pub fn write_hapcut2_frags_file(
+    frags_map: &FxHashMap<String, Vec<Frag>>,
+    vcf_record_indices: &FxHashMap<String, Vec<usize>>,
+    filename: &str,
+) {
+    let file = File::create(filename).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+    let mut contigs: Vec<&String> = frags_map.keys().collect();
+    contigs.sort_by_key(|contig| (vcf_record_indices.get(*contig).map(|x| x.first()), *contig));
+    for contig in contigs {
+        let record_indices = match vcf_record_indices.get(contig) {
+            Some(record_indices) => record_indices,
+            None => panic!("Contig {} has no VCF records for HapCUT2 output.", contig),
+        };
+        for frag in frags_map[contig].iter() {
+            let mut hapcut2_frag =
+                build_frag(frag.id.clone(), frag.counter_id, frag.is_paired, frag.source_file);
+            for i in 0..frag.num_calls() {
+                hapcut2_frag.add_call(
+                    record_indices[frag.snps[i] as usize - 1],
+                    frag.alleles[i] as usize,
+                    frag.quals[i],
+                    frag.mates[i],
+                    0,
+                );
             }
-            write!(file, "\t").unwrap();
+            //Index of the first SNP of the second mate, -1 if there is none.
+            let mate2_index = hapcut2_frag
+                .positions()
+                .zip(hapcut2_frag.mates.iter())
+                .find(|(_, mate)| **mate == 1)
+                .map(|(snp, _)| snp as i64)
+                .unwrap_or(-1);
+            let barcode = if frag.molecule_reads.is_empty() {
+                "-1"
+            } else {
+                frag.id.as_str()
+            };
+            write_block_frag_line(&mut file, &hapcut2_frag, Some((mate2_index, barcode)));
         }
+    }
+}
 
-        for q in qual_block.iter() {
-            if *q as usize + 33 > 255 {
-                write!(file, "{}", (*q) as char).unwrap();
-            } else {
-                write!(file, "{}", (*q + 33) as char).unwrap();
+//One line of an H-PoP style fragment file, or of HapCUT2's extended format if the mate and barcode
+//columns are given. Data type is always 0 (not Hi-C).
+fn write_block_frag_line<W: Write>(
+    file: &mut W,
+    frag: &Frag,
+    extended: Option<(i64, &str)>,
+) {
+    let (start_vec, blocks, qual_block) = convert_dict_to_block(frag);
+    if start_vec.len() != blocks.len() {
+        dbg!(start_vec.len(), blocks.len());
+        panic!("Block length diff");
+    }
+
+    write!(file, "{}\t", blocks.len()).unwrap();
+    write!(file, "{}\t", frag.id).unwrap();
+    if let Some((mate2_index, barcode)) = extended {
+        write!(file, "0\t{}\t{}\t", mate2_index, barcode).unwrap();
+    }
+    for i in 0..blocks.len() {
+        write!(file, "{}\t", start_vec[i]).unwrap();
+        for var in blocks[i].iter() {
+            if *var > 9 {
+                panic!("Alleles of 10 or more can only be written in the glopp fragment format.");
             }
+            write!(file, "{}", *var).unwrap();
         }
+        write!(file, "\t").unwrap();
+    }
 
-        write!(file, "\n").unwrap();
+    for q in qual_block.iter() {
+        if *q as usize + 33 > 255 {
+            write!(file, "{}", (*q) as char).unwrap();
+        } else {
+            write!(file, "{}", (*q + 33) as char).unwrap();
+        }
+    }
+
+    write!(file, "\n").unwrap();
+}
+
+//Write fragments in glopp's fragment format, which keeps the contig, source file, pairing and
+//linked-read molecule of each fragment, and allows any number of alleles:
+//
+//  ##glopp-frags-version=1
+//  ##sites=chr1 1042,1050,...
+//  #contig id source_file paired molecule_reads calls
+//  chr1 read1 0 0 . 1:1042:0:40:0,2:1050:1:38:0
+//
+//The optional ##sites lines give the genome position of every SNP of a contig, including SNPs
+//without fragments. Columns are tab separated. paired is 0 or 1, molecule_reads is a comma separated list of read
+//names ("." if the fragment is a single read) and calls is a comma separated list of
+//snp:genome_pos:allele:qual:mate, where snp is the 1-based SNP index within the contig,
+//genome_pos is the 1-based VCF position ("." if unknown), qual is the phred base quality and mate is
+//0 or 1 for the first or second read of a pair.
+pub fn write_glopp_frags_file(
+    frags_map: &FxHashMap<String, Vec<Frag>>,
+    snp_to_genome_pos_map: &FxHashMap<String, Vec<usize>>,
+    filename: &str,
+) {
+    let file = File::create(filename).expect("Can't create file");
+    let mut file = LineWriter::new(file);
+    writeln!(file, "##glopp-frags-version={}", FRAG_FORMAT_VERSION).unwrap();
+    let mut contigs: Vec<&String> = frags_map.keys().collect();
+    contigs.sort();
+    for contig in contigs.iter() {
+        if let Some(snp_to_genome_pos) = snp_to_genome_pos_map.get(*contig) {
+            let sites: Vec<String> = snp_to_genome_pos.iter().map(|x| x.to_string()).collect();
+            writeln!(file, "##sites={}\t{}", contig, sites.join(",")).unwrap();
+        }
+    }
+    writeln!(file, "#contig\tid\tsource_file\tpaired\tmolecule_reads\tcalls").unwrap();
+    for contig in contigs {
+        let snp_to_genome_pos = snp_to_genome_pos_map.get(contig);
+        for frag in frags_map[contig].iter() {
+            let calls: Vec<String> = (0..frag.num_calls())
+                .map(|i| {
+                    let snp = frag.snps[i] as usize;
+                    let genome_pos = match snp_to_genome_pos {
+                        Some(snp_to_genome_pos) if snp > 0 && snp_to_genome_pos.len() >= snp => {
+                            snp_to_genome_pos[snp - 1].to_string()
+                        }
+                        _ => String::from("."),
+                    };
+                    format!(
+                        "{}:{}:{}:{}:{}",
+                        snp, genome_pos, frag.alleles[i], frag.quals[i], frag.mates[i]
+                    )
+                })
+                .collect();
+            let molecule_reads = if frag.molecule_reads.is_empty() {
+                String::from(".")
+            } else {
+                frag.molecule_reads.join(",")
+            };
+            writeln!(
+                file,
+                "{}\t{}\t{}\t{}\t{}\t{}",
+                contig,
+                frag.id,
+                frag.source_file,
+                frag.is_paired as u8,
+                molecule_reads,
+                calls.join(",")
+            )
+            .unwrap();
+        }
+    }
+}
+
+//Contigs of the VCF header, in order.
+pub fn get_vcf_contigs<P>(vcf_file: P) -> Vec<String>
+where
+    P: AsRef<Path>,
+{
+    let vcf = match bcf::Reader::from_path(&vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+    };
+    let header = vcf.header();
+    (0..header.contig_count())
+        .map(|rid| String::from_utf8(header.rid2name(rid).unwrap().to_vec()).unwrap())
+        .collect()
+}
+
+//1-based index among all records of the VCF of each SNP of snp_to_genome_pos_map, which has the
+//sites of the same VCF passing site_filter (see get_genotypes_from_vcf_hts). Records that are
+//filtered out or outside the regions still count, as they do for HapCUT2.
+pub fn get_vcf_record_indices<P>(
+    vcf_file: P,
+    snp_to_genome_pos_map: &FxHashMap<String, Vec<usize>>,
+    site_filter: &SiteFilter,
+) -> FxHashMap<String, Vec<usize>>
+where
+    P: AsRef<Path>,
+{
+    let mut vcf = match bcf::Reader::from_path(&vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+    };
+    let header = vcf.header().clone();
+    let sample_index = get_sample_index(&header, site_filter);
+    let mut record_indices_map: FxHashMap<String, Vec<usize>> = FxHashMap::default();
+    for (i, rec) in vcf.records().enumerate() {
+        let rec = rec.unwrap();
+        let contig = String::from_utf8(header.rid2name(rec.rid().unwrap()).unwrap().to_vec()).unwrap();
+        let snp_to_genome_pos = match snp_to_genome_pos_map.get(&contig) {
+            Some(snp_to_genome_pos) => snp_to_genome_pos,
+            None => continue,
+        };
+        let record_indices = record_indices_map.entry(contig).or_insert(vec![]);
+        //SNPs are in the order of the records, so this record is the next SNP if it is a SNP.
+        if record_indices.len() < snp_to_genome_pos.len()
+            && snp_to_genome_pos[record_indices.len()] == rec.pos() as usize + 1
+            && site_passed_check(&rec, sample_index, site_filter)
+        {
+            record_indices.push(i + 1);
+        }
+    }
+    for (contig, snp_to_genome_pos) in snp_to_genome_pos_map.iter() {
+        if record_indices_map.get(contig).map_or(0, |x| x.len()) != snp_to_genome_pos.len() {
+            panic!("SNPs of contig {} not found in the VCF in order.", contig);
+        }
     }
+    record_indices_map
 }
 
 pub fn write_output_partition_to_file(
@@ -657,10 +1807,17 @@ pub fn write_output_partition_to_file(
     contig: &String,
     snp_pos_to_genome_pos: &Vec<usize>,
 ) {
+    //Fragments read without their read sequences (see get_frags_from_bamvcf) or from fragment
+    //files have no reads to write.
+    let write_reads = part
+        .iter()
+        .any(|set| set.iter().any(|frag| frag.seq_string.iter().any(|seq| seq.len() != 0)));
     fs::create_dir_all(&out_bam_part_dir).unwrap();
     fs::create_dir_all(&format!("{}/local_parts", out_bam_part_dir)).unwrap();
-    fs::create_dir_all(&format!("{}/short_reads", out_bam_part_dir)).unwrap();
-    fs::create_dir_all(&format!("{}/long_reads", out_bam_part_dir)).unwrap();
+    if write_reads {
+        fs::create_dir_all(&format!("{}/short_reads", out_bam_part_dir)).unwrap();
+        fs::create_dir_all(&format!("{}/long_reads", out_bam_part_dir)).unwrap();
+    }
     fs::create_dir_all(&format!("{}/haplotypes", out_bam_part_dir)).unwrap();
 
     let contig_path = &format!("{}/{}_part.txt", out_bam_part_dir, contig);
@@ -674,6 +1831,16 @@ pub fn write_output_partition_to_file(
         let mut vec_part: Vec<&&Frag> = set.into_iter().collect();
         vec_part.sort_by(|a, b| a.first_position.cmp(&b.first_position));
         write!(file, "#{}\n", i).unwrap();
+        for frag in vec_part.iter() {
+            write!(
+                file,
+                "{}\t{}\t{}\n",
+                frag.id.clone(),
+                frag.first_position,
+                frag.last_position
+            )
+            .unwrap();
+        }
 
         //Non-empty means that we're writing the final partition after path collection
         if !snp_range_parts_vec.is_empty() {
@@ -690,6 +1857,9 @@ pub fn write_output_partition_to_file(
                 &snp_pos_to_genome_pos,
                 append,
             );
+            if !write_reads {
+                continue;
+            }
 
             let part_fastq_reads = format!("{}/long_reads/{}_part.fastq", out_bam_part_dir, i);
             let part_fastq_reads_paired1 =
@@ -735,8 +1905,7 @@ pub fn write_output_partition_to_file(
                 let mut tmp = left_snp_pos;
                 let left_read_pair;
                 loop {
-                    if frag.snp_pos_to_seq_pos.contains_key(&tmp) {
-                        let info = frag.snp_pos_to_seq_pos[&tmp];
+                    if let Some(info) = frag.get_seq_pos(tmp) {
                         left_seq_pos = info.1;
                         left_read_pair = info.0;
                         break;
@@ -748,7 +1917,7 @@ pub fn write_output_partition_to_file(
                             &frag.last_position,
                             left_snp_pos,
                             right_snp_pos,
-                            &frag.snp_pos_to_seq_pos,
+                            &frag.snps,
                         );
                         panic!();
                     }
@@ -763,14 +1932,13 @@ pub fn write_output_partition_to_file(
                 let mut tmp = right_snp_pos;
                 let right_read_pair;
                 loop {
-                    if frag.snp_pos_to_seq_pos.contains_key(&tmp) {
-                        let info = frag.snp_pos_to_seq_pos[&tmp];
+                    if let Some(info) = frag.get_seq_pos(tmp) {
                         right_seq_pos = info.1;
                         right_read_pair = info.0;
                         break;
                     }
                     if tmp == 0 {
-                        dbg!(&frag.positions, left_snp_pos, right_snp_pos);
+                        dbg!(&frag.snps, left_snp_pos, right_snp_pos);
                     }
                     tmp -= 1;
                 }
@@ -803,24 +1971,13 @@ pub fn write_output_partition_to_file(
                         .write(
                             &frag.id,
                             None,
-                            &frag.seq_string[0].as_slice()[left_seq_pos..right_seq_pos + 1],
+                            &frag.seq_string[0].slice(left_seq_pos, right_seq_pos + 1),
                             &frag.qual_string[0].as_slice()[left_seq_pos..right_seq_pos + 1],
                         )
                         .unwrap();
                 }
             }
         }
-
-        for frag in vec_part {
-            write!(
-                file,
-                "{}\t{}\t{}\n",
-                frag.id.clone(),
-                frag.first_position,
-                frag.last_position
-            )
-            .unwrap();
-        }
     }
 }
 
@@ -848,7 +2005,7 @@ fn write_paired_reads_no_trim<W: Write>(
             .write(
                 &format!("{}/1", frag.id),
                 None,
-                &frag.seq_string[0],
+                &frag.seq_string[0].to_bytes(),
                 &frag.qual_string[0],
             )
             .unwrap();
@@ -868,7 +2025,7 @@ fn write_paired_reads_no_trim<W: Write>(
             .write(
                 &format!("{}/2", frag.id),
                 None,
-                &revcomp(&frag.seq_string[1]),
+                &revcomp(&frag.seq_string[1].to_bytes()),
                 &frag.qual_string[1],
             )
             .unwrap();
@@ -904,7 +2061,7 @@ fn _write_paired_reads<W: Write>(
             .write(
                 &format!("{}/{}", frag.id, read_pair),
                 None,
-                &frag.seq_string[read_pair as usize].as_slice()[left_seq_pos..right_seq_pos + 1],
+                &frag.seq_string[read_pair as usize].slice(left_seq_pos, right_seq_pos + 1),
                 &frag.qual_string[read_pair as usize].as_slice()[left_seq_pos..right_seq_pos + 1],
             )
             .unwrap();
@@ -933,7 +2090,8 @@ fn _write_paired_reads<W: Write>(
                 .write(
                     &format!("{}/1", frag.id),
                     None,
-                    &frag.seq_string[left_read_pair as usize].as_slice()[left_seq_pos..],
+                    &frag.seq_string[left_read_pair as usize]
+                        .slice(left_seq_pos, frag.seq_string[left_read_pair as usize].len()),
                     &frag.qual_string[left_read_pair as usize].as_slice()[left_seq_pos..],
                 )
                 .unwrap();
@@ -957,7 +2115,7 @@ fn _write_paired_reads<W: Write>(
                     &format!("{}/2", frag.id),
                     None,
                     &revcomp(
-                        &frag.seq_string[right_read_pair as usize].as_slice()[..right_seq_pos],
+                        &frag.seq_string[right_read_pair as usize].slice(0, right_seq_pos),
                     ),
                     //TODO Do we need to flip this as well?
                     rev_quals.as_slice(),
@@ -967,26 +2125,365 @@ fn _write_paired_reads<W: Write>(
     }
 }
 
-pub fn alignment_passed_check(
-    flags: u16,
-    mapq: u8,
-    use_supplementary: bool,
-    filter_supplementary: bool,
-) -> (bool, bool) {
-    let errors_mask = 1796;
-    let secondary_mask = 256;
+//Write the haplotigs for a contig as a phased VCF. By default, each haplotig gets its own
+//(haploid) sample column with GT/PS/AD/DP. If polyploid_gt is true, a single sample is written
+//instead, where the GT joins the alleles of all haplotigs overlapping the site with '|'.
+//
+//PS is the genome position of the first SNP of the haplotig (or of the run of overlapping
+//haplotigs in polyploid mode). AD/DP are the allele counts of the reads assigned to the haplotig.
+pub fn write_phased_vcf<P>(
+    vcf_file: P,
+    part: &Vec<FxHashSet<&Frag>>,
+    snp_range_parts_vec: &Vec<(usize, usize)>,
+    out_dir: &str,
+    contig: &str,
+    snp_pos_to_genome_pos: &Vec<usize>,
+    phase_sets: &Vec<i32>,
+    polyploid_gt: bool,
+    regions: &Vec<GenomeRegion>,
+    site_filter: &SiteFilter,
+) where
+    P: AsRef<Path>,
+{
+    let vcf = match bcf::Reader::from_path(&vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+    };
+    let vcf_header = vcf.header().clone();
+    let sample_index = get_sample_index(&vcf_header, site_filter);
+    let mut header = bcf::Header::from_template_subset(&vcf_header, &[]).unwrap();
+
+    let mut existing_format = FxHashSet::default();
+    for record in vcf_header.header_records() {
+        if let bcf::header::HeaderRecord::Format { values, .. } = record {
+            if let Some(id) = values.get("ID") {
+                existing_format.insert(id.clone());
+            }
+        }
+    }
+    let format_lines = [
+        ("GT", "##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">"),
+        ("PS", "##FORMAT=<ID=PS,Number=1,Type=Integer,Description=\"Phase set\">"),
+        ("AD", "##FORMAT=<ID=AD,Number=R,Type=Integer,Description=\"Allele counts of reads assigned to the haplotig\">"),
+        ("DP", "##FORMAT=<ID=DP,Number=1,Type=Integer,Description=\"Number of reads assigned to the haplotig covering the site\">"),
+    ];
+    for (id, line) in format_lines.iter() {
+        if !existing_format.contains(*id) {
+            header.push_record(line.as_bytes());
+        }
+    }
+
+    if polyploid_gt {
+        if let Some(sample_index) = sample_index {
+            header.push_sample(vcf_header.samples()[sample_index]);
+        } else {
+            header.push_sample(b"glopp");
+        }
+    } else {
+        for i in 0..part.len() {
+            header.push_sample(format!("{}_{}", contig, i).as_bytes());
+        }
+    }
+
+    fs::create_dir_all(out_dir).unwrap();
+    let mut writer = match bcf::Writer::from_path(
+        format!("{}/phased.vcf", out_dir),
+        &header,
+        true,
+        bcf::Format::Vcf,
+    ) {
+        Ok(writer) => writer,
+        Err(_) => panic!("rust_htslib had an error while writing the phased VCF. Exiting."),
+    };
+
+    let hap_maps: Vec<FxHashMap<usize, FxHashMap<usize, usize>>> = part
+        .iter()
+        .map(|set| utils_frags::set_to_seq_dict(set))
+        .collect();
+
+    //Phase sets for polyploid output are runs of overlapping haplotigs. Haplotigs sharing a phase
+    //set (scaffolded with Hi-C) are in the same run.
+    let mut sorted_ranges = snp_range_parts_vec.clone();
+    let mut phase_set_spans: FxHashMap<i32, (usize, usize)> = FxHashMap::default();
+    for (i, range) in snp_range_parts_vec.iter().enumerate() {
+        let span = phase_set_spans.entry(phase_sets[i]).or_insert(*range);
+        span.0 = usize::min(span.0, range.0);
+        span.1 = usize::max(span.1, range.1);
+    }
+    sorted_ranges.extend(phase_set_spans.into_values());
+    sorted_ranges.sort();
+    let mut merged_ranges: Vec<(usize, usize)> = vec![];
+    for range in sorted_ranges {
+        if let Some(last) = merged_ranges.last_mut() {
+            if range.0 <= last.1 {
+                last.1 = usize::max(last.1, range.1);
+                continue;
+            }
+        }
+        merged_ranges.push(range);
+    }
+
+    let genome_pos = |snp: usize| -> i32 {
+        if snp_pos_to_genome_pos.len() >= snp && snp > 0 {
+            snp_pos_to_genome_pos[snp - 1] as i32
+        } else {
+            snp as i32
+        }
+    };
+
+    //SNP indices must agree with get_frags_from_bamvcf, so the same sites are used.
+    let mut snp_counter = 0;
+    visit_vcf_records(&vcf_file, regions, |rec| {
+        let record_rid = rec.rid().unwrap();
+        if vcf_header.rid2name(record_rid).unwrap() != contig.as_bytes() {
+            return;
+        }
+        let num_alleles = rec.alleles().len();
+        if !site_passed_check(rec, sample_index, site_filter) {
+            return;
+        }
+        snp_counter += 1;
+
+        writer.translate(rec);
+        writer.subset(rec);
+
+        let mut gts = vec![];
+        let mut ps = vec![];
+        let mut ad = vec![];
+        let mut dp = vec![];
+        if polyploid_gt {
+            let mut ad_sample = vec![0; num_alleles];
+            for (i, hap_map) in hap_maps.iter().enumerate() {
+                let range = snp_range_parts_vec[i];
+                if snp_counter < range.0 || snp_counter > range.1 {
+                    continue;
+                }
+                if let Some(allele_map) = hap_map.get(&snp_counter) {
+                    let best_allele = allele_map.iter().max_by_key(|entry| entry.1).unwrap().0;
+                    gts.push(GenotypeAllele::Phased(*best_allele as i32));
+                    for (allele, count) in allele_map.iter() {
+                        if *allele < num_alleles {
+                            ad_sample[*allele] += *count as i32;
+                        }
+                    }
+                }
+            }
+            if gts.is_empty() {
+                gts.push(GenotypeAllele::PhasedMissing);
+                ps.push(i32::missing());
+            } else {
+                let block = merged_ranges
+                    .iter()
+                    .find(|x| x.0 <= snp_counter && snp_counter <= x.1)
+                    .unwrap();
+                ps.push(genome_pos(block.0));
+            }
+            dp.push(ad_sample.iter().sum());
+            ad.extend(ad_sample);
+        } else {
+            for (i, hap_map) in hap_maps.iter().enumerate() {
+                let range = snp_range_parts_vec[i];
+                let allele_map = match hap_map.get(&snp_counter) {
+                    Some(allele_map) if snp_counter >= range.0 && snp_counter <= range.1 => {
+                        allele_map
+                    }
+                    _ => {
+                        gts.push(GenotypeAllele::PhasedMissing);
+                        ps.push(i32::missing());
+                        ad.extend(vec![i32::missing(); num_alleles]);
+                        dp.push(0);
+                        continue;
+                    }
+                };
+                let best_allele = allele_map.iter().max_by_key(|entry| entry.1).unwrap().0;
+                gts.push(GenotypeAllele::Phased(*best_allele as i32));
+                ps.push(phase_sets[i]);
+                let mut ad_sample = vec![0; num_alleles];
+                for (allele, count) in allele_map.iter() {
+                    if *allele < num_alleles {
+                        ad_sample[*allele] += *count as i32;
+                    }
+                }
+                dp.push(ad_sample.iter().sum());
+                ad.extend(ad_sample);
+            }
+        }
+
+        if !gts.is_empty() {
+            rec.push_genotypes(&gts).unwrap();
+            rec.push_format_integer(b"PS", &ps).unwrap();
+            rec.push_format_integer(b"AD", &ad).unwrap();
+            rec.push_format_integer(b"DP", &dp).unwrap();
+        }
+        writer.write(rec).unwrap();
+    });
+}
+
+//Write the reads of a contig to a BAM where every alignment in a haplotig is tagged with HP:i
+//(haplotig number) and PS:i (phase set of the haplotig; see scaffolding::get_phase_sets). Alignments which
+//aren't assigned or don't pass alignment_passed_check are passed through untagged. If
+//split_by_haplotig is true, the tagged alignments for haplotig i are also written to
+//bam_parts/i_part.bam. With more than one input BAM, each input gets its own output, e.g.
+//haplotagged_1.bam and bam_parts/i_part_1.bam for the second input.
+pub fn write_haplotagged_bam<P>(
+    bam_files: &[P],
+    part: &Vec<FxHashSet<&Frag>>,
+    out_dir: &str,
+    contig: &str,
+    phase_sets: &Vec<i32>,
+    split_by_haplotig: bool,
+    alignment_filter: &AlignmentFilter,
+    reference: Option<&str>,
+) where
+    P: AsRef<Path>,
+{
+    fs::create_dir_all(out_dir).unwrap();
+    if split_by_haplotig {
+        fs::create_dir_all(format!("{}/bam_parts", out_dir)).unwrap();
+    }
+
+    for (source_file, bam_file) in bam_files.iter().enumerate() {
+        let mut read_to_tags = FxHashMap::default();
+        for (i, set) in part.iter().enumerate() {
+            let phase_set = phase_sets[i];
+            for frag in set.iter() {
+                if frag.source_file == source_file {
+                    if frag.molecule_reads.is_empty() {
+                        read_to_tags.insert(frag.id.as_bytes(), (i as i32, phase_set));
+                    } else {
+                        for read_name in frag.molecule_reads.iter() {
+                            read_to_tags.insert(read_name.as_bytes(), (i as i32, phase_set));
+                        }
+                    }
+                }
+            }
+        }
+
+        let suffix = if bam_files.len() > 1 {
+            format!("_{}", source_file)
+        } else {
+            String::new()
+        };
+
+        //Only scan the contig if the BAM is indexed; otherwise go through the whole file.
+        match get_indexed_bam_reader(&bam_file, reference) {
+            Some(mut bam) => {
+                if bam.fetch(contig).is_err() {
+                    panic!("Contig {} not found in the BAM index.", contig);
+                }
+                haplotag_records(
+                    &mut bam,
+                    &read_to_tags,
+                    part.len(),
+                    out_dir,
+                    &suffix,
+                    contig,
+                    split_by_haplotig,
+                    alignment_filter,
+                );
+            }
+            None => {
+                let mut bam = get_bam_reader(&bam_file, reference);
+                haplotag_records(
+                    &mut bam,
+                    &read_to_tags,
+                    part.len(),
+                    out_dir,
+                    &suffix,
+                    contig,
+                    split_by_haplotig,
+                    alignment_filter,
+                );
+            }
+        }
+    }
+}
+
+fn haplotag_records<R: bam::Read>(
+    bam: &mut R,
+    read_to_tags: &FxHashMap<&[u8], (i32, i32)>,
+    num_haplotigs: usize,
+    out_dir: &str,
+    suffix: &str,
+    contig: &str,
+    split_by_haplotig: bool,
+    alignment_filter: &AlignmentFilter,
+) {
+    let header = Header::from_template(bam.header());
+    let tid = match bam.header().tid(contig.as_bytes()) {
+        Some(tid) => tid as i32,
+        None => panic!("Contig {} not found in the BAM header.", contig),
+    };
+
+    let mut writer = match bam::Writer::from_path(
+        format!("{}/haplotagged{}.bam", out_dir, suffix),
+        &header,
+        bam::Format::Bam,
+    ) {
+        Ok(writer) => writer,
+        Err(_) => panic!("rust_htslib had an error while writing the BAM file. Exiting"),
+    };
+    let mut part_writers = vec![];
+    if split_by_haplotig {
+        for i in 0..num_haplotigs {
+            let part_writer = bam::Writer::from_path(
+                format!("{}/bam_parts/{}_part{}.bam", out_dir, i, suffix),
+                &header,
+                bam::Format::Bam,
+            )
+            .unwrap();
+            part_writers.push(part_writer);
+        }
+    }
+
+    let mut record = bam::Record::new();
+    while let Some(result) = bam.read(&mut record) {
+        if result.is_err() {
+            panic!("rust_htslib had an error while reading the BAM file. Exiting");
+        }
+        if record.tid() != tid {
+            continue;
+        }
+        //Don't keep stale tags from the input BAM.
+        record.remove_aux(b"HP").ok();
+        record.remove_aux(b"PS").ok();
+
+        let (passed, _is_supp) = alignment_passed_check(&record, alignment_filter);
+        let tags = if passed {
+            read_to_tags.get(record.qname())
+        } else {
+            None
+        };
+        if let Some((haplotig, phase_set)) = tags {
+            record
+                .push_aux(b"HP", bam::record::Aux::I32(*haplotig))
+                .unwrap();
+            record
+                .push_aux(b"PS", bam::record::Aux::I32(*phase_set))
+                .unwrap();
+            if split_by_haplotig {
+                part_writers[*haplotig as usize].write(&record).unwrap();
+            }
+        }
+        writer.write(&record).unwrap();
+    }
+}
+
+//Returns whether the alignment passes the filter and whether it is supplementary.
+pub fn alignment_passed_check(record: &bam::Record, alignment_filter: &AlignmentFilter) -> (bool, bool) {
+    let duplicate_mask = 1024;
     let supplementary_mask = 2048;
-    let mapq_supp_cutoff = 59;
-    let mapq_normal_cutoff = 15;
+    let flags = record.flags();
+    let mapq = record.mapq();
 
     let is_supp;
     if flags & supplementary_mask > 0 {
         is_supp = true;
-        if !use_supplementary {
+        if !alignment_filter.use_supplementary {
             return (false, true);
         }
-        if filter_supplementary {
-            if mapq < mapq_supp_cutoff {
+        if alignment_filter.filter_supplementary {
+            if mapq < alignment_filter.min_mapq_supplementary {
                 return (false, true);
             }
         }
@@ -994,21 +2491,36 @@ pub fn alignment_passed_check(
         is_supp = false;
     }
 
-    if mapq < mapq_normal_cutoff {
+    if mapq < alignment_filter.min_mapq {
         return (false, is_supp);
     }
-    //Erroneous alignment, skip
-    if flags & errors_mask > 0 {
-        //dbg!(&flags,&id_string);
+
+    let mut exclude_flags = alignment_filter.exclude_flags;
+    if !alignment_filter.keep_duplicates {
+        exclude_flags |= duplicate_mask;
+    }
+    if flags & exclude_flags > 0 {
         return (false, is_supp);
     }
-
-    //Secondary alignment, skip
-    if flags & secondary_mask > 0 {
-        //dbg!(&flags,&id_string);
+    if flags & alignment_filter.include_flags != alignment_filter.include_flags {
         return (false, is_supp);
     }
 
+    //Only look at the CIGAR if we have to.
+    if alignment_filter.max_soft_clip_frac < 1.0 || alignment_filter.min_aligned_length > 0 {
+        let cigar = record.cigar();
+        let soft_clipped = cigar.leading_softclips() + cigar.trailing_softclips();
+        let read_length = record.seq_len();
+        if read_length > 0
+            && soft_clipped as f64 / read_length as f64 > alignment_filter.max_soft_clip_frac
+        {
+            return (false, is_supp);
+        }
+        if cigar.end_pos() - record.pos() < alignment_filter.min_aligned_length {
+            return (false, is_supp);
+        }
+    }
+
     return (true, is_supp);
 }
 
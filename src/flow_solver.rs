@@ -0,0 +1,405 @@
+use crate::types_structs::HapNode;
+use fxhash::FxHashMap;
+use highs::{RowProblem, Sense};
+
+//Flows fitted to the edges of the haplotype graph: (column, row) of the two nodes of an edge and
+//its flow, for the nodes in order and their out edges in order.
+pub type FlowUpVec = Vec<((usize, usize), (usize, usize), f64)>;
+
+//Fits flows to the edge weights of the haplotype graph (the number of reads linking two nodes)
+//such that the flow into each node equals the flow out of it. Nodes in the first and last columns
+//and nodes without in or out edges are where strains start and end, so their flow isn't conserved.
+pub trait FlowSolver: Sync {
+    fn name(&self) -> &'static str;
+    fn solve(&self, hap_graph: &Vec<Vec<HapNode>>) -> FlowUpVec;
+}
+
+//Minimise the sum of |flow - weight| over the edges with a linear program.
+pub struct L1LpSolver;
+
+//Minimise the sum of (flow - weight)^2 / variance over the edges, i.e. a least squares fit where the
+//variance of an edge's read count is its mean, plus the variance of the count from splitting reads
+//between nodes if the graph has posterior edge weights. Solved by Dykstra's alternating projections onto the
+//conservation constraint of each node and onto flow >= 0.
+pub struct L2Solver {
+    pub max_iters: usize,
+    //Stop once no flow changes by more than this in an iteration.
+    pub tolerance: f64,
+}
+
+impl Default for L2Solver {
+    fn default() -> L2Solver {
+        L2Solver {
+            max_iters: 100000,
+            tolerance: 1e-6,
+        }
+    }
+}
+
+//The same fit as L1LpSolver, solved as a min-cost flow problem without an LP. Each edge's flow is
+//its weight plus an increase (cost 1 per unit) minus a decrease (cost 1 per unit, at most the
+//weight). A node whose in and out weights differ has to be balanced by changing the flows of its
+//edges, and the nodes whose flow isn't conserved are connected to a free super node.
+pub struct MinCostFlowSolver;
+
+pub const FLOW_SOLVERS: [&str; 3] = ["l1", "l2", "min-cost-flow"];
+
+pub fn get_flow_solver(name: &str) -> Box<dyn FlowSolver> {
+    match name {
+        "l1" => Box::new(L1LpSolver),
+        "l2" => Box::new(L2Solver::default()),
+        "min-cost-flow" => Box::new(MinCostFlowSolver),
+        _ => panic!(
+            "Unknown flow solver {}; use one of {}.",
+            name,
+            FLOW_SOLVERS.join(", ")
+        ),
+    }
+}
+
+//The edges of a haplotype graph as (node id, node id, weight), where a node's id is its index in
+//nodes, the variances of the edges' weights, and the in and out edges of each node whose flow is
+//conserved.
+struct FlowGraph<'a, 'b> {
+    nodes: Vec<&'b HapNode<'a>>,
+    edges: Vec<(usize, usize, f64)>,
+    edge_variances: Vec<f64>,
+    conserved_nodes: Vec<(usize, Vec<usize>, Vec<usize>)>,
+}
+
+fn get_flow_graph<'a, 'b>(hap_graph: &'b Vec<Vec<HapNode<'a>>>) -> FlowGraph<'a, 'b> {
+    let mut nodes = vec![];
+    for hap_block in hap_graph.iter() {
+        for hap_node in hap_block.iter() {
+            nodes.push(hap_node);
+        }
+    }
+
+    let mut edges = vec![];
+    let mut edge_variances = vec![];
+    let mut nodes_to_edges = FxHashMap::default();
+    for hap_node in nodes.iter() {
+        let id1 = hap_node.id;
+        for (i, edge) in hap_node.out_edges.iter().enumerate() {
+            let id2 = hap_graph[hap_node.column + 1][edge.0].id;
+            edges.push((id1, id2, edge.1));
+            edge_variances.push(hap_node.out_edge_variances.get(i).copied().unwrap_or(0.));
+            nodes_to_edges.insert((id1, id2), edges.len() - 1);
+        }
+    }
+
+    let mut conserved_nodes = vec![];
+    for (column_ind, hap_block) in hap_graph.iter().enumerate() {
+        if column_ind == 0 || column_ind == hap_graph.len() - 1 {
+            continue;
+        }
+        for hap_node in hap_block.iter() {
+            if hap_node.in_edges.is_empty() || hap_node.out_edges.is_empty() {
+                continue;
+            }
+            let node_id = hap_node.id;
+            let mut in_edge_ids = vec![];
+            let mut out_edge_ids = vec![];
+            for in_edge in hap_node.in_edges.iter() {
+                let node_id2 = hap_graph[hap_node.column - 1][in_edge.0].id;
+                in_edge_ids.push(nodes_to_edges[&(node_id2, node_id)]);
+            }
+            for out_edge in hap_node.out_edges.iter() {
+                let node_id2 = hap_graph[hap_node.column + 1][out_edge.0].id;
+                out_edge_ids.push(nodes_to_edges[&(node_id, node_id2)]);
+            }
+            conserved_nodes.push((node_id, in_edge_ids, out_edge_ids));
+        }
+    }
+
+    FlowGraph {
+        nodes,
+        edges,
+        edge_variances,
+        conserved_nodes,
+    }
+}
+
+fn to_flow_update_vec(flow_graph: &FlowGraph, flows: &[f64]) -> FlowUpVec {
+    let mut flow_update_vec = vec![];
+    for (i, (node1_id, node2_id, _)) in flow_graph.edges.iter().enumerate() {
+        let node1 = flow_graph.nodes[*node1_id];
+        let node2 = flow_graph.nodes[*node2_id];
+        flow_update_vec.push((
+            (node1.column, node1.row),
+            (node2.column, node2.row),
+            flows[i],
+        ));
+    }
+    flow_update_vec
+}
+
+impl FlowSolver for L1LpSolver {
+    fn name(&self) -> &'static str {
+        "l1"
+    }
+
+    fn solve(&self, hap_graph: &Vec<Vec<HapNode>>) -> FlowUpVec {
+        let flow_graph = get_flow_graph(hap_graph);
+        let ae: Vec<f64> = flow_graph.edges.iter().map(|edge| edge.2).collect();
+        let mut pb = RowProblem::default();
+        let mut t = vec![];
+        let mut x = vec![];
+
+        for _i in 0..ae.len() {
+            x.push(pb.add_column(0., 0..));
+        }
+        for _i in 0..ae.len() {
+            t.push(pb.add_column(1., 0..));
+        }
+
+        for (_, in_edge_ids, out_edge_ids) in flow_graph.conserved_nodes.iter() {
+            let mut constraint_row = vec![];
+            for in_edge_id in in_edge_ids.iter() {
+                constraint_row.push((x[*in_edge_id], 1.));
+            }
+            for out_edge_id in out_edge_ids.iter() {
+                constraint_row.push((x[*out_edge_id], -1.));
+            }
+            pb.add_row(..0, &constraint_row);
+            pb.add_row(0.., &constraint_row);
+        }
+
+        for i in 0..t.len() {
+            pb.add_row(-1.0 * ae[i].., &[(t[i], 1.), (x[i], -1.)]);
+            pb.add_row(1.0 * ae[i].., &[(t[i], 1.), (x[i], 1.)]);
+            pb.add_row(0.0.., &[(x[i], 1.)]);
+        }
+
+        let solved = pb.optimise(Sense::Minimise).solve();
+        let solution = solved.get_solution();
+        log::debug!("Linear program finished.");
+        to_flow_update_vec(&flow_graph, &solution.columns()[0..ae.len()])
+    }
+}
+
+impl FlowSolver for L2Solver {
+    fn name(&self) -> &'static str {
+        "l2"
+    }
+
+    fn solve(&self, hap_graph: &Vec<Vec<HapNode>>) -> FlowUpVec {
+        let flow_graph = get_flow_graph(hap_graph);
+        //The inverse of the weights of the squared errors, i.e. the variances.
+        let ae: Vec<f64> = flow_graph
+            .edges
+            .iter()
+            .zip(flow_graph.edge_variances.iter())
+            .map(|(edge, variance)| f64::max(edge.2 + variance, 1e-6))
+            .collect();
+        //Projecting onto sum(in flows) - sum(out flows) = 0 in the weighted norm moves each flow by
+        //a multiple of its variance.
+        let variance_sums: Vec<f64> = flow_graph
+            .conserved_nodes
+            .iter()
+            .map(|(_, in_edge_ids, out_edge_ids)| {
+                in_edge_ids
+                    .iter()
+                    .chain(out_edge_ids.iter())
+                    .map(|e| ae[*e])
+                    .sum()
+            })
+            .collect();
+        let tolerance = self.tolerance * ae.iter().copied().fold(1., f64::max);
+
+        let mut x: Vec<f64> = flow_graph.edges.iter().map(|edge| edge.2).collect();
+        //Dykstra's corrections: a multiple of the projection direction for each node and a vector
+        //for flow >= 0.
+        let mut node_corrections = vec![0.; flow_graph.conserved_nodes.len()];
+        let mut positive_corrections = vec![0.; x.len()];
+        let mut num_iters = 0;
+        while num_iters < self.max_iters {
+            num_iters += 1;
+            let mut max_change: f64 = 0.;
+            for (j, (_, in_edge_ids, out_edge_ids)) in flow_graph.conserved_nodes.iter().enumerate()
+            {
+                let mut imbalance = 0.;
+                for e in in_edge_ids.iter() {
+                    x[*e] += node_corrections[j] * ae[*e];
+                    imbalance += x[*e];
+                }
+                for e in out_edge_ids.iter() {
+                    x[*e] -= node_corrections[j] * ae[*e];
+                    imbalance -= x[*e];
+                }
+                let correction = imbalance / variance_sums[j];
+                for e in in_edge_ids.iter() {
+                    let change = (correction - node_corrections[j]) * ae[*e];
+                    x[*e] -= correction * ae[*e];
+                    max_change = max_change.max(change.abs());
+                }
+                for e in out_edge_ids.iter() {
+                    let change = (correction - node_corrections[j]) * ae[*e];
+                    x[*e] += correction * ae[*e];
+                    max_change = max_change.max(change.abs());
+                }
+                node_corrections[j] = correction;
+            }
+            for i in 0..x.len() {
+                let y = x[i] + positive_corrections[i];
+                let projected = f64::max(y, 0.);
+                max_change = max_change.max((projected - x[i]).abs());
+                positive_corrections[i] = y - projected;
+                x[i] = projected;
+            }
+            if max_change < tolerance {
+                break;
+            }
+        }
+        log::debug!(
+            "Least squares flow fit finished after {} iterations.",
+            num_iters
+        );
+        to_flow_update_vec(&flow_graph, &x)
+    }
+}
+
+//Arc of the residual network of the min-cost flow problem. The reverse arc of arcs[i] is
+//arcs[i ^ 1].
+struct Arc {
+    to: usize,
+    capacity: f64,
+    cost: f64,
+}
+
+fn add_arc(
+    arcs: &mut Vec<Arc>,
+    out_arcs: &mut Vec<Vec<usize>>,
+    from: usize,
+    to: usize,
+    capacity: f64,
+    cost: f64,
+) {
+    out_arcs[from].push(arcs.len());
+    arcs.push(Arc { to, capacity, cost });
+    out_arcs[to].push(arcs.len());
+    arcs.push(Arc {
+        to: from,
+        capacity: 0.,
+        cost: -cost,
+    });
+}
+
+impl FlowSolver for MinCostFlowSolver {
+    fn name(&self) -> &'static str {
+        "min-cost-flow"
+    }
+
+    fn solve(&self, hap_graph: &Vec<Vec<HapNode>>) -> FlowUpVec {
+        let flow_graph = get_flow_graph(hap_graph);
+        let num_nodes = flow_graph.nodes.len() + 1;
+        let super_node = num_nodes - 1;
+        let min_flow = 1e-9;
+
+        let mut arcs = vec![];
+        let mut out_arcs = vec![vec![]; num_nodes];
+        //Arcs 4i and 4i + 2 increase and decrease the flow of edge i.
+        for (node1_id, node2_id, weight) in flow_graph.edges.iter() {
+            add_arc(
+                &mut arcs,
+                &mut out_arcs,
+                *node1_id,
+                *node2_id,
+                f64::INFINITY,
+                1.,
+            );
+            add_arc(&mut arcs, &mut out_arcs, *node2_id, *node1_id, *weight, 1.);
+        }
+        let mut supply = vec![0.; num_nodes];
+        let mut is_conserved = vec![false; num_nodes];
+        for (node_id, in_edge_ids, out_edge_ids) in flow_graph.conserved_nodes.iter() {
+            is_conserved[*node_id] = true;
+            let in_weight: f64 = in_edge_ids.iter().map(|e| flow_graph.edges[*e].2).sum();
+            let out_weight: f64 = out_edge_ids.iter().map(|e| flow_graph.edges[*e].2).sum();
+            supply[*node_id] = in_weight - out_weight;
+            supply[super_node] -= in_weight - out_weight;
+        }
+        for node_id in 0..flow_graph.nodes.len() {
+            if !is_conserved[node_id] {
+                add_arc(
+                    &mut arcs,
+                    &mut out_arcs,
+                    node_id,
+                    super_node,
+                    f64::INFINITY,
+                    0.,
+                );
+                add_arc(
+                    &mut arcs,
+                    &mut out_arcs,
+                    super_node,
+                    node_id,
+                    f64::INFINITY,
+                    0.,
+                );
+            }
+        }
+
+        //Successive shortest paths from a node with supply to the closest node with demand. The
+        //residual network has negative costs, so the paths are found with Bellman-Ford (SPFA).
+        let mut num_paths = 0;
+        while let Some(source) = (0..num_nodes).find(|i| supply[*i] > min_flow) {
+            let mut dist = vec![f64::INFINITY; num_nodes];
+            let mut prev_arc = vec![usize::MAX; num_nodes];
+            let mut in_queue = vec![false; num_nodes];
+            let mut queue = std::collections::VecDeque::new();
+            dist[source] = 0.;
+            queue.push_back(source);
+            in_queue[source] = true;
+            while let Some(node) = queue.pop_front() {
+                in_queue[node] = false;
+                for arc_id in out_arcs[node].iter() {
+                    let arc = &arcs[*arc_id];
+                    if arc.capacity > min_flow && dist[node] + arc.cost < dist[arc.to] {
+                        dist[arc.to] = dist[node] + arc.cost;
+                        prev_arc[arc.to] = *arc_id;
+                        if !in_queue[arc.to] {
+                            queue.push_back(arc.to);
+                            in_queue[arc.to] = true;
+                        }
+                    }
+                }
+            }
+            let sink = (0..num_nodes)
+                .filter(|i| supply[*i] < -min_flow && dist[*i] < f64::INFINITY)
+                .min_by(|i, j| dist[*i].partial_cmp(&dist[*j]).unwrap())
+                .expect("Min-cost flow has no path from a node with supply to a node with demand.");
+
+            let mut amount = f64::min(supply[source], -supply[sink]);
+            let mut node = sink;
+            while node != source {
+                let arc_id = prev_arc[node];
+                amount = amount.min(arcs[arc_id].capacity);
+                node = arcs[arc_id ^ 1].to;
+            }
+            let mut node = sink;
+            while node != source {
+                let arc_id = prev_arc[node];
+                arcs[arc_id].capacity -= amount;
+                arcs[arc_id ^ 1].capacity += amount;
+                node = arcs[arc_id ^ 1].to;
+            }
+            supply[source] -= amount;
+            supply[sink] += amount;
+            num_paths += 1;
+        }
+
+        //The flow on an arc is the capacity of its reverse arc.
+        let flows: Vec<f64> = flow_graph
+            .edges
+            .iter()
+            .enumerate()
+            .map(|(i, (_, _, weight))| weight + arcs[4 * i + 1].capacity - arcs[4 * i + 3].capacity)
+            .collect();
+        log::debug!(
+            "Min-cost flow finished after {} augmenting paths.",
+            num_paths
+        );
+        to_flow_update_vec(&flow_graph, &flows)
+    }
+}
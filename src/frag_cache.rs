@@ -0,0 +1,330 @@
+use crate::types_structs::{
+    AlignmentFilter, Frag, GenomeRegion, MoleculeGrouping, PackedSeq, SiteFilter,
+};
+use fxhash::{FxHashMap, FxHasher};
+use rust_htslib::{bam, bam::Read as DUMMY_NAME1};
+use rust_htslib::{bcf, bcf::Read as DUMMY_NAME2};
+use std::fs;
+use std::fs::File;
+use std::hash::Hasher;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+//Binary cache of the fragments read from the BAM and VCF files by
+//file_reader::get_frags_from_bamvcf, so that runs with different phasing settings don't have to
+//scan the BAM files again. The header has a key made from the paths, sizes, modification times
+//and headers of the inputs (optionally checksums of their contents) and the filter settings; a
+//cache is only used if its key matches the current run.
+//
+//Layout: magic, version, key, number of contigs, then for each contig its name, number of
+//fragments and the fragments. Integers are LEB128 varints.
+
+const MAGIC: &[u8; 8] = b"GLOPPFC\0";
+pub const FRAG_CACHE_VERSION: u64 = 2;
+
+//Checksum of the contents of a file. Reads the whole file.
+pub fn file_checksum<P>(file: P) -> u64
+where
+    P: AsRef<Path>,
+{
+    let mut reader = match File::open(&file) {
+        Ok(reader) => reader,
+        Err(_) => panic!("Can't open {} for the fragment cache.", file.as_ref().display()),
+    };
+    let mut hasher = FxHasher::default();
+    let mut buf = vec![0; 1 << 20];
+    loop {
+        let n = reader.read(&mut buf).unwrap();
+        if n == 0 {
+            break;
+        }
+        hasher.write(&buf[..n]);
+    }
+    hasher.finish()
+}
+
+//Canonical path, size and modification time of a file.
+fn file_metadata_key(file: &str) -> String {
+    let path = match fs::canonicalize(file) {
+        Ok(path) => path,
+        Err(_) => panic!("Can't open {} for the fragment cache.", file),
+    };
+    let metadata = fs::metadata(&path).unwrap();
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map_or(0, |time| time.as_nanos());
+    format!("{}:{}:{}", path.display(), metadata.len(), mtime)
+}
+
+fn bytes_checksum(bytes: &[u8]) -> u64 {
+    let mut hasher = FxHasher::default();
+    hasher.write(bytes);
+    hasher.finish()
+}
+
+fn bam_header_checksum(bam_file: &str, reference: Option<&str>) -> u64 {
+    let mut bam = match bam::Reader::from_path(bam_file) {
+        Ok(bam) => bam,
+        Err(_) => panic!("rust_htslib had an error while reading the BAM file {}", bam_file),
+    };
+    if let Some(reference) = reference {
+        bam.set_reference(reference).unwrap();
+    }
+    bytes_checksum(bam.header().as_bytes())
+}
+
+fn vcf_header_checksum(vcf_file: &str) -> u64 {
+    let vcf = match bcf::Reader::from_path(vcf_file) {
+        Ok(vcf) => vcf,
+        Err(_) => panic!("rust_htslib had an error reading the VCF file. Exiting."),
+    };
+    let header = vcf.header();
+    let header_string = format!("{:?};{:?}", header.header_records(), header.samples());
+    bytes_checksum(header_string.as_bytes())
+}
+
+//Key of a cache built from these inputs and settings. The input files are identified by their
+//metadata and headers, which doesn't need the files to be read; with content_checksum, the whole
+//contents are checksummed too.
+pub fn get_cache_key(
+    vcf_file: &str,
+    bam_files: &[&str],
+    reference: Option<&str>,
+    alignment_filter: &AlignmentFilter,
+    molecule_grouping: &MoleculeGrouping,
+    site_filter: &SiteFilter,
+    regions: &Vec<GenomeRegion>,
+    store_reads: bool,
+    content_checksum: bool,
+) -> String {
+    let mut key = format!(
+        "vcf:{}:{:016x}",
+        file_metadata_key(vcf_file),
+        vcf_header_checksum(vcf_file)
+    );
+    for bam_file in bam_files.iter() {
+        key.push_str(&format!(
+            ";bam:{}:{:016x}",
+            file_metadata_key(bam_file),
+            bam_header_checksum(bam_file, reference)
+        ));
+    }
+    if let Some(reference) = reference {
+        key.push_str(&format!(";reference:{}", file_metadata_key(reference)));
+    }
+    if content_checksum {
+        key.push_str(&format!(";vcf_checksum:{:016x}", file_checksum(vcf_file)));
+        for bam_file in bam_files.iter() {
+            key.push_str(&format!(";bam_checksum:{:016x}", file_checksum(bam_file)));
+        }
+        if let Some(reference) = reference {
+            key.push_str(&format!(";reference_checksum:{:016x}", file_checksum(reference)));
+        }
+    }
+    key.push_str(&format!(
+        ";{:?};{:?};{:?};{:?};store_reads:{}",
+        alignment_filter, molecule_grouping, site_filter, regions, store_reads
+    ));
+    key
+}
+
+fn write_varint<W: Write>(writer: &mut W, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            writer.write_all(&[byte]).unwrap();
+            break;
+        }
+        writer.write_all(&[byte | 0x80]).unwrap();
+    }
+}
+
+fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) {
+    write_varint(writer, bytes.len() as u64);
+    writer.write_all(bytes).unwrap();
+}
+
+fn read_varint<R: Read>(reader: &mut R) -> Option<u64> {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let mut byte = [0; 1];
+        reader.read_exact(&mut byte).ok()?;
+        if shift > 63 {
+            return None;
+        }
+        value |= ((byte[0] & 0x7f) as u64) << shift;
+        if byte[0] & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+    }
+}
+
+fn read_usize<R: Read>(reader: &mut R) -> Option<usize> {
+    read_varint(reader).map(|x| x as usize)
+}
+
+fn read_bytes<R: Read>(reader: &mut R) -> Option<Vec<u8>> {
+    let len = read_usize(reader)?;
+    let mut bytes = vec![];
+    reader.take(len as u64).read_to_end(&mut bytes).ok()?;
+    if bytes.len() != len {
+        return None;
+    }
+    Some(bytes)
+}
+
+fn read_string<R: Read>(reader: &mut R) -> Option<String> {
+    String::from_utf8(read_bytes(reader)?).ok()
+}
+
+fn write_frag<W: Write>(writer: &mut W, frag: &Frag) {
+    write_bytes(writer, frag.id.as_bytes());
+    write_varint(writer, frag.counter_id as u64);
+    write_varint(writer, frag.num_calls() as u64);
+    for i in 0..frag.num_calls() {
+        write_varint(writer, frag.snps[i] as u64);
+        writer
+            .write_all(&[frag.alleles[i], frag.quals[i], frag.mates[i]])
+            .unwrap();
+        write_varint(writer, frag.seq_offsets[i] as u64);
+    }
+    write_varint(writer, frag.first_position as u64);
+    write_varint(writer, frag.last_position as u64);
+    write_varint(writer, frag.seq_string.len() as u64);
+    for (seq, qual) in frag.seq_string.iter().zip(frag.qual_string.iter()) {
+        write_varint(writer, seq.len() as u64);
+        write_bytes(writer, &seq.encoded);
+        write_bytes(writer, qual);
+    }
+    writer.write_all(&[frag.is_paired as u8]).unwrap();
+    write_varint(writer, frag.source_file as u64);
+    write_varint(writer, frag.library as u64);
+    writer.write_all(&frag.epsilon.to_le_bytes()).unwrap();
+    write_varint(writer, frag.molecule_reads.len() as u64);
+    for read_name in frag.molecule_reads.iter() {
+        write_bytes(writer, read_name.as_bytes());
+    }
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> Option<u8> {
+    let mut byte = [0; 1];
+    reader.read_exact(&mut byte).ok()?;
+    Some(byte[0])
+}
+
+fn read_frag<R: Read>(reader: &mut R) -> Option<Frag> {
+    let id = read_string(reader)?;
+    let counter_id = read_usize(reader)?;
+    let num_calls = read_usize(reader)?;
+    let mut snps = Vec::with_capacity(num_calls);
+    let mut alleles = Vec::with_capacity(num_calls);
+    let mut quals = Vec::with_capacity(num_calls);
+    let mut mates = Vec::with_capacity(num_calls);
+    let mut seq_offsets = Vec::with_capacity(num_calls);
+    for _ in 0..num_calls {
+        snps.push(read_varint(reader)? as u32);
+        alleles.push(read_u8(reader)?);
+        quals.push(read_u8(reader)?);
+        mates.push(read_u8(reader)?);
+        seq_offsets.push(read_varint(reader)? as u32);
+    }
+    let first_position = read_usize(reader)?;
+    let last_position = read_usize(reader)?;
+    let mut seq_string = vec![];
+    let mut qual_string = vec![];
+    for _ in 0..read_usize(reader)? {
+        let len = read_usize(reader)?;
+        let encoded = read_bytes(reader)?;
+        if encoded.len() != (len + 1) / 2 {
+            return None;
+        }
+        seq_string.push(PackedSeq { len, encoded });
+        qual_string.push(read_bytes(reader)?);
+    }
+    let is_paired = read_u8(reader)? == 1;
+    let source_file = read_usize(reader)?;
+    let library = read_usize(reader)?;
+    let mut epsilon = [0; 8];
+    reader.read_exact(&mut epsilon).ok()?;
+    let mut molecule_reads = vec![];
+    for _ in 0..read_usize(reader)? {
+        molecule_reads.push(read_string(reader)?);
+    }
+    Some(Frag {
+        id,
+        counter_id,
+        snps,
+        alleles,
+        quals,
+        mates,
+        seq_offsets,
+        first_position,
+        last_position,
+        seq_string,
+        qual_string,
+        is_paired,
+        source_file,
+        library,
+        epsilon: f64::from_le_bytes(epsilon),
+        molecule_reads,
+    })
+}
+
+//Write the cache to cache_file.tmp and then move it to cache_file, so that an interrupted run
+//doesn't leave a truncated cache.
+pub fn write_frag_cache(frags_map: &FxHashMap<String, Vec<Frag>>, key: &str, cache_file: &str) {
+    let tmp_file = format!("{}.tmp", cache_file);
+    let file = match File::create(&tmp_file) {
+        Ok(file) => file,
+        Err(_) => panic!("Can't create the fragment cache {}", tmp_file),
+    };
+    let mut writer = BufWriter::new(file);
+    writer.write_all(MAGIC).unwrap();
+    write_varint(&mut writer, FRAG_CACHE_VERSION);
+    write_bytes(&mut writer, key.as_bytes());
+    write_varint(&mut writer, frags_map.len() as u64);
+    for (contig, frags) in frags_map.iter() {
+        write_bytes(&mut writer, contig.as_bytes());
+        write_varint(&mut writer, frags.len() as u64);
+        for frag in frags.iter() {
+            write_frag(&mut writer, frag);
+        }
+    }
+    writer.flush().unwrap();
+    drop(writer);
+    if fs::rename(&tmp_file, cache_file).is_err() {
+        panic!("Can't move {} to the fragment cache {}", tmp_file, cache_file);
+    }
+}
+
+//Load the fragments from a cache. Returns None if the cache doesn't exist, was written by another
+//version or for other inputs/settings, or is truncated.
+pub fn read_frag_cache(key: &str, cache_file: &str) -> Option<FxHashMap<String, Vec<Frag>>> {
+    let file = File::open(cache_file).ok()?;
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic).ok()?;
+    if &magic != MAGIC || read_varint(&mut reader)? != FRAG_CACHE_VERSION {
+        return None;
+    }
+    if read_string(&mut reader)? != key {
+        return None;
+    }
+    let mut frags_map = FxHashMap::default();
+    for _ in 0..read_usize(&mut reader)? {
+        let contig = read_string(&mut reader)?;
+        let num_frags = read_usize(&mut reader)?;
+        let mut frags = Vec::new();
+        for _ in 0..num_frags {
+            frags.push(read_frag(&mut reader)?);
+        }
+        frags_map.insert(contig, frags);
+    }
+    Some(frags_map)
+}
@@ -7,15 +7,16 @@ extern crate time;
 use crate::utils_frags;
 use fxhash::{FxHashMap, FxHashSet};
 
+//Fragments are scored with their own error rates (Frag.epsilon).
 pub fn beam_search_phasing<'a>(
     clique: Vec<FxHashSet<&'a Frag>>,
     all_reads: &'a Vec<&Frag>,
-    epsilon: f64,
     div_factor: f64,
     cutoff_value: f64,
     max_number_solns: usize,
     use_mec: bool,
     use_ref_bias: bool,
+    use_qual: bool,
 ) -> (FxHashMap<usize, FxHashSet<usize>>, Vec<FxHashSet<&'a Frag>>) {
     if all_reads.len() == 0{
         return (FxHashMap::default(), vec![]);
@@ -71,7 +72,7 @@ pub fn beam_search_phasing<'a>(
                     let ((same_ref, diff_ref), (same_alt, diff_alt)) =
                         utils_frags::distance_read_haplo_ref_wild(frag, &block.blocks[part_index]);
                     let epsilon_ref = 0.01;
-                    let epsilon_alt = 2.0 * epsilon;
+                    let epsilon_alt = 2.0 * frag.epsilon;
                     let dist_ref = 1.0
                         * utils_frags::stable_binom_cdf_p_rev(
                             (same_ref + diff_ref) as usize,
@@ -90,16 +91,29 @@ pub fn beam_search_phasing<'a>(
                         );
                     dist = dist_alt + dist_ref;
                 } else {
-                    let (same, diff) =
-                        utils_frags::distance_read_haplo_epsilon_empty(frag, &block.blocks[part_index], epsilon);
-                    dist = 1.0
-                        * utils_frags::stable_binom_cdf_p_rev(
-                            (same + diff) as usize,
-                            diff as usize,
-                            //2.0 * epsilon_ref * (1.0 - epsilon_ref),
-                            epsilon,
+                    let (same, diff) = utils_frags::distance_read_haplo_epsilon_empty(
+                        frag,
+                        &block.blocks[part_index],
+                        frag.epsilon,
+                        use_qual,
+                    );
+                    if use_qual {
+                        dist = utils_frags::stable_binom_cdf_p_rev_f64(
+                            same + diff,
+                            diff,
+                            frag.epsilon,
                             div_factor,
                         );
+                    } else {
+                        dist = 1.0
+                            * utils_frags::stable_binom_cdf_p_rev(
+                                (same + diff) as usize,
+                                diff as usize,
+                                //2.0 * epsilon_ref * (1.0 - epsilon_ref),
+                                frag.epsilon,
+                                div_factor,
+                            );
+                    }
                 }
 
                 p_value_list.push(dist);
@@ -112,7 +126,7 @@ pub fn beam_search_phasing<'a>(
                     //score is either the PEM or MEC score. I want to play around with using the
                     //iterative sum of p-values as well.
                     let (score, new_error_vec) =
-                        read_to_node_value(node, frag, block, j, epsilon, div_factor, use_mec);
+                        read_to_node_value(node, frag, block, j, frag.epsilon, div_factor, use_mec, use_qual);
                     let new_node_score;
                     new_node_score = -score;
 
@@ -185,11 +199,12 @@ fn read_to_node_value(
     epsilon: f64,
     _div_factor: f64,
     use_mec: bool,
+    use_qual: bool,
 ) -> (f64, Vec<(f64, f64)>) {
     let ploidy = block.blocks.len();
     //    let (same, diff) = utils_frags::distance_read_haplo(frag, &block.blocks[part_index]);
     let (same, diff) =
-        utils_frags::distance_read_haplo_epsilon_empty(frag, &block.blocks[part_index], epsilon);
+        utils_frags::distance_read_haplo_epsilon_empty(frag, &block.blocks[part_index], epsilon, use_qual);
     let mut new_error_vec = vec![];
     for i in 0..ploidy {
         if i == part_index {
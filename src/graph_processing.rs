@@ -1,67 +1,91 @@
+use crate::abundance::{self, AbundanceSettings};
 use crate::file_reader;
+use crate::flow_solver::{FlowSolver, FlowUpVec};
 use crate::global_clustering;
 use crate::local_clustering;
-use crate::types_structs::{Frag, HapNode, TraceBackNode};
+use crate::types_structs::{ExactPathsSettings, Frag, FragIndex, HapBlock, HapNode, TraceBackNode};
 use crate::utils_frags;
 use fxhash::{FxHashMap, FxHashSet};
-use highs::{RowProblem, Sense};
+use highs::{Col, HighsModelStatus, RowProblem, Sense};
+use rand::prelude::*;
+use rand_pcg::Pcg64;
 use rayon::prelude::*;
 use std::sync::Mutex;
-//use osqp::{CscMatrix, Problem, Settings};
 use petgraph::algo;
 use petgraph::dot::Dot;
 use petgraph::prelude::*;
+use petgraph::visit::IntoEdgeReferences;
 use std::fs::File;
 use std::io::Write;
 use std::mem;
+use std::time::Instant;
+
+//Edges need more than CUTOFF_VAL reads, or with edge posteriors at least POSTERIOR_CUTOFF_VAL
+//expected reads; see update_hap_graph.
+const CUTOFF_VAL: f64 = 3.0;
+const POSTERIOR_CUTOFF_VAL: f64 = 1.0;
+
+//Smallest fitted flow of an edge used for the haplotigs. Matches the edge cutoff of
+//update_hap_graph, so that the edges kept for low-coverage strains with edge_posteriors aren't
+//dropped again after the flows are fitted.
+pub fn get_flow_cutoff(edge_posteriors: bool) -> f64 {
+    if edge_posteriors {
+        POSTERIOR_CUTOFF_VAL
+    } else {
+        CUTOFF_VAL
+    }
+}
 
-//Not using osqp anymore.
-
-//pub fn entry_list_to_csc<'a>(
-//    mut entry_list: Vec<(usize, usize, f64)>,
-//    ncols: usize,
-//    nrows: usize,
-//) -> CscMatrix<'a> {
-//    //Traverse each column first
-//
-//    let mut indptr = vec![];
-//    let mut indices = vec![];
-//    let mut data = vec![];
-//    entry_list.sort_by(|x, y| x.0.cmp(&y.0));
-//    let mut current_col = usize::MAX;
-//    for entry in entry_list {
-//        let col_id = entry.0;
-//        if col_id != current_col {
-//            if current_col == usize::MAX {
-//                current_col = 0;
-//                indptr.push(0);
-//            }
-//            for _i in current_col..col_id {
-//                indptr.push(data.len());
-//            }
-//            current_col = col_id;
-//        }
-//        let row_id = entry.1;
-//        let val = entry.2;
-//        indices.push(row_id);
-//        data.push(val);
-//    }
-//    indptr.push(data.len());
-//
-//    CscMatrix {
-//        nrows: nrows,
-//        ncols: ncols,
-//        indptr: indptr.into(),
-//        indices: indices.into(),
-//        data: data.into(),
-//    }
-//}
-
-type FlowUpVec = Vec<((usize, usize), (usize, usize), f64)>;
-
-pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>) {
+//Add each node of hap_block2's posterior probability of containing read to its expected count, and
+//p(1 - p) to the variance of the count. The likelihood of a node is (1 - epsilon)^same *
+//epsilon^diff over the read's alleles and the prior over the nodes is uniform. Reads which aren't
+//in any node of hap_block2 don't overlap it and aren't counted.
+fn add_read_posteriors(
+    read: &Frag,
+    hap_block2: &Vec<HapNode>,
+    use_qual: bool,
+    out_weights: &mut Vec<f64>,
+    out_variances: &mut Vec<f64>,
+) {
+    if !hap_block2.iter().any(|hap_node2| hap_node2.frag_set.contains(read)) {
+        return;
+    }
+    let epsilon = read.epsilon;
+    let log_eps = epsilon.max(1e-6).ln();
+    let mut log_probs = vec![];
+    for hap_node2 in hap_block2.iter() {
+        let (same, diff);
+        if use_qual {
+            let (same_qual, diff_qual) =
+                utils_frags::distance_read_haplo_qual(read, &hap_node2.hap_map, epsilon);
+            same = same_qual;
+            diff = diff_qual;
+        } else {
+            let (same_count, diff_count) =
+                utils_frags::distance_read_haplo(read, &hap_node2.hap_map);
+            same = same_count as f64;
+            diff = diff_count as f64;
+        }
+        log_probs.push(same * (1. - epsilon).ln() + diff * log_eps);
+    }
+    let log_total = utils_frags::log_sum_exp(&log_probs);
+    for (l, log_prob) in log_probs.iter().enumerate() {
+        let p = (log_prob - log_total).exp();
+        out_weights[l] += p;
+        out_variances[l] += p * (1. - p);
+    }
+}
+
+//Add the edges between adjacent blocks of the haplotype graph. By default, an edge's weight is the
+//number of reads in both nodes which match the next node better than any other, and edges with at
+//most CUTOFF_VAL reads are dropped. With edge_posteriors, every read in the overlap is split
+//between the next block's nodes by posterior probability (see add_read_posteriors), so weights
+//are expected counts and out_edge_variances holds their variances. Edges are then also kept if
+//they have at least one expected read and are a large enough fraction of the node's out weight,
+//so that the edges of low-coverage strains aren't cut off.
+pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>, use_qual: bool, edge_posteriors: bool) {
     //    let pseudo_count = 10.;
-    let cutoff_val = 3.0;
+    let posterior_cutoff_fraction = 0.1;
     let mut out_edges_block_hap = vec![];
     for i in 0..hap_graph.len() - 1 {
         let mut out_edges_block = vec![];
@@ -69,19 +93,35 @@ pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>) {
         let hap_block2 = &hap_graph[i + 1];
         for hap_node1 in hap_block1 {
             let mut out_weights = vec![0.0; hap_block2.len()];
+            let mut out_variances = vec![0.0; hap_block2.len()];
             //We have to make sure that ambiguous reads do not
             //get accounted for.
             for read in hap_node1.frag_set.iter() {
+                if edge_posteriors {
+                    add_read_posteriors(
+                        read,
+                        hap_block2,
+                        use_qual,
+                        &mut out_weights,
+                        &mut out_variances,
+                    );
+                    continue;
+                }
                 let mut read_to_hap_sim = vec![];
                 let mut hap_id_in = usize::MAX;
                 for (l, hap_node2) in hap_block2.iter().enumerate() {
                     if hap_node2.frag_set.contains(read) {
                         hap_id_in = l;
                     }
-                    let (_same, diff) = utils_frags::distance_read_haplo(read, &hap_node2.hap_map);
+                    let diff;
+                    if use_qual {
+                        diff = utils_frags::distance_read_haplo_qual(read, &hap_node2.hap_map, read.epsilon).1;
+                    } else {
+                        diff = utils_frags::distance_read_haplo(read, &hap_node2.hap_map).1 as f64;
+                    }
                     read_to_hap_sim.push((diff, l));
                 }
-                read_to_hap_sim.sort();
+                read_to_hap_sim.sort_by(|a, b| a.partial_cmp(b).unwrap());
                 if read_to_hap_sim.len() > 1 {
                     if read_to_hap_sim[0].0 != read_to_hap_sim[1].0 {
                         if hap_id_in != usize::MAX {
@@ -98,18 +138,22 @@ pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>) {
             }
             //            let sum: f64 = out_weights.iter().sum();
             //            let _normalized_out_weights: Vec<f64> = out_weights.iter().map(|x| x / sum).collect();
+            let total_weight: f64 = out_weights.iter().sum();
             let mut out_edges_hap = vec![];
             for l in 0..hap_block2.len() {
-                if out_weights[l] > cutoff_val {
+                let keep_posterior = edge_posteriors
+                    && out_weights[l] >= POSTERIOR_CUTOFF_VAL
+                    && out_weights[l] >= posterior_cutoff_fraction * total_weight;
+                if out_weights[l] > CUTOFF_VAL || keep_posterior {
                     //                    out_edges_hap.push((l, normalized_out_weights[l]));
-                    out_edges_hap.push((l, out_weights[l]));
+                    out_edges_hap.push((l, out_weights[l], out_variances[l]));
                 }
             }
             //            let new_normalization: f64 = out_edges_hap.iter().map(|x| x.1).sum();
             let new_normalization: f64 = 1.;
             let new_normal_out_edges_hap: Vec<_> = out_edges_hap
                 .iter()
-                .map(|x| (x.0, x.1 / new_normalization))
+                .map(|x| (x.0, x.1 / new_normalization, x.2))
                 .collect();
             out_edges_block.push(new_normal_out_edges_hap);
         }
@@ -122,8 +166,9 @@ pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>) {
             let out_edges_block = &out_edges_block_hap[i];
             for (j, hap_node) in hap_block.iter_mut().enumerate() {
                 let out_edges_hap = &out_edges_block[j];
-                for (k, prob) in out_edges_hap {
+                for (k, prob, variance) in out_edges_hap {
                     hap_node.out_edges.push((*k, *prob));
+                    hap_node.out_edge_variances.push(*variance);
                     log::trace!("BLOCK {}: {}-{} weight {}", i, j, k, prob);
                 }
             }
@@ -131,7 +176,7 @@ pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>) {
         if i != 0 {
             let prev_out_edges_block = &out_edges_block_hap[i - 1];
             for (j, out_edges_hap) in prev_out_edges_block.iter().enumerate() {
-                for (k, prob) in out_edges_hap.iter() {
+                for (k, prob, _) in out_edges_hap.iter() {
                     let hap_node = &mut hap_block[*k];
                     hap_node.in_edges.push((j, *prob));
                 }
@@ -140,209 +185,261 @@ pub fn update_hap_graph(hap_graph: &mut Vec<Vec<HapNode>>) {
     }
 }
 
-pub fn solve_lp_graph(hap_graph: &Vec<Vec<HapNode>>, glopp_out_dir: String) -> FlowUpVec {
-    let flow_cutoff = 3.0;
-    let mut ae = vec![];
-
-    //LP values
-    let mut pb = RowProblem::default();
-    let mut t = vec![];
-    let mut x = vec![];
-
-    //QP variables
-    // Vector looks like [x,t]
-    let mut num_constraints = 0;
-    let mut a_entry = vec![];
-    let mut p_entry = vec![];
-    let mut l = vec![];
-    let mut u = vec![];
+//Fit flows to the haplotype graph with flow_solver and write the edges with at least flow_cutoff
+//flow (see get_flow_cutoff) to graph.csv.
+pub fn solve_flow_graph(
+    hap_graph: &Vec<Vec<HapNode>>,
+    flow_solver: &dyn FlowSolver,
+    glopp_out_dir: String,
+    flow_cutoff: f64,
+) -> FlowUpVec {
+    let flow_update_vec = flow_solver.solve(hap_graph);
 
-    let mut hap_graph_vec = vec![];
-    for hap_block in hap_graph.iter() {
-        for hap_node in hap_block.iter() {
-            hap_graph_vec.push(hap_node);
+    let mut file = File::create(format!("{}/graph.csv", glopp_out_dir)).expect("Can't create file");
+    for (n1_inf, n2_inf, flow) in flow_update_vec.iter() {
+        if *flow < flow_cutoff {
+            continue;
         }
+        let node1 = &hap_graph[n1_inf.0][n1_inf.1];
+        let node2 = &hap_graph[n2_inf.0][n2_inf.1];
+        let weight = node1
+            .out_edges
+            .iter()
+            .find(|edge| edge.0 == n2_inf.1)
+            .unwrap()
+            .1;
+        writeln!(
+            file,
+            "{},{}-{},{}-{},{}",
+            flow, node1.column, node1.id, node2.column, node2.id, weight
+        )
+        .unwrap();
     }
+    drop(file);
 
-    let mut edge_to_nodes = vec![];
-    let mut nodes_to_edges = FxHashMap::default();
+    flow_update_vec
+}
 
-    for hap_node in hap_graph_vec.iter() {
-        let id1 = hap_node.id;
-        for edge in hap_node.out_edges.iter() {
-            let id2 = hap_graph[hap_node.column + 1][edge.0].id;
-            edge_to_nodes.push((id1, id2));
-            nodes_to_edges.insert((id1, id2), edge_to_nodes.len() - 1);
-            //            ae.push(edge.1 * hap_node.cov());
-            ae.push(edge.1);
-        }
+//Add a column to a MIP; returns its index in cols, which is also its index in the solution.
+fn add_mip_column(
+    pb: &mut RowProblem,
+    cols: &mut Vec<Col>,
+    cost: f64,
+    upper: f64,
+    integer: bool,
+) -> usize {
+    if integer {
+        cols.push(pb.add_integer_column(cost, 0.0..=upper));
+    } else {
+        cols.push(pb.add_column(cost, 0.0..=upper));
     }
+    cols.len() - 1
+}
 
-    for _i in 0..edge_to_nodes.len() {
-        x.push(pb.add_column(0., 0..));
-    }
-    for _i in 0..edge_to_nodes.len() {
-        t.push(pb.add_column(1., 0..));
+//Paths and weights of the MIP with num_paths paths, or None if it is infeasible. Err with the
+//solver's status if the MIP didn't finish, e.g. within time_limit seconds.
+fn solve_exact_paths(
+    nodes: &Vec<NodeIndex>,
+    edges: &Vec<(usize, usize, f64)>,
+    num_paths: usize,
+    tolerance: f64,
+    time_limit: f64,
+) -> Result<Option<Vec<(Vec<NodeIndex>, f64)>>, String> {
+    let max_weight = edges.iter().map(|edge| edge.2).fold(0., f64::max) * (1. + tolerance);
+    let mut in_edges = vec![vec![]; nodes.len()];
+    let mut out_edges = vec![vec![]; nodes.len()];
+    for (e, edge) in edges.iter().enumerate() {
+        out_edges[edge.0].push(e);
+        in_edges[edge.1].push(e);
     }
 
-    for i in edge_to_nodes.len()..2 * edge_to_nodes.len() {
-        p_entry.push((i, i, 1. / ae[i - edge_to_nodes.len()]));
+    let mut pb = RowProblem::default();
+    let mut cols = vec![];
+    let mut weights = vec![];
+    //Whether path k starts at a node, ends at a node or uses an edge.
+    let mut starts = vec![];
+    let mut ends = vec![];
+    let mut uses = vec![];
+    //Flow of path k on an edge: its weight if it uses the edge, otherwise 0.
+    let mut path_flows = vec![];
+    for _k in 0..num_paths {
+        weights.push(add_mip_column(&mut pb, &mut cols, 0., max_weight, false));
+        let mut add_columns = |num: usize, upper: f64, integer: bool| -> Vec<usize> {
+            (0..num)
+                .map(|_| add_mip_column(&mut pb, &mut cols, 0., upper, integer))
+                .collect()
+        };
+        starts.push(add_columns(nodes.len(), 1., true));
+        ends.push(add_columns(nodes.len(), 1., true));
+        uses.push(add_columns(edges.len(), 1., true));
+        path_flows.push(add_columns(edges.len(), max_weight, false));
     }
-
-    for (column_ind, hap_block) in hap_graph.iter().enumerate() {
-        if column_ind == 0 || column_ind == hap_graph.len() - 1 {
-            continue;
+    //Difference between the flow of an edge and the flow of the paths through it.
+    let deviations: Vec<usize> = edges
+        .iter()
+        .map(|edge| add_mip_column(&mut pb, &mut cols, 1., tolerance * edge.2, false))
+        .collect();
+
+    for k in 0..num_paths {
+        pb.add_row(1..=1, starts[k].iter().map(|i| (cols[*i], 1.)));
+        pb.add_row(1..=1, ends[k].iter().map(|i| (cols[*i], 1.)));
+        for i in 0..nodes.len() {
+            let mut constraint_row = vec![(cols[starts[k][i]], 1.), (cols[ends[k][i]], -1.)];
+            for e in in_edges[i].iter() {
+                constraint_row.push((cols[uses[k][*e]], 1.));
+            }
+            for e in out_edges[i].iter() {
+                constraint_row.push((cols[uses[k][*e]], -1.));
+            }
+            pb.add_row(0..=0, &constraint_row);
         }
-        for hap_node in hap_block.iter() {
-            if !hap_node.in_edges.is_empty() && !hap_node.out_edges.is_empty() {
-                let node_id = hap_node.id;
-                let mut in_edge_ids = vec![];
-                let mut out_edge_ids = vec![];
-                for in_edge in hap_node.in_edges.iter() {
-                    let node_id2 = hap_graph[hap_node.column - 1][in_edge.0].id;
-                    let in_edge_id = nodes_to_edges.get(&(node_id2, node_id)).unwrap();
-                    in_edge_ids.push(in_edge_id);
-                }
-                for out_edge in hap_node.out_edges.iter() {
-                    let node_id2 = hap_graph[hap_node.column + 1][out_edge.0].id;
-                    let out_edge_id = nodes_to_edges.get(&(node_id, node_id2)).unwrap();
-                    out_edge_ids.push(out_edge_id);
-                }
-                let mut constraint_row = vec![];
-
-                for in_edge_id in in_edge_ids.iter() {
-                    constraint_row.push((x[**in_edge_id], 1.));
-                }
-                for out_edge_id in out_edge_ids.iter() {
-                    constraint_row.push((x[**out_edge_id], -1.));
-                }
+        for e in 0..edges.len() {
+            let flow = cols[path_flows[k][e]];
+            let used = cols[uses[k][e]];
+            let weight = cols[weights[k]];
+            pb.add_row(..=0, &[(flow, 1.), (used, -max_weight)]);
+            pb.add_row(..=0, &[(flow, 1.), (weight, -1.)]);
+            pb.add_row(-max_weight.., &[(flow, 1.), (weight, -1.), (used, -max_weight)]);
+        }
+        //Heaviest path first, which also removes symmetric solutions.
+        if k > 0 {
+            pb.add_row(0.., &[(cols[weights[k - 1]], 1.), (cols[weights[k]], -1.)]);
+        }
+    }
+    for (e, edge) in edges.iter().enumerate() {
+        let mut constraint_row: Vec<_> = (0..num_paths)
+            .map(|k| (cols[path_flows[k][e]], 1.))
+            .collect();
+        constraint_row.push((cols[deviations[e]], 1.));
+        pb.add_row(edge.2.., &constraint_row);
+        constraint_row.pop();
+        constraint_row.push((cols[deviations[e]], -1.));
+        pb.add_row(..=edge.2, &constraint_row);
+    }
 
-                //                dbg!(&constraint_row, &hap_node.in_edges);
-                pb.add_row(..0, &constraint_row);
-                pb.add_row(0.., &constraint_row);
+    let mut model = pb.optimise(Sense::Minimise);
+    model.make_quiet();
+    model.set_option("time_limit", time_limit);
+    //HiGHS returns a warning instead of a model if it stops at the time limit.
+    let solved = match model.try_solve() {
+        Ok(solved) => solved,
+        Err(status) => return Err(format!("{:?}", status)),
+    };
+    match solved.status() {
+        HighsModelStatus::Optimal => {}
+        HighsModelStatus::Infeasible => return Ok(None),
+        status => return Err(format!("{:?}", status)),
+    }
+    let solution = solved.get_solution();
+    let is_set = |i: usize| solution.columns()[i] > 0.5;
+
+    let mut paths = vec![];
+    for k in 0..num_paths {
+        let mut i = (0..nodes.len()).find(|i| is_set(starts[k][*i])).unwrap();
+        let mut path = vec![nodes[i]];
+        while let Some(e) = out_edges[i].iter().find(|e| is_set(uses[k][**e])) {
+            i = edges[*e].1;
+            path.push(nodes[i]);
+        }
+        paths.push((path, solution.columns()[weights[k]]));
+    }
+    Ok(Some(paths))
+}
 
-                //QP variables
-                for in_edge_id in in_edge_ids {
-                    a_entry.push((*in_edge_id, num_constraints, 1.));
+//Fewest weighted paths which explain the flows of the graph: for every edge, the weights of the
+//paths through it sum to within settings.tolerance of its flow. A path can start and end at any
+//node, and nodes without edges are paths by themselves. Each path through a column of the graph
+//uses at most one of its nodes and edges, so the MIP is solved with num_paths paths for num_paths
+//from the largest number of nodes or edges of a column up to max_paths, and the first feasible
+//solution has the fewest paths. Returns None if there is no solution with at most max_paths paths
+//or the MIPs take longer than settings.time_limit.
+pub fn get_exact_paths(
+    hap_petgraph: &StableGraph<(usize, usize), f64>,
+    max_paths: usize,
+    settings: &ExactPathsSettings,
+) -> Option<Vec<(Vec<NodeIndex>, f64)>> {
+    let start_t = Instant::now();
+    let (nodes, isolated_nodes): (Vec<NodeIndex>, Vec<NodeIndex>) =
+        hap_petgraph.node_indices().partition(|node_index| {
+            hap_petgraph.neighbors_undirected(*node_index).next().is_some()
+        });
+    let node_to_index: FxHashMap<NodeIndex, usize> = nodes
+        .iter()
+        .enumerate()
+        .map(|(i, node_index)| (*node_index, i))
+        .collect();
+    let edges: Vec<(usize, usize, f64)> = hap_petgraph
+        .edge_references()
+        .map(|edge| {
+            (
+                node_to_index[&edge.source()],
+                node_to_index[&edge.target()],
+                *edge.weight(),
+            )
+        })
+        .collect();
+
+    let mut column_counts: FxHashMap<usize, (usize, usize)> = FxHashMap::default();
+    for node_index in nodes.iter() {
+        let column = hap_petgraph[*node_index].0;
+        column_counts.entry(column).or_insert((0, 0)).0 += 1;
+    }
+    for edge in edges.iter() {
+        let column = hap_petgraph[nodes[edge.0]].0;
+        column_counts.entry(column).or_insert((0, 0)).1 += 1;
+    }
+    let min_paths = column_counts
+        .values()
+        .map(|(num_nodes, num_edges)| usize::max(*num_nodes, *num_edges))
+        .max()
+        .unwrap_or(0);
+
+    let mut paths = vec![];
+    if !edges.is_empty() {
+        let mut found = false;
+        for num_paths in min_paths..=usize::max(min_paths, max_paths) {
+            let time_left = settings.time_limit - start_t.elapsed().as_secs_f64();
+            if time_left <= 0. {
+                return None;
+            }
+            match solve_exact_paths(&nodes, &edges, num_paths, settings.tolerance, time_left) {
+                Ok(Some(solution)) => {
+                    paths = solution;
+                    found = true;
+                    break;
                 }
-                for out_edge_id in out_edge_ids {
-                    a_entry.push((*out_edge_id, num_constraints, -1.));
+                Ok(None) => log::trace!("No exact path decomposition with {} paths", num_paths),
+                Err(status) => {
+                    log::debug!("Exact path decomposition stopped: {}", status);
+                    return None;
                 }
-                l.push(0.);
-                u.push(0.);
-                num_constraints += 1;
             }
         }
-    }
-
-    for i in 0..t.len() {
-        pb.add_row(-1.0 * ae[i].., &[(t[i], 1.), (x[i], -1.)]);
-        pb.add_row(1.0 * ae[i].., &[(t[i], 1.), (x[i], 1.)]);
-        pb.add_row(0.0.., &[(x[i], 1.)]);
-    }
-
-    //QP variables
-    for i in 0..t.len() {
-        a_entry.push((i, num_constraints, 1.));
-        a_entry.push((i + t.len(), num_constraints, -1.));
-        l.push(ae[i]);
-        u.push(ae[i]);
-        num_constraints += 1;
-
-        a_entry.push((i, num_constraints, 1.));
-        l.push(0.);
-        u.push(f64::MAX);
-        num_constraints += 1;
-    }
-
-    //QP SOLVING
-    //    let p = entry_list_to_csc(p_entry, t.len() * 2, t.len() * 2);
-    //    let a = entry_list_to_csc(a_entry, t.len() * 2, num_constraints);
-    //    let mut q = vec![0.; t.len() * 2];
-    //    let lambda = 0.;
-    //    for i in 0..x.len() {
-    //        q[i] = lambda;
-    //    }
-    //
-    //    // Disable verbose output
-    //    let settings = Settings::default().verbose(false);
-    //
-    //    // Create an OSQP problem
-    //    let mut prob = Problem::new(p, &q, a, &l, &u, &settings).expect("failed to setup problem");
-    //
-    //    // Solve problem
-    //    let qp_solution = prob.solve();
-    //    println!("{:?}", qp_solution.x().expect("failed to solve problem"));
-
-    let solved = pb.optimise(Sense::Minimise).solve();
-    let solution = solved.get_solution();
-
-    let mut file = File::create(format!("{}/graph.csv", glopp_out_dir)).expect("Can't create file");
-    for i in 0..edge_to_nodes.len() {
-        if solution.columns()[i] < flow_cutoff {
-            continue;
+        if !found {
+            return None;
         }
-        writeln!(
-            file,
-            "{},{}-{},{}-{},{}",
-            &solution.columns()[i],
-            hap_graph_vec[edge_to_nodes[i].0].column,
-            edge_to_nodes[i].0,
-            hap_graph_vec[edge_to_nodes[i].1].column,
-            edge_to_nodes[i].1,
-            ae[i]
-        )
-        .unwrap();
     }
-    drop(file);
-
-    //    let mut file = File::create(format!("{}/qp_graph.csv", glopp_out_dir)).expect("Can't create file");
-    //    for i in 0..edge_to_nodes.len() {
-    //        if qp_solution.x().unwrap()[i] < flow_cutoff {
-    //            continue;
-    //        }
-    //        writeln!(
-    //            file,
-    //            "{},{}-{},{}-{},{}",
-    //            &qp_solution.x().unwrap()[i],
-    //            hap_graph_vec[edge_to_nodes[i].0].column,
-    //            edge_to_nodes[i].0,
-    //            hap_graph_vec[edge_to_nodes[i].1].column,
-    //            edge_to_nodes[i].1,
-    //            ae[i]
-    //        )
-    //        .unwrap();
-    //    }
-    //    drop(file);
-
-    let mut flow_update_vec = vec![];
-    for i in 0..edge_to_nodes.len() {
-        let (node1_id, node2_id) = edge_to_nodes[i];
-        let node1 = hap_graph_vec[node1_id];
-        let node2 = hap_graph_vec[node2_id];
-        let flow = solution.columns()[i];
-        flow_update_vec.push(((node1.column, node1.row), (node2.column, node2.row), flow));
-    }
-
-    println!("Linear program finished.");
-    return flow_update_vec;
+    for node_index in isolated_nodes {
+        paths.push((vec![node_index], 0.));
+    }
+    Some(paths)
 }
 
 fn get_local_hap_blocks<'a>(
     _num_blocks: usize,
     _num_iters: usize,
     all_frags: &'a Vec<Frag>,
-    epsilon: f64,
+    frag_index: &FragIndex,
     snp_to_genome_pos: &'a Vec<usize>,
     max_number_solns: usize,
     _block_length: usize,
     glopp_out_dir: &str,
     j: usize,
     random_vec: &Vec<(usize, usize)>,
+    use_qual: bool,
 ) -> Vec<Vec<HapNode<'a>>> {
     let ploidy_start = 1;
     let ploidy_end = 6;
-    let error_rate = epsilon;
     let num_ploidies = ploidy_end - ploidy_start;
     let mut mec_vector = vec![0.; num_ploidies];
     let mut parts_vector = vec![];
@@ -353,12 +450,15 @@ fn get_local_hap_blocks<'a>(
         random_vec[j].0 + 1,
         random_vec[j].1 + 1,
         all_frags,
+        frag_index,
         usize::MAX,
     );
     let mut best_ploidy = ploidy_start;
     if reads.is_empty() {
         return vec![];
     }
+    //Reads from libraries with different error rates may be mixed.
+    let error_rate = utils_frags::mean_error_rate(reads.iter().copied());
     for ploidy in ploidy_start..ploidy_end {
         best_ploidy = ploidy;
         let mut num_alleles = 0.0;
@@ -370,18 +470,18 @@ fn get_local_hap_blocks<'a>(
         let (break_pos, part) = global_clustering::beam_search_phasing(
             vec![FxHashSet::default(); ploidy],
             &vec_reads_own,
-            epsilon,
             0.05,
 //            f64::MIN,
             0.0001_f64.ln(),
             max_number_solns,
             true,
             false,
+            use_qual,
         );
 
         //            let optimized_part = part;
         let (_new_score, optimized_part, block) =
-            local_clustering::optimize_clustering(part, epsilon, 10);
+            local_clustering::optimize_clustering(part, error_rate, 10, use_qual);
 
         let split_part =
             utils_frags::split_part_using_breaks(&break_pos, &optimized_part, &all_frags);
@@ -395,7 +495,7 @@ fn get_local_hap_blocks<'a>(
             merge_split_parts(split_part, break_pos, endpoints);
         //            let block = utils_frags::hap_block_from_partition(&optimized_part);
         //            let (binom_vec, _freq_vec) = local_clustering::get_partition_stats(&part, &block);
-        let binom_vec = local_clustering::get_mec_stats_epsilon(&optimized_part, &block, epsilon);
+        let binom_vec = local_clustering::get_mec_stats_epsilon(&optimized_part, &block, error_rate);
         for (good, bad) in binom_vec {
             mec_vector[ploidy - ploidy_start] += bad;
             num_alleles += good;
@@ -512,11 +612,12 @@ pub fn generate_hap_graph<'a>(
     num_blocks: usize,
     num_iters: usize,
     all_frags: &'a Vec<Frag>,
-    epsilon: f64,
     snp_to_genome_pos: &'a Vec<usize>,
     max_number_solns: usize,
     block_length: usize,
     glopp_out_dir: String,
+    use_qual: bool,
+    edge_posteriors: bool,
 ) -> Vec<Vec<HapNode<'a>>> {
     let using_bam;
     //Using frags instead of bam
@@ -541,6 +642,7 @@ pub fn generate_hap_graph<'a>(
     let random_vec = iter_vec[0..iter_vec.len()].to_vec();
     log::trace!("SNP Endpoints {:?}", &random_vec);
 
+    let frag_index = FragIndex::new(all_frags);
     let block_chunks: Mutex<Vec<_>> = Mutex::new(vec![]);
     (0..random_vec.len())
         .collect::<Vec<usize>>()
@@ -560,13 +662,14 @@ pub fn generate_hap_graph<'a>(
                 num_blocks,
                 num_iters,
                 all_frags,
-                epsilon,
+                &frag_index,
                 snp_to_genome_pos,
                 max_number_solns,
                 block_length,
                 &glopp_out_dir,
                 j,
                 &random_vec,
+                use_qual,
             );
 
             let mut locked = block_chunks.lock().unwrap();
@@ -576,7 +679,7 @@ pub fn generate_hap_graph<'a>(
     let block_chunks = block_chunks.into_inner().unwrap();
     let mut hap_node_blocks = process_chunks(block_chunks);
     println!("Phasing done");
-    update_hap_graph(&mut hap_node_blocks);
+    update_hap_graph(&mut hap_node_blocks, use_qual, edge_posteriors);
     hap_node_blocks
 }
 
@@ -663,14 +766,105 @@ fn merge_split_parts(
     return (split_part_merge, snp_breakpoints);
 }
 
-pub fn get_disjoint_paths_rewrite(
-    hap_graph: &mut Vec<Vec<HapNode>>,
+//Add the reads of the nodes (column, row) of a path to read_to_parts_map as part path_id. Returns
+//the reads, the SNP endpoints and the largest coverage of the path's nodes.
+fn get_path_part<'a>(
+    path_nodes: &Vec<(usize, usize)>,
+    hap_graph: &Vec<Vec<HapNode<'a>>>,
+    path_id: usize,
+    read_to_parts_map: &mut FxHashMap<&'a Frag, FxHashSet<usize>>,
+    node_num_paths: &mut FxHashMap<(usize, usize), usize>,
+) -> (FxHashSet<&'a Frag>, (usize, usize), f64) {
+    let mut joined_path_part = FxHashSet::default();
+    let mut snp_endpoints = (usize::MAX, usize::MIN);
+    let mut path_cov = 0.;
+    for (col, row) in path_nodes.iter() {
+        let hap_graph_node = &hap_graph[*col][*row];
+        *node_num_paths.entry((*col, *row)).or_insert(0) += 1;
+        path_cov = f64::max(path_cov, hap_graph_node.cov());
+
+        if hap_graph_node.snp_endpoints.0 < snp_endpoints.0 {
+            snp_endpoints.0 = hap_graph_node.snp_endpoints.0;
+        }
+        if hap_graph_node.snp_endpoints.1 > snp_endpoints.1 {
+            snp_endpoints.1 = hap_graph_node.snp_endpoints.1;
+        }
+
+        for frag in hap_graph_node.frag_set.iter() {
+            joined_path_part.insert(*frag);
+            let corresponding_partitions = read_to_parts_map
+                .entry(*frag)
+                .or_insert(FxHashSet::default());
+            corresponding_partitions.insert(path_id);
+        }
+    }
+    (joined_path_part, snp_endpoints, path_cov)
+}
+
+//Pick one of the paths containing a read from a node shared by several paths. The probability of a
+//path is proportional to its flow times the likelihood of the read given the path's alleles. The
+//generator is seeded by the read so that runs are reproducible.
+fn sample_part_for_read(
+    frag: &Frag,
+    part_ids: &Vec<usize>,
+    all_parts_block: &HapBlock,
+    path_flows: &Vec<f64>,
+    use_qual: bool,
+) -> usize {
+    let epsilon = frag.epsilon;
+    let mut log_probs = vec![];
+    for id in part_ids.iter() {
+        let (same, diff) = utils_frags::distance_read_haplo_epsilon_empty(
+            frag,
+            &all_parts_block.blocks[*id],
+            epsilon,
+            use_qual,
+        );
+        //Clamped so that a zero error rate doesn't give 0 * -inf.
+        let log_eps = epsilon.max(1e-6).ln();
+        let log_prob = path_flows[*id].max(f64::MIN_POSITIVE).ln()
+            + same * (1. - epsilon).ln()
+            + diff * log_eps;
+        log_probs.push(log_prob);
+    }
+    let log_total = utils_frags::log_sum_exp(&log_probs);
+    let mut rng = Pcg64::seed_from_u64(frag.counter_id as u64);
+    let mut u: f64 = rng.gen();
+    for (i, log_prob) in log_probs.iter().enumerate() {
+        u -= (log_prob - log_total).exp();
+        if u < 0. {
+            return part_ids[i];
+        }
+    }
+    *part_ids.last().unwrap()
+}
+
+//Returns the haplotigs (read sets) and the SNP endpoints of each haplotig. By default the nodes of
+//each extracted path are removed from the graph. With share_nodes, the path's bottleneck flow is
+//subtracted from its edges instead and nodes with flow left stay in the graph, so that a node where
+//several strains were collapsed into one can be used by each of their paths. Reads in such shared
+//nodes are assigned to one of the paths at random, weighted by the paths' flows and how well the
+//read matches each path. With exact_paths, the greedy paths are replaced by the fewest paths which
+//explain the flows (see get_exact_paths) if they are found within the time limit. The haplotigs'
+//abundances are written to abundances.tsv (see abundance::get_haplotig_abundances). Edges with
+//less than flow_cutoff fitted flow are not used (see get_flow_cutoff).
+pub fn get_disjoint_paths_rewrite<'a>(
+    hap_graph: &mut Vec<Vec<HapNode<'a>>>,
     flow_update_vec: FlowUpVec,
-    epsilon: f64,
     glopp_out_dir: String,
+    contig: &str,
     snp_to_genome_pos: &Vec<usize>,
-) {
-    let flow_cutoff = 3.0;
+    use_qual: bool,
+    share_nodes: bool,
+    exact_paths: Option<&ExactPathsSettings>,
+    flow_cutoff: f64,
+    abundance_settings: &AbundanceSettings,
+) -> (Vec<FxHashSet<&'a Frag>>, Vec<(usize, usize)>) {
+    //Fitted flows of all edges, for the haplotigs' abundances.
+    let fitted_flows: FxHashMap<((usize, usize), (usize, usize)), f64> = flow_update_vec
+        .iter()
+        .map(|(n1_inf, n2_inf, flow)| ((*n1_inf, *n2_inf), *flow))
+        .collect();
     let mut hap_petgraph = StableGraph::<(usize, usize), f64>::new();
     //Update the graph to include flows.
     for (n1_inf, n2_inf, flow) in flow_update_vec {
@@ -737,6 +931,9 @@ pub fn get_disjoint_paths_rewrite(
         };
     }
 
+    //The greedy decomposition below changes the graph.
+    let exact_petgraph = hap_petgraph.clone();
+
     let mut pet_graph_file =
         File::create(format!("{}/pet_graph.dot", glopp_out_dir)).expect("Can't create file");
     write!(pet_graph_file, "{:?}", Dot::new(&hap_petgraph)).unwrap();
@@ -744,6 +941,9 @@ pub fn get_disjoint_paths_rewrite(
     let mut all_joined_path_parts = vec![];
     let mut path_parts_snp_endspoints = vec![];
     let mut best_paths = vec![];
+    let mut all_path_nodes = vec![];
+    let mut path_flows = vec![];
+    let mut node_num_paths: FxHashMap<(usize, usize), usize> = FxHashMap::default();
     let mut read_to_parts_map = FxHashMap::default();
 
     while hap_petgraph.node_count() > 0 {
@@ -860,58 +1060,154 @@ pub fn get_disjoint_paths_rewrite(
             iter_count,
             hap_petgraph.node_count()
         );
-        let mut joined_path_part = FxHashSet::default();
-        let mut snp_endpoints = (usize::MAX, usize::MIN);
+        let mut path_nodes = vec![];
         while !index_of_best_end_node.is_none() {
             let node_index = NodeIndex::new(index_of_best_end_node.unwrap());
             if let None = hap_petgraph.node_weight(node_index) {
                 dbg!(&hap_petgraph, node_index, &trace_back_vec.len());
                 panic!();
             }
-            let (col, row) = hap_petgraph.node_weight(node_index).unwrap();
-            let hap_graph_node = &hap_graph[*col][*row];
-
-            if hap_graph_node.snp_endpoints.0 < snp_endpoints.0 {
-                snp_endpoints.0 = hap_graph_node.snp_endpoints.0;
-            }
-            if hap_graph_node.snp_endpoints.1 > snp_endpoints.1 {
-                snp_endpoints.1 = hap_graph_node.snp_endpoints.1;
-            }
-
-            for frag in hap_graph_node.frag_set.iter() {
-                joined_path_part.insert(*frag);
-                let corresponding_partitions = read_to_parts_map
-                    .entry(*frag)
-                    .or_insert(FxHashSet::default());
-                corresponding_partitions.insert(iter_count);
-            }
+            path_nodes.push(*hap_petgraph.node_weight(node_index).unwrap());
             //            dbg!(index_of_best_end_node,trace_back_vec[index_of_best_end_node.0][index_of_best_end_node.1]);
             best_path.push(index_of_best_end_node);
-            log::trace!("{:?}, {:?}", &index_of_best_end_node, snp_endpoints);
+            log::trace!("{:?}", &index_of_best_end_node);
             index_of_best_end_node = trace_back_vec[index_of_best_end_node.unwrap()].prev_ind;
         }
+        let (joined_path_part, snp_endpoints, path_cov) = get_path_part(
+            &path_nodes,
+            hap_graph,
+            iter_count,
+            &mut read_to_parts_map,
+            &mut node_num_paths,
+        );
+
+        //best_path goes from the sink to the source.
+        let path_edges: Vec<_> = best_path
+            .windows(2)
+            .filter_map(|pair| {
+                hap_petgraph.find_edge(
+                    NodeIndex::new(pair[1].unwrap()),
+                    NodeIndex::new(pair[0].unwrap()),
+                )
+            })
+            .collect();
+        let bottleneck = path_edges
+            .iter()
+            .map(|edge| hap_petgraph[*edge])
+            .fold(f64::MAX, f64::min);
+        if path_edges.is_empty() {
+            path_flows.push(path_cov);
+        } else {
+            path_flows.push(bottleneck);
+        }
 
-        for index in best_path.iter() {
-            let node_index = NodeIndex::new(index.unwrap());
-            hap_petgraph.remove_node(node_index);
+        if share_nodes && !path_edges.is_empty() {
+            //The bottleneck edge drops below the cutoff, so every iteration removes an edge.
+            for edge in path_edges {
+                hap_petgraph[edge] -= bottleneck;
+                if hap_petgraph[edge] < flow_cutoff {
+                    hap_petgraph.remove_edge(edge);
+                }
+            }
+            for index in best_path.iter() {
+                let node_index = NodeIndex::new(index.unwrap());
+                if hap_petgraph.edges_directed(node_index, Direction::Incoming).next().is_none()
+                    && hap_petgraph.edges_directed(node_index, Direction::Outgoing).next().is_none()
+                {
+                    hap_petgraph.remove_node(node_index);
+                }
+            }
+        } else {
+            for index in best_path.iter() {
+                let node_index = NodeIndex::new(index.unwrap());
+                hap_petgraph.remove_node(node_index);
+            }
         }
 
         all_joined_path_parts.push(joined_path_part);
         path_parts_snp_endspoints.push(snp_endpoints);
+        all_path_nodes.push(path_nodes);
 
         iter_count += 1;
 
         best_paths.push(best_path);
     }
 
-    println!("Number of haplotigs/disjoint paths: {}", best_paths.len());
+    if let Some(settings) = exact_paths {
+        let exact_start_t = Instant::now();
+        match get_exact_paths(&exact_petgraph, best_paths.len(), settings) {
+            Some(paths) => {
+                println!(
+                    "{}: Exact path decomposition finished in {:.2}s.",
+                    contig,
+                    exact_start_t.elapsed().as_secs_f64()
+                );
+                all_joined_path_parts.clear();
+                path_parts_snp_endspoints.clear();
+                best_paths.clear();
+                all_path_nodes.clear();
+                path_flows.clear();
+                node_num_paths.clear();
+                read_to_parts_map.clear();
+                for (path_id, (path, weight)) in paths.into_iter().enumerate() {
+                    let path_nodes: Vec<(usize, usize)> = path
+                        .iter()
+                        .map(|node_index| exact_petgraph[*node_index])
+                        .collect();
+                    let (joined_path_part, snp_endpoints, path_cov) = get_path_part(
+                        &path_nodes,
+                        hap_graph,
+                        path_id,
+                        &mut read_to_parts_map,
+                        &mut node_num_paths,
+                    );
+                    all_joined_path_parts.push(joined_path_part);
+                    path_parts_snp_endspoints.push(snp_endpoints);
+                    if path.len() == 1 {
+                        path_flows.push(path_cov);
+                    } else {
+                        path_flows.push(weight);
+                    }
+                    best_paths.push(path.iter().map(|node_index| Some(node_index.index())).collect());
+                    all_path_nodes.push(path_nodes);
+                }
+            }
+            None => println!(
+                "{}: No exact path decomposition found in time; using the greedy paths.",
+                contig
+            ),
+        }
+    }
+
+    println!("{}: Number of haplotigs/disjoint paths: {}", contig, best_paths.len());
     let mut all_parts_block = utils_frags::hap_block_from_partition(&all_joined_path_parts);
+
+    let mut shared_frags = FxHashSet::default();
+    for ((col, row), num_paths) in node_num_paths.iter() {
+        if *num_paths > 1 {
+            shared_frags.extend(hap_graph[*col][*row].frag_set.iter().copied());
+        }
+    }
+    log::trace!("{} reads in nodes shared by paths", shared_frags.len());
+
     for (frag, part_ids) in read_to_parts_map {
+        if part_ids.len() > 1 && shared_frags.contains(frag) {
+            let mut part_ids: Vec<usize> = part_ids.into_iter().collect();
+            part_ids.sort();
+            let chosen_part = sample_part_for_read(frag, &part_ids, &all_parts_block, &path_flows, use_qual);
+            for id in part_ids.iter() {
+                if *id != chosen_part {
+                    all_joined_path_parts[*id].remove(frag);
+                    utils_frags::remove_read_from_block(&mut all_parts_block, frag, *id);
+                }
+            }
+            continue;
+        }
         let mut diff_part_vec = vec![];
         for id in part_ids.iter() {
             let block_with_id = &all_parts_block.blocks[*id];
             let (_same, diff) =
-                utils_frags::distance_read_haplo_epsilon_empty(frag, block_with_id, epsilon);
+                utils_frags::distance_read_haplo_epsilon_empty(frag, block_with_id, frag.epsilon, use_qual);
             diff_part_vec.push((diff, id));
         }
         let best_part = diff_part_vec
@@ -951,4 +1247,41 @@ pub fn get_disjoint_paths_rewrite(
         .unwrap();
         writeln!(path_debug_file, "{:?}", path_parts_snps_endpoints_copy[i]).unwrap();
     }
+
+    let mut path_edge_flows = vec![];
+    let mut path_coverages = vec![];
+    for (i, path_nodes) in all_path_nodes.iter().enumerate() {
+        let mut path_nodes = path_nodes.clone();
+        path_nodes.sort();
+        let mut edge_flows: Vec<f64> = path_nodes
+            .windows(2)
+            .filter_map(|pair| fitted_flows.get(&(pair[0], pair[1])).copied())
+            .collect();
+        //A path of one node has no edges; its flow is the node's coverage as above.
+        if edge_flows.is_empty() {
+            edge_flows.push(path_flows[i]);
+        }
+        path_edge_flows.push(edge_flows);
+        path_coverages.push(
+            path_nodes
+                .iter()
+                .map(|(col, row)| hap_graph[*col][*row].cov())
+                .collect(),
+        );
+    }
+    let haplotig_abundances = abundance::get_haplotig_abundances(
+        &all_joined_path_parts,
+        &path_parts_snps_endpoints_copy,
+        &path_edge_flows,
+        &path_coverages,
+        use_qual,
+        abundance_settings,
+    );
+    file_reader::write_haplotig_abundances(
+        &glopp_out_dir_copy,
+        &path_parts_snps_endpoints_copy,
+        &haplotig_abundances,
+    );
+
+    (all_joined_path_parts, path_parts_snps_endpoints_copy)
 }
@@ -11,3 +11,13 @@ pub mod global_clustering;
 pub mod vcf_polishing;
 
 pub mod graph_processing;
+
+pub mod cli_args;
+
+pub mod scaffolding;
+
+pub mod frag_cache;
+
+pub mod flow_solver;
+
+pub mod abundance;
@@ -1,4 +1,4 @@
-use crate::types_structs::{Frag, HapBlock};
+use crate::types_structs::{Frag, FragIndex, HapBlock};
 use rand::prelude::*;
 use rand_core::SeedableRng;
 //use rand::rng::Rng;
@@ -23,47 +23,24 @@ macro_rules! debug {
 pub fn find_reads_in_interval<'a>(
     start: usize,
     end: usize,
-    //position_to_reads : &FxHashMap<usize,FxHashSet<&Frag>>,
     all_frags: &'a Vec<Frag>,
+    frag_index: &FragIndex,
     max_num_reads: usize
 ) -> FxHashSet<&'a Frag> {
     let mut final_set = FxHashSet::default();
-    //Original method of doing this : This is slower than just iterating thorugh the entire fragment list. We can speed this up by
-    //indexing the fragments as well.
-
-    //    for i in (start..end+1).step_by(10){
-    //        let empty_set = &FxHashSet::default();
-    //        let set1 = position_to_reads.get(&(i)).unwrap_or(empty_set);
-    //        for elt in set1.iter() {
-    //            final_set.insert(*elt);
-    //        }
-    //    }
 
-    //Frags are sorted by first position so we can do this.
-    for frag in all_frags.iter() {
+    //Indices are in fragment order, i.e. by first position, so the cap keeps the leftmost reads.
+    for i in frag_index.query(start, end) {
         if final_set.len() > max_num_reads{
             break;
         }
-        if frag.last_position < start {
-            continue;
-        }
-        if frag.first_position > end {
-            break;
-        }
+        let frag = &all_frags[i];
 
         //TODO we use this routine in glopp estimate ploidy, don't want circular mappings.
         if frag.last_position - frag.first_position > 10000{
             continue;
         }
 
-        //Currently we use 1/3 quantile as the length of the block, i.e.
-        //end-start. If a mapping is weird and the fragment
-        //spans several regions, we ignore the fragment.
-        if false{
-        //if frag.last_position - frag.first_position > 60 * (end - start) {
-            continue;
-        }
-
         final_set.insert(frag);
     }
     final_set
@@ -76,11 +53,12 @@ pub fn generate_hap_block<'a>(
     //position_to_reads : &'a FxHashMap<usize,FxHashSet<&Frag>>,
     ploidy: usize,
     all_frags: &'a Vec<Frag>,
+    frag_index: &FragIndex,
     epsilon: f64,
 ) -> Vec<FxHashSet<&'a Frag>> {
     //debug!(start);
     //debug!(end);
-    let all_reads = find_reads_in_interval(start, end, all_frags, 100);
+    let all_reads = find_reads_in_interval(start, end, all_frags, frag_index, 100);
     let partition = cluster_reads(&all_reads, ploidy, epsilon);
     partition
 }
@@ -366,7 +344,7 @@ fn populate_clusters3(
                     if cluster.contains(&edge.1) {
                         let edge_index = edge.1 as usize;
                         let read2 = vec_all_reads[edge_index];
-                        let t: Vec<_> = read.positions.intersection(&read2.positions).collect();
+                        let t = read.shared_positions(read2);
                         let overlap_len = t.len();
                         if overlap_len > read_overlaps_between_clusters[j] {
                             read_overlaps_between_clusters[j] = overlap_len;
@@ -573,7 +551,7 @@ fn _populate_clusters1(
                     if cluster.contains(&edge.1) {
                         let edge_index = edge.1 as usize;
                         let read2 = vec_all_reads[edge_index];
-                        let t: Vec<_> = read.positions.intersection(&read2.positions).collect();
+                        let t = read.shared_positions(read2);
                         let overlap_len = t.len();
                         if overlap_len > read_overlaps_between_clusters[j] {
                             read_overlaps_between_clusters[j] = overlap_len;
@@ -644,6 +622,7 @@ pub fn optimize_clustering<'a>(
     partition: Vec<FxHashSet<&'a Frag>>,
     epsilon: f64,
     max_iters: usize,
+    use_qual: bool,
 ) -> (f64, Vec<FxHashSet<&'a Frag>>, HapBlock) {
     let mut not_empty = false;
     for part in partition.iter() {
@@ -675,7 +654,7 @@ pub fn optimize_clustering<'a>(
     //Iterate until an iteration yields a lower UPEM score -- return partition corresponding
     //to the best UPEM score.
     for _i in 0..max_iters {
-        let new_part = opt_iterate(&best_part, &prev_hap_block, epsilon);
+        let new_part = opt_iterate(&best_part, &prev_hap_block, use_qual);
         let new_block = utils_frags::hap_block_from_partition(&new_part);
         let new_binom_vec = get_mec_stats_epsilon(&new_part, &new_block,epsilon);
         let new_score = new_binom_vec.iter().map(|x| x.1).sum::<f64>() * -1.;
@@ -889,7 +868,7 @@ pub fn get_mec_score(
 fn opt_iterate<'a>(
     partition: &Vec<FxHashSet<&'a Frag>>,
     hap_block: &HapBlock,
-    epsilon: f64,
+    use_qual: bool,
 ) -> Vec<FxHashSet<&'a Frag>> {
     let ploidy = partition.len();
     let mut best_moves = Vec::new();
@@ -900,7 +879,7 @@ fn opt_iterate<'a>(
         }
         for read in partition[i].iter() {
             let haplo_i = &hap_block.blocks[i];
-            let (_bases_good_read, errors_read) = utils_frags::distance_read_haplo_epsilon_empty(read, haplo_i, epsilon);
+            let (_bases_good_read, errors_read) = utils_frags::distance_read_haplo_epsilon_empty(read, haplo_i, read.epsilon, use_qual);
             for j in 0..ploidy {
                 if j == i {
                     continue;
@@ -909,7 +888,7 @@ fn opt_iterate<'a>(
                 //Test out new move
                 let haplo_j = &hap_block.blocks[j];
                 let (_read_bases_good_movej, read_errors_movej) =
-                    utils_frags::distance_read_haplo_epsilon_empty(read, haplo_j, epsilon);
+                    utils_frags::distance_read_haplo_epsilon_empty(read, haplo_j, read.epsilon, use_qual);
 
                 let diff_score = errors_read - read_errors_movej;
                 if diff_score > 0.0 {
@@ -970,6 +949,7 @@ pub fn estimate_epsilon(
         random_vec.push(rng.gen_range(0..num_iters));
     }
 
+    let frag_index = FragIndex::new(all_frags);
     let mut part_stats = vec![Vec::new();ploidy];
     for i in random_vec.into_iter() {
         let part = generate_hap_block(
@@ -977,6 +957,7 @@ pub fn estimate_epsilon(
             (i + 1) * block_len,
             ploidy,
             all_frags,
+            &frag_index,
             initial_epsilon,
         );
         let mut part_lens: Vec<usize> = part.iter().map(|x| x.len()).collect();
@@ -1028,6 +1009,7 @@ pub fn estimate_ploidy_flopp(
     let num_ploidies = ploidy_end - ploidy_start;
     let mut mec_vector = vec![0;num_ploidies];
     let mut expected_errors_ref = vec![];
+    let frag_index = FragIndex::new(all_frags);
     for ploidy in ploidy_start..ploidy_end{
         let mut num_alleles = 0;
         for i in random_vec.iter(){
@@ -1036,6 +1018,7 @@ pub fn estimate_ploidy_flopp(
                 (i + 1) * block_len,
                 ploidy,
                 all_frags,
+                &frag_index,
                 initial_epsilon,
             );
             let mut part_lens: Vec<usize> = part.iter().map(|x| x.len()).collect();
@@ -0,0 +1,269 @@
+use crate::types_structs::{Frag, HaplotigJoin};
+use fxhash::{FxHashMap, FxHashSet};
+
+//Haplotigs end wherever the read overlap graph breaks. Hi-C read pairs span much longer distances,
+//so a pair whose alleles match haplotig A on one side and haplotig B on the other links A and B.
+//Haplotigs with enough consistent links are joined into scaffolds, which are written with a
+//shared phase set.
+
+fn ranges_overlap(r1: (usize, usize), r2: (usize, usize)) -> bool {
+    r1.0 <= r2.1 && r2.0 <= r1.1
+}
+
+//SNPs of each haplotig's range which are covered by its reads. Neighbouring haplotigs can share
+//the SNPs at a break in their ranges, so overlaps between haplotigs are checked on these spans.
+pub fn get_haplotig_spans(
+    haplotigs: &Vec<FxHashSet<&Frag>>,
+    snp_endpoints: &Vec<(usize, usize)>,
+) -> Vec<(usize, usize)> {
+    let mut spans = vec![];
+    for (i, set) in haplotigs.iter().enumerate() {
+        let range = snp_endpoints[i];
+        let mut span = (usize::MAX, usize::MIN);
+        for frag in set.iter() {
+            for pos in frag.positions() {
+                if pos >= range.0 && pos <= range.1 {
+                    span.0 = usize::min(span.0, pos);
+                    span.1 = usize::max(span.1, pos);
+                }
+            }
+        }
+        if span.0 > span.1 {
+            span = range;
+        }
+        spans.push(span);
+    }
+    spans
+}
+
+//Count the Hi-C fragments linking each pair of haplotigs that don't overlap. A fragment is placed
+//on a haplotig if it agrees with the haplotig's consensus at every covered SNP in its range, and no
+//other overlapping haplotig also agrees with it, i.e. the SNPs actually tell the haplotigs apart.
+//Keys are (left haplotig, right haplotig).
+pub fn get_hic_links(
+    haplotigs: &Vec<FxHashSet<&Frag>>,
+    spans: &Vec<(usize, usize)>,
+    hic_frags: &Vec<Frag>,
+) -> FxHashMap<(usize, usize), usize> {
+    let mut snp_to_consensus: FxHashMap<usize, Vec<(usize, usize)>> = FxHashMap::default();
+    for (i, set) in haplotigs.iter().enumerate() {
+        let range = spans[i];
+        let mut allele_counts: FxHashMap<usize, FxHashMap<usize, usize>> = FxHashMap::default();
+        for frag in set.iter() {
+            for (pos, allele) in frag.calls() {
+                if pos < range.0 || pos > range.1 {
+                    continue;
+                }
+                *allele_counts
+                    .entry(pos)
+                    .or_insert(FxHashMap::default())
+                    .entry(allele)
+                    .or_insert(0) += 1;
+            }
+        }
+        for (pos, counts) in allele_counts {
+            let best_allele = counts
+                .into_iter()
+                .max_by_key(|(allele, count)| (*count, usize::MAX - *allele))
+                .unwrap()
+                .0;
+            snp_to_consensus
+                .entry(pos)
+                .or_insert(vec![])
+                .push((i, best_allele));
+        }
+    }
+
+    let mut links = FxHashMap::default();
+    for frag in hic_frags.iter() {
+        //Haplotig -> (same, diff) allele counts.
+        let mut agreement: FxHashMap<usize, (usize, usize)> = FxHashMap::default();
+        for (pos, allele) in frag.calls() {
+            if let Some(consensus) = snp_to_consensus.get(&pos) {
+                for (i, best_allele) in consensus.iter() {
+                    let counts = agreement.entry(*i).or_insert((0, 0));
+                    if *best_allele == allele {
+                        counts.0 += 1;
+                    } else {
+                        counts.1 += 1;
+                    }
+                }
+            }
+        }
+        let consistent: Vec<usize> = agreement
+            .iter()
+            .filter(|(_, counts)| counts.0 > 0 && counts.1 == 0)
+            .map(|(i, _)| *i)
+            .collect();
+        let mut placed: Vec<usize> = consistent
+            .iter()
+            .filter(|i| {
+                !consistent
+                    .iter()
+                    .any(|j| *j != **i && ranges_overlap(spans[*j], spans[**i]))
+            })
+            .copied()
+            .collect();
+        placed.sort_by_key(|i| spans[*i]);
+        for (k, i) in placed.iter().enumerate() {
+            for j in placed[k + 1..].iter() {
+                *links.entry((*i, *j)).or_insert(0) += 1;
+            }
+        }
+    }
+
+    links
+}
+
+fn find_scaffold(parent: &mut Vec<usize>, i: usize) -> usize {
+    let mut root = i;
+    while parent[root] != root {
+        root = parent[root];
+    }
+    let mut i = i;
+    while parent[i] != root {
+        let next = parent[i];
+        parent[i] = root;
+        i = next;
+    }
+    root
+}
+
+//Turn Hi-C links into joins. The confidence of joining A and B is the number of links between A and
+//B over all links from A to haplotigs overlapping B and from B to haplotigs overlapping A, i.e.
+//how much of the evidence picks B over the other haplotypes in its region (and vice versa). Joins
+//are made greedily by confidence; a scaffold is a single haplotype, so two scaffolds are only
+//joined if none of their haplotigs overlap. Every candidate with at least min_links links is
+//returned, with joined set for the ones that were made.
+pub fn get_haplotig_joins(
+    spans: &Vec<(usize, usize)>,
+    links: &FxHashMap<(usize, usize), usize>,
+    min_links: usize,
+    min_confidence: f64,
+) -> Vec<HaplotigJoin> {
+    let mut links_of: Vec<Vec<(usize, usize)>> = vec![vec![]; spans.len()];
+    for ((left, right), count) in links.iter() {
+        links_of[*left].push((*right, *count));
+        links_of[*right].push((*left, *count));
+    }
+
+    let mut joins = vec![];
+    for ((left, right), count) in links.iter() {
+        if *count < min_links {
+            continue;
+        }
+        let mut total = 0;
+        for (other, other_count) in links_of[*left].iter() {
+            if ranges_overlap(spans[*other], spans[*right]) {
+                total += other_count;
+            }
+        }
+        for (other, other_count) in links_of[*right].iter() {
+            if ranges_overlap(spans[*other], spans[*left]) {
+                total += other_count;
+            }
+        }
+        total -= count;
+        joins.push(HaplotigJoin {
+            left: *left,
+            right: *right,
+            links: *count,
+            confidence: *count as f64 / total as f64,
+            joined: false,
+        });
+    }
+    joins.sort_by(|x, y| {
+        y.confidence
+            .partial_cmp(&x.confidence)
+            .unwrap()
+            .then(y.links.cmp(&x.links))
+            .then((x.left, x.right).cmp(&(y.left, y.right)))
+    });
+
+    let mut parent: Vec<usize> = (0..spans.len()).collect();
+    let mut members: Vec<Vec<usize>> = (0..spans.len()).map(|i| vec![i]).collect();
+    for join in joins.iter_mut() {
+        if join.confidence < min_confidence {
+            continue;
+        }
+        let root_left = find_scaffold(&mut parent, join.left);
+        let root_right = find_scaffold(&mut parent, join.right);
+        if root_left == root_right {
+            continue;
+        }
+        let clash = members[root_left].iter().any(|i| {
+            members[root_right]
+                .iter()
+                .any(|j| ranges_overlap(spans[*i], spans[*j]))
+        });
+        if clash {
+            continue;
+        }
+        parent[root_right] = root_left;
+        let moved = std::mem::take(&mut members[root_right]);
+        members[root_left].extend(moved);
+        join.joined = true;
+    }
+
+    joins
+}
+
+//Scaffold index of each haplotig. Scaffolds are numbered by their first SNP.
+pub fn get_scaffolds(snp_endpoints: &Vec<(usize, usize)>, joins: &Vec<HaplotigJoin>) -> Vec<usize> {
+    let mut parent: Vec<usize> = (0..snp_endpoints.len()).collect();
+    for join in joins.iter() {
+        if join.joined {
+            let root_left = find_scaffold(&mut parent, join.left);
+            let root_right = find_scaffold(&mut parent, join.right);
+            parent[root_right] = root_left;
+        }
+    }
+    let roots: Vec<usize> = (0..snp_endpoints.len())
+        .map(|i| find_scaffold(&mut parent, i))
+        .collect();
+    let mut scaffold_starts: FxHashMap<usize, (usize, usize)> = FxHashMap::default();
+    for (i, root) in roots.iter().enumerate() {
+        let start = scaffold_starts.entry(*root).or_insert((snp_endpoints[i].0, i));
+        if (snp_endpoints[i].0, i) < *start {
+            *start = (snp_endpoints[i].0, i);
+        }
+    }
+    let mut sorted_roots: Vec<(usize, usize, usize)> = scaffold_starts
+        .into_iter()
+        .map(|(root, start)| (start.0, start.1, root))
+        .collect();
+    sorted_roots.sort();
+    let root_to_scaffold: FxHashMap<usize, usize> = sorted_roots
+        .iter()
+        .enumerate()
+        .map(|(scaffold, (_, _, root))| (*root, scaffold))
+        .collect();
+    roots.iter().map(|root| root_to_scaffold[root]).collect()
+}
+
+//Phase set of each haplotig: the genome position of the first SNP of its scaffold. Without joins
+//this is the first SNP of the haplotig itself.
+pub fn get_phase_sets(
+    snp_endpoints: &Vec<(usize, usize)>,
+    scaffolds: &Vec<usize>,
+    snp_to_genome_pos: &Vec<usize>,
+) -> Vec<i32> {
+    let mut scaffold_first_snp: FxHashMap<usize, usize> = FxHashMap::default();
+    for (i, scaffold) in scaffolds.iter().enumerate() {
+        let first_snp = scaffold_first_snp.entry(*scaffold).or_insert(usize::MAX);
+        if snp_endpoints[i].0 < *first_snp {
+            *first_snp = snp_endpoints[i].0;
+        }
+    }
+    scaffolds
+        .iter()
+        .map(|scaffold| {
+            let first_snp = scaffold_first_snp[scaffold];
+            if first_snp > 0 && snp_to_genome_pos.len() >= first_snp {
+                snp_to_genome_pos[first_snp - 1] as i32
+            } else {
+                first_snp as i32
+            }
+        })
+        .collect()
+}
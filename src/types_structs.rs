@@ -11,20 +11,212 @@ pub struct TraceBackNode{
     pub is_sink: bool,
     pub is_source: bool
 }
-//Positions are inclusive
-#[derive(Eq, Debug, Clone)]
+//Read bases packed two per byte with htslib's 4-bit encoding (=ACMGRSVTWYHKDBN). Other
+//characters are stored as N.
+#[derive(Debug, Clone, Default)]
+pub struct PackedSeq {
+    pub len: usize,
+    pub encoded: Vec<u8>,
+}
+
+const PACKED_BASES: &[u8; 16] = b"=ACMGRSVTWYHKDBN";
+
+fn encode_base(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'=' => 0,
+        b'A' => 1,
+        b'C' => 2,
+        b'M' => 3,
+        b'G' => 4,
+        b'R' => 5,
+        b'S' => 6,
+        b'V' => 7,
+        b'T' => 8,
+        b'W' => 9,
+        b'Y' => 10,
+        b'H' => 11,
+        b'K' => 12,
+        b'D' => 13,
+        b'B' => 14,
+        _ => 15,
+    }
+}
+
+impl PackedSeq {
+    pub fn from_bytes(seq: &[u8]) -> PackedSeq {
+        let mut encoded = vec![0; (seq.len() + 1) / 2];
+        for (i, base) in seq.iter().enumerate() {
+            encoded[i / 2] |= encode_base(*base) << ((1 - i % 2) * 4);
+        }
+        PackedSeq {
+            len: seq.len(),
+            encoded: encoded,
+        }
+    }
+
+    //Take a sequence already in htslib's encoding, e.g. from bam::record::Seq.
+    pub fn from_encoded(encoded: &[u8], len: usize) -> PackedSeq {
+        PackedSeq {
+            len: len,
+            encoded: encoded[..(len + 1) / 2].to_vec(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn base(&self, i: usize) -> u8 {
+        PACKED_BASES[((self.encoded[i / 2] >> ((1 - i % 2) * 4)) & 15) as usize]
+    }
+
+    //Decode bases start..end.
+    pub fn slice(&self, start: usize, end: usize) -> Vec<u8> {
+        (start..end).map(|i| self.base(i)).collect()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.slice(0, self.len)
+    }
+}
+
+//Positions are inclusive. Allele calls are sorted by SNP and stored as parallel arrays: the SNP
+//index, the allele, its quality, and the mate (0 or 1) and offset in the mate's sequence where the
+//SNP was read. Use the accessors below instead of the arrays where possible.
+#[derive(Debug, Clone)]
 pub struct Frag {
     pub id: String,
     pub counter_id: usize,
-    pub seq_dict: FxHashMap<usize, usize>,
-    pub qual_dict: FxHashMap<usize, u8>,
-    pub positions: FxHashSet<usize>,
+    pub snps: Vec<u32>,
+    pub alleles: Vec<u8>,
+    pub quals: Vec<u8>,
+    pub mates: Vec<u8>,
+    pub seq_offsets: Vec<u32>,
     pub first_position: usize,
     pub last_position: usize,
-    pub seq_string: Vec<Vec<u8>>,
+    //Sequence and Phred+33 qualities of each mate; empty unless kept by the reader.
+    pub seq_string: Vec<PackedSeq>,
     pub qual_string: Vec<Vec<u8>>,
     pub is_paired :bool, 
-    pub snp_pos_to_seq_pos: FxHashMap<usize,(u8, usize)>,
+    //Index of the input BAM file the fragment comes from. Reads with the same name from different
+    //files are different fragments.
+    pub source_file: usize,
+    //Library (read type) of the fragment and its allele call error rate; see
+    //utils_frags::set_library_error_rates.
+    pub library: usize,
+    pub epsilon: f64,
+    //Names of the reads merged into a linked-read molecule. Empty for fragments made from a single
+    //read (pair), whose id is the read name.
+    pub molecule_reads: Vec<String>,
+}
+
+impl Frag {
+    pub fn num_calls(&self) -> usize {
+        self.snps.len()
+    }
+
+    fn call_index(&self, snp: usize) -> Option<usize> {
+        if snp > u32::MAX as usize {
+            return None;
+        }
+        self.snps.binary_search(&(snp as u32)).ok()
+    }
+
+    pub fn contains(&self, snp: usize) -> bool {
+        self.call_index(snp).is_some()
+    }
+
+    pub fn get_allele(&self, snp: usize) -> Option<usize> {
+        self.call_index(snp).map(|i| self.alleles[i] as usize)
+    }
+
+    pub fn get_qual(&self, snp: usize) -> Option<u8> {
+        self.call_index(snp).map(|i| self.quals[i])
+    }
+
+    //(mate, offset in the mate's sequence) of a SNP.
+    pub fn get_seq_pos(&self, snp: usize) -> Option<(u8, usize)> {
+        self.call_index(snp)
+            .map(|i| (self.mates[i], self.seq_offsets[i] as usize))
+    }
+
+    //SNPs in increasing order.
+    pub fn positions(&self) -> impl Iterator<Item = usize> + '_ {
+        self.snps.iter().map(|snp| *snp as usize)
+    }
+
+    //(SNP, allele) calls in increasing SNP order.
+    pub fn calls(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        self.snps
+            .iter()
+            .zip(self.alleles.iter())
+            .map(|(snp, allele)| (*snp as usize, *allele as usize))
+    }
+
+    //SNPs called by both fragments, in increasing order.
+    pub fn shared_positions(&self, other: &Frag) -> Vec<usize> {
+        let mut shared = vec![];
+        let mut i = 0;
+        let mut j = 0;
+        while i < self.snps.len() && j < other.snps.len() {
+            if self.snps[i] < other.snps[j] {
+                i += 1;
+            } else if self.snps[i] > other.snps[j] {
+                j += 1;
+            } else {
+                shared.push(self.snps[i] as usize);
+                i += 1;
+                j += 1;
+            }
+        }
+        shared
+    }
+
+    //Add or replace the call at a SNP. Reads are scanned left to right, so calls are almost always
+    //appended.
+    pub fn add_call(&mut self, snp: usize, allele: usize, qual: u8, mate: u8, seq_offset: usize) {
+        if snp > u32::MAX as usize || allele > u8::MAX as usize || seq_offset > u32::MAX as usize {
+            panic!(
+                "Allele call out of range: SNP {}, allele {}, read offset {}",
+                snp, allele, seq_offset
+            );
+        }
+        let snp32 = snp as u32;
+        let i = match self.snps.last() {
+            Some(last) if *last >= snp32 => match self.snps.binary_search(&snp32) {
+                Ok(i) => {
+                    self.alleles[i] = allele as u8;
+                    self.quals[i] = qual;
+                    self.mates[i] = mate;
+                    self.seq_offsets[i] = seq_offset as u32;
+                    return;
+                }
+                Err(i) => i,
+            },
+            _ => self.snps.len(),
+        };
+        self.snps.insert(i, snp32);
+        self.alleles.insert(i, allele as u8);
+        self.quals.insert(i, qual);
+        self.mates.insert(i, mate);
+        self.seq_offsets.insert(i, seq_offset as u32);
+        if snp < self.first_position {
+            self.first_position = snp;
+        }
+        if snp > self.last_position {
+            self.last_position = snp;
+        }
+    }
+
+    //Drop the read sequences once they are no longer needed.
+    pub fn clear_seqs(&mut self) {
+        self.seq_string = vec![PackedSeq::default(); 2];
+        self.qual_string = vec![vec![]; 2];
+    }
 }
 
 impl Hash for Frag {
@@ -40,6 +232,100 @@ impl PartialEq for Frag {
     }
 }
 
+impl Eq for Frag {}
+
+//Interval index over the SNP spans (first_position..=last_position) of a contig's fragments, so
+//the fragments overlapping a block can be found without scanning all of them. This is an
+//implicit augmented interval tree (as in cgranges): the spans are sorted by first position and the
+//node at index i on level k stores the largest end in its subtree.
+pub struct FragIndex {
+    //(first_position, last_position + 1, index in the fragment vector)
+    spans: Vec<(usize, usize, usize)>,
+    max_ends: Vec<usize>,
+    root_level: usize,
+}
+
+impl FragIndex {
+    pub fn new(all_frags: &Vec<Frag>) -> FragIndex {
+        let mut spans: Vec<(usize, usize, usize)> = all_frags
+            .iter()
+            .enumerate()
+            .map(|(i, frag)| (frag.first_position, frag.last_position + 1, i))
+            .collect();
+        spans.sort();
+        let n = spans.len();
+        let mut max_ends: Vec<usize> = spans.iter().map(|span| span.1).collect();
+        let mut root_level = 0;
+        if n > 0 {
+            let mut last_i = (n - 1) & !1;
+            let mut last = max_ends[last_i];
+            let mut k = 1;
+            while 1 << k <= n {
+                let x = 1 << (k - 1);
+                let mut i = (x << 1) - 1;
+                while i < n {
+                    let left = max_ends[i - x];
+                    let right = if i + x < n { max_ends[i + x] } else { last };
+                    max_ends[i] = max_ends[i].max(left).max(right);
+                    i += x << 2;
+                }
+                //Move last_i to its parent.
+                last_i = if (last_i >> k) & 1 == 1 { last_i - x } else { last_i + x };
+                if last_i < n && max_ends[last_i] > last {
+                    last = max_ends[last_i];
+                }
+                k += 1;
+            }
+            root_level = k - 1;
+        }
+        FragIndex {
+            spans,
+            max_ends,
+            root_level,
+        }
+    }
+
+    //Indices of the fragments whose span overlaps SNPs start..=end, in increasing order.
+    pub fn query(&self, start: usize, end: usize) -> Vec<usize> {
+        let mut hits = vec![];
+        let n = self.spans.len();
+        if n == 0 {
+            return hits;
+        }
+        let end = end + 1;
+        //(node, level, left child visited)
+        let mut stack = vec![((1 << self.root_level) - 1, self.root_level, false)];
+        while let Some((x, k, visited)) = stack.pop() {
+            if k <= 3 {
+                //Small subtree; scan it.
+                let i0 = x >> k << k;
+                let i1 = usize::min(i0 + (1 << (k + 1)) - 1, n);
+                for i in i0..i1 {
+                    if self.spans[i].0 >= end {
+                        break;
+                    }
+                    if start < self.spans[i].1 {
+                        hits.push(self.spans[i].2);
+                    }
+                }
+            } else if !visited {
+                let left = x - (1 << (k - 1));
+                stack.push((x, k, true));
+                if left >= n || self.max_ends[left] > start {
+                    stack.push((left, k - 1, false));
+                }
+            } else if x < n && self.spans[x].0 < end {
+                if start < self.spans[x].1 {
+                    hits.push(self.spans[x].2);
+                }
+                stack.push((x + (1 << (k - 1)), k - 1, false));
+            }
+        }
+        hits.sort_unstable();
+        hits
+    }
+}
+
 #[derive(PartialEq)]
 pub struct SearchNode<'a> {
     pub read: &'a Frag,
@@ -84,6 +370,9 @@ impl Drop for SearchNode<'_> {
 pub struct HapNode<'a> {
     pub frag_set: FxHashSet<&'a Frag>,
     pub out_edges: Vec<(usize,f64)>,
+    //Variances of the out edges' weights from splitting reads between nodes; see
+    //graph_processing::update_hap_graph. Empty or 0 for read counts.
+    pub out_edge_variances: Vec<f64>,
     pub in_edges: Vec<(usize,f64)>,
     pub column: usize,
     pub row: usize,
@@ -99,10 +388,9 @@ impl<'a> HapNode<'a> {
         
         let mut hap_map = FxHashMap::default();
         for frag in frag_set.iter() {
-            for pos in frag.positions.iter() {
-                let var_at_pos = frag.seq_dict.get(pos).unwrap();
-                let sites = hap_map.entry(*pos).or_insert(FxHashMap::default());
-                let site_counter = sites.entry(*var_at_pos).or_insert(0);
+            for (pos, var_at_pos) in frag.calls() {
+                let sites = hap_map.entry(pos).or_insert(FxHashMap::default());
+                let site_counter = sites.entry(var_at_pos).or_insert(0);
                 *site_counter += 1;
             }
         }
@@ -118,6 +406,7 @@ impl<'a> HapNode<'a> {
         let toret = HapNode {
             frag_set: frag_set,
             out_edges: vec![],
+            out_edge_variances: vec![],
             in_edges: vec![],
             column: usize::MAX,
             row: usize::MAX,
@@ -173,6 +462,156 @@ pub fn build_child_node<'a>(
     toret
 }
 
+//A genomic region. start is 0-based and end is exclusive; if end is None, the region extends to
+//the end of the contig.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GenomeRegion {
+    pub contig: String,
+    pub start: u64,
+    pub end: Option<u64>,
+}
+
+//A candidate join of two haplotigs from Hi-C links; see scaffolding::get_haplotig_joins. The left
+//haplotig ends before the right haplotig starts.
+#[derive(Debug, Clone)]
+pub struct HaplotigJoin {
+    pub left: usize,
+    pub right: usize,
+    pub links: usize,
+    //Fraction of the Hi-C links of the two haplotigs to the other's region which support this join.
+    pub confidence: f64,
+    pub joined: bool,
+}
+
+//Abundance estimates of a haplotig; see abundance::get_haplotig_abundances.
+#[derive(Debug, Clone)]
+pub struct HaplotigAbundance {
+    //Mean and variance of the fitted flows of the haplotig's edges in the haplotype graph.
+    pub mean_flow: f64,
+    pub flow_variance: f64,
+    //Mean coverage of the haplotig's nodes.
+    pub coverage: f64,
+    pub num_reads: usize,
+    //Fraction of the reads overlapping the haplotig which come from it, fitted by EM, and its 95%
+    //bootstrap confidence interval.
+    pub abundance: f64,
+    pub abundance_ci: (f64, f64),
+}
+
+//Settings of the exact path decomposition of the flow graph; see graph_processing::get_exact_paths.
+#[derive(Debug, Clone)]
+pub struct ExactPathsSettings {
+    //Seconds for all MIPs of a contig. The greedy paths are used if the MIPs take longer.
+    pub time_limit: f64,
+    //The weights of the paths through an edge must sum to within this fraction of its flow.
+    pub tolerance: f64,
+}
+
+impl Default for ExactPathsSettings {
+    fn default() -> ExactPathsSettings {
+        ExactPathsSettings {
+            time_limit: 60.0,
+            tolerance: 0.1,
+        }
+    }
+}
+
+//Which alignments are used for building fragments; see file_reader::alignment_passed_check. The
+//defaults are the cutoffs glopp has always used.
+#[derive(Debug, Clone)]
+pub struct AlignmentFilter {
+    pub min_mapq: u8,
+    //Only applied to supplementary alignments if filter_supplementary is true.
+    pub min_mapq_supplementary: u8,
+    pub use_supplementary: bool,
+    pub filter_supplementary: bool,
+    //Allele calls with a lower base quality are ignored.
+    pub min_base_qual: u8,
+    //All of these flags must be set.
+    pub include_flags: u16,
+    //None of these flags may be set.
+    pub exclude_flags: u16,
+    pub keep_duplicates: bool,
+    //Soft clipped bases over the read length.
+    pub max_soft_clip_frac: f64,
+    //Number of reference bases spanned by the alignment.
+    pub min_aligned_length: i64,
+}
+
+impl Default for AlignmentFilter {
+    fn default() -> AlignmentFilter {
+        AlignmentFilter {
+            min_mapq: 15,
+            min_mapq_supplementary: 59,
+            use_supplementary: false,
+            filter_supplementary: true,
+            min_base_qual: 0,
+            include_flags: 0,
+            //Unmapped, secondary and QC fail. Duplicates are handled by keep_duplicates.
+            exclude_flags: 772,
+            keep_duplicates: false,
+            max_soft_clip_frac: 1.0,
+            min_aligned_length: 0,
+        }
+    }
+}
+
+//How linked-read alignments are grouped into molecule fragments; see file_reader::get_molecule_id.
+//The default doesn't group alignments.
+#[derive(Debug, Clone)]
+pub struct MoleculeGrouping {
+    //Alignments with this tag (e.g. BX) are grouped by its value into molecule fragments.
+    pub barcode_tag: Option<String>,
+    //A barcode starts a new molecule after a gap of more than this many bases.
+    pub max_molecule_gap: i64,
+}
+
+impl Default for MoleculeGrouping {
+    fn default() -> MoleculeGrouping {
+        MoleculeGrouping {
+            barcode_tag: None,
+            max_molecule_gap: 50000,
+        }
+    }
+}
+
+//Which VCF records are used as phasable sites; see file_reader::site_filter_reason. Every scan of
+//the VCF must use the same filter so that SNP indices agree. The defaults use every site.
+#[derive(Debug, Clone)]
+pub struct SiteFilter {
+    pub use_indels: bool,
+    //Sample used for genotypes. Only needed for multi-sample VCFs.
+    pub sample: Option<String>,
+    //Skip sites where the sample is homozygous reference or has a missing genotype.
+    pub skip_hom_ref: bool,
+    //Only use sites with FILTER PASS (or missing).
+    pub pass_only: bool,
+    pub min_qual: f32,
+    //Bounds on INFO/DP.
+    pub min_depth: i32,
+    pub max_depth: i32,
+    //Minimum fraction of non-reference reads in the sample's FORMAT/AD.
+    pub min_allele_frac: f64,
+    //Skip sites overlapping these regions. Must be merged with merge_regions.
+    pub exclude_regions: Vec<GenomeRegion>,
+}
+
+impl Default for SiteFilter {
+    fn default() -> SiteFilter {
+        SiteFilter {
+            use_indels: false,
+            sample: None,
+            skip_hom_ref: false,
+            pass_only: false,
+            min_qual: 0.0,
+            min_depth: 0,
+            max_depth: i32::MAX,
+            min_allele_frac: 0.0,
+            exclude_regions: vec![],
+        }
+    }
+}
+
 #[derive(Debug, PartialEq,Eq)]
 pub struct HapBlock {
     pub blocks: Vec<FxHashMap<usize, FxHashMap<usize, usize>>>,
@@ -190,48 +629,39 @@ impl PartialOrd for HapBlock{
     }
 }
 
-pub fn build_frag(id: String, counter_id: usize, is_paired: bool) -> Frag {
+pub fn build_frag(id: String, counter_id: usize, is_paired: bool, source_file: usize) -> Frag {
     let toret = Frag {
         id: id,
         counter_id: counter_id,
-        seq_dict: FxHashMap::default(),
-        qual_dict: FxHashMap::default(),
-        positions: FxHashSet::default(),
+        snps: vec![],
+        alleles: vec![],
+        quals: vec![],
+        mates: vec![],
+        seq_offsets: vec![],
         first_position: usize::MAX,
         last_position: usize::MIN,
-        seq_string: vec![vec![];2],
+        seq_string: vec![PackedSeq::default(); 2],
         qual_string: vec![vec![];2],
         is_paired: is_paired,
-        snp_pos_to_seq_pos: FxHashMap::default(),
+        source_file: source_file,
+        library: 0,
+        epsilon: 0.04,
+        molecule_reads: vec![],
     };
 
     toret
 }
 #[inline]
-pub fn update_frag(frag: &mut Frag, geno: usize, snp_pos: usize, qual: u8, pair_number: u8, is_supp: bool,  record: &Record, qpos : usize) {
-    frag.seq_dict.insert(snp_pos, geno);
-    frag.qual_dict.insert(snp_pos, qual);
-    frag.positions.insert(snp_pos);
+pub fn update_frag(frag: &mut Frag, geno: usize, snp_pos: usize, qual: u8, pair_number: u8, is_supp: bool,  record: &Record, qpos : usize, store_read: bool) {
     let mut seq_pos = qpos;
     if is_supp{
         let clipping_offset = record.cigar().leading_hardclips();
         seq_pos = qpos + clipping_offset as usize;
     }
-    frag.snp_pos_to_seq_pos.insert(snp_pos, (pair_number, seq_pos as usize));
-    if !is_supp && frag.seq_string[pair_number as usize].len() == 0{
-        frag.seq_string[pair_number as usize] = record.seq().as_bytes();
-        if qual <= 255 - 33{
-        frag.qual_string[pair_number as usize] = record.qual().iter().map(|x| x + 33).collect();
-        }
-        else{
-            frag.qual_string[pair_number as usize] = record.qual().iter().map(|x| 255).collect();
-        }
-    }
-    if snp_pos < frag.first_position {
-        frag.first_position = snp_pos;
-    }
-    if snp_pos > frag.last_position {
-        frag.last_position = snp_pos
+    frag.add_call(snp_pos, geno, qual, pair_number, seq_pos);
+    if store_read && !is_supp && frag.seq_string[pair_number as usize].is_empty(){
+        frag.seq_string[pair_number as usize] = PackedSeq::from_encoded(record.seq().encoded, record.seq_len());
+        frag.qual_string[pair_number as usize] = record.qual().iter().map(|x| x.saturating_add(33)).collect();
     }
 }
 
@@ -274,10 +704,9 @@ if *pos < current_startpos {
         }
     }
 
-    for pos in frag.positions.iter() {
-        let var_at_pos = frag.seq_dict.get(pos).unwrap();
-        let sites = block_vec[part].entry(*pos).or_insert(FxHashMap::default());
-        let site_counter = sites.entry(*var_at_pos).or_insert(0);
+    for (pos, var_at_pos) in frag.calls() {
+        let sites = block_vec[part].entry(pos).or_insert(FxHashMap::default());
+        let site_counter = sites.entry(var_at_pos).or_insert(0);
         *site_counter += 1;
     }
 
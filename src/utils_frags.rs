@@ -11,8 +11,8 @@ pub fn distance(r1: &Frag, r2: &Frag) -> (i32, i32) {
     let mut diff = 0;
     let mut same = 0;
 
-    for pos in r1.positions.intersection(&r2.positions) {
-        if r1.seq_dict.get(pos) == r2.seq_dict.get(pos) {
+    for pos in r1.shared_positions(r2) {
+        if r1.get_allele(pos) == r2.get_allele(pos) {
             same += 1;
         } else {
             diff += 1;
@@ -22,14 +22,38 @@ pub fn distance(r1: &Frag, r2: &Frag) -> (i32, i32) {
     (same, diff)
 }
 
+//Weight of an allele call with Phred quality qual relative to a call with the global error
+//rate epsilon. This is the ratio of the log-likelihood ratios (match vs mismatch) of the two
+//calls, capped at 1 so that high quality calls count the same as before.
+pub fn qual_weight(qual: u8, epsilon: f64) -> f64 {
+    let err = f64::powf(10.0, -(qual as f64) / 10.0).min(0.5);
+    let llr_qual = ((1.0 - err) / err).ln();
+    let llr_eps = ((1.0 - epsilon) / epsilon).ln();
+    if llr_eps <= 0.0 {
+        return 1.0;
+    }
+    (llr_qual / llr_eps).min(1.0)
+}
+
+fn allele_weight(qual: u8, epsilon: f64, use_qual: bool) -> f64 {
+    if !use_qual {
+        return 1.0;
+    }
+    qual_weight(qual, epsilon)
+}
+
+//If use_qual is true, each allele counts as a fraction of a base according to its
+//Phred quality; see qual_weight.
 pub fn distance_read_haplo_epsilon_empty(
     r: &Frag,
     hap: &FxHashMap<usize, FxHashMap<usize, usize>>,
     epsilon: f64,
+    use_qual: bool,
 ) -> (f64, f64) {
     let mut diff = 0.0;
     let mut same = 0.0;
-    for pos in r.positions.iter() {
+    for (i, snp) in r.snps.iter().enumerate() {
+        let pos = &(*snp as usize);
         if !hap.contains_key(pos) {
             diff += epsilon;
             //TODO remove this just a test
@@ -39,25 +63,26 @@ pub fn distance_read_haplo_epsilon_empty(
             continue;
         }
 
-        let frag_var = r.seq_dict.get(pos).unwrap();
+        let weight = allele_weight(r.quals[i], epsilon, use_qual);
+        let frag_var = &(r.alleles[i] as usize);
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
             .unwrap()
             .0;
         if *frag_var == *consensus_var {
-            same += 1.0;
+            same += weight;
         } else {
             let frag_var_count = hap.get(pos).unwrap().get(frag_var);
             if let Some(count) = frag_var_count {
                 if count == hap.get(pos).unwrap().get(consensus_var).unwrap() {
-                    same += 1.0;
+                    same += weight;
                     continue;
                 }
             }
-            diff += 1.0;
+            diff += weight;
         }
     }
 
@@ -70,36 +95,32 @@ pub fn chunk_vec_update(
     chunk_vec: &mut Vec<usize>)
 {
     let n = 10;
-    let mut sorted_pos :Vec<&usize> = frag.positions.iter().collect();
-    sorted_pos.sort();
-
     let mut same = 0;
     let mut diff = 0;
 
-    for pos in sorted_pos {
+    for (pos, frag_var) in frag.calls() {
         if same+diff == n{
             chunk_vec.push(diff);
             same = 0;
             diff = 0;
         }
-        if !hap.contains_key(pos) {
+        if !hap.contains_key(&pos) {
             continue;
         }
 
-        let frag_var = frag.seq_dict.get(pos).unwrap();
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
             .unwrap()
             .0;
-        if *frag_var == *consensus_var {
+        if frag_var == *consensus_var {
             same += 1;
         } else {
-            let frag_var_count = hap.get(pos).unwrap().get(frag_var);
+            let frag_var_count = hap.get(&pos).unwrap().get(&frag_var);
             if let Some(count) = frag_var_count {
-                if count == hap.get(pos).unwrap().get(consensus_var).unwrap() {
+                if count == hap.get(&pos).unwrap().get(consensus_var).unwrap() {
                     same += 1;
                     continue;
                 }
@@ -115,30 +136,69 @@ pub fn distance_read_haplo(
 ) -> (usize, usize) {
     let mut diff = 0;
     let mut same = 0;
-    for pos in r1.positions.iter() {
+    for (pos, frag_var) in r1.calls() {
+        if !hap.contains_key(&pos) {
+            continue;
+        }
+
+        let consensus_var = hap
+            .get(&pos)
+            .unwrap()
+            .iter()
+            .max_by_key(|entry| entry.1)
+            .unwrap()
+            .0;
+        if frag_var == *consensus_var {
+            same += 1;
+        } else {
+            let frag_var_count = hap.get(&pos).unwrap().get(&frag_var);
+            if let Some(count) = frag_var_count {
+                if count == hap.get(&pos).unwrap().get(consensus_var).unwrap() {
+                    same += 1;
+                    continue;
+                }
+            }
+            diff += 1;
+        }
+    }
+
+    (same, diff)
+}
+
+//Same as distance_read_haplo but with quality weighted alleles.
+pub fn distance_read_haplo_qual(
+    r1: &Frag,
+    hap: &FxHashMap<usize, FxHashMap<usize, usize>>,
+    epsilon: f64,
+) -> (f64, f64) {
+    let mut diff = 0.0;
+    let mut same = 0.0;
+    for (i, snp) in r1.snps.iter().enumerate() {
+        let pos = &(*snp as usize);
         if !hap.contains_key(pos) {
             continue;
         }
 
-        let frag_var = r1.seq_dict.get(pos).unwrap();
+        let weight = allele_weight(r1.quals[i], epsilon, true);
+        let frag_var = &(r1.alleles[i] as usize);
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
             .unwrap()
             .0;
         if *frag_var == *consensus_var {
-            same += 1;
+            same += weight;
         } else {
             let frag_var_count = hap.get(pos).unwrap().get(frag_var);
             if let Some(count) = frag_var_count {
                 if count == hap.get(pos).unwrap().get(consensus_var).unwrap() {
-                    same += 1;
+                    same += weight;
                     continue;
                 }
             }
-            diff += 1;
+            diff += weight;
         }
     }
 
@@ -154,14 +214,13 @@ pub fn distance_read_haplo_ref_wild(
     let mut diff_alt = 0;
     let mut same_alt = 0;
     let mut is_ref_allele = true;
-    for pos in r1.positions.iter() {
-        if !hap.contains_key(pos) {
+    for (pos, frag_var) in r1.calls() {
+        if !hap.contains_key(&pos) {
             continue;
         }
 
-        let frag_var = r1.seq_dict.get(pos).unwrap();
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
@@ -170,16 +229,16 @@ pub fn distance_read_haplo_ref_wild(
         if *consensus_var != 0 {
             is_ref_allele = false;
         }
-        if *frag_var == *consensus_var {
+        if frag_var == *consensus_var {
             if is_ref_allele {
                 same_ref += 1;
             } else {
                 same_alt += 1;
             }
         } else {
-            let frag_var_count = hap.get(pos).unwrap().get(frag_var);
+            let frag_var_count = hap.get(&pos).unwrap().get(&frag_var);
             if let Some(count) = frag_var_count {
-                if count == hap.get(pos).unwrap().get(consensus_var).unwrap() {
+                if count == hap.get(&pos).unwrap().get(consensus_var).unwrap() {
                     if is_ref_allele {
                         same_ref += 1;
                     } else {
@@ -207,20 +266,19 @@ pub fn distance_read_haplo_range(
 ) -> (usize, usize) {
     let mut diff = 0;
     let mut same = 0;
-    for pos in r1.positions.iter() {
-        if !hap.contains_key(pos) || *pos > end || *pos < start {
+    for (pos, frag_var) in r1.calls() {
+        if !hap.contains_key(&pos) || pos > end || pos < start {
             continue;
         }
 
-        let frag_var = r1.seq_dict.get(pos).unwrap();
         let consensus_var = hap
-            .get(pos)
+            .get(&pos)
             .unwrap()
             .iter()
             .max_by_key(|entry| entry.1)
             .unwrap()
             .0;
-        if *frag_var == *consensus_var {
+        if frag_var == *consensus_var {
             same += 1;
         } else {
             diff += 1;
@@ -234,8 +292,8 @@ pub fn distance_read_haplo_range(
 pub fn get_all_overlaps(frags: &Vec<Frag>) -> FxHashMap<usize, FxHashSet<&Frag>> {
     let mut overlaps = FxHashMap::default();
     for frag in frags.iter() {
-        for pos in frag.positions.iter() {
-            let pos_set = overlaps.entry(*pos).or_insert(FxHashSet::default());
+        for pos in frag.positions() {
+            let pos_set = overlaps.entry(pos).or_insert(FxHashSet::default());
             pos_set.insert(frag);
         }
     }
@@ -272,7 +330,7 @@ pub fn check_overlap(r1: &Frag, r2: &Frag) -> bool {
     if r2.last_position < r1.first_position {
         return false;
     }
-    let t: Vec<_> = r1.positions.intersection(&r2.positions).collect();
+    let t = r1.shared_positions(r2);
     if t.len() == 0 {
         return false;
     } else {
@@ -283,10 +341,9 @@ pub fn check_overlap(r1: &Frag, r2: &Frag) -> bool {
 pub fn set_to_seq_dict(frag_set: &FxHashSet<&Frag>) -> FxHashMap<usize, FxHashMap<usize,usize>> {
     let mut hap_map = FxHashMap::default();
     for frag in frag_set.iter() {
-        for pos in frag.positions.iter() {
-            let var_at_pos = frag.seq_dict.get(pos).unwrap();
-            let sites = hap_map.entry(*pos).or_insert(FxHashMap::default());
-            let site_counter = sites.entry(*var_at_pos).or_insert(0);
+        for (pos, var_at_pos) in frag.calls() {
+            let sites = hap_map.entry(pos).or_insert(FxHashMap::default());
+            let site_counter = sites.entry(var_at_pos).or_insert(0);
             *site_counter += 1;
         }
     }
@@ -311,6 +368,44 @@ pub fn get_avg_length(all_frags: &Vec<Frag>, quantile: f64) -> usize {
     return length_vec[(length_vec.len() as f64 * quantile) as usize];
 }
 
+//Set the library and error rate of each fragment. error_rates has either one error rate for all
+//fragments, or one error rate per input BAM file, in which case each file is its own library (e.g.
+//ONT and Illumina reads).
+pub fn set_library_error_rates(all_frags: &mut Vec<Frag>, error_rates: &Vec<f64>) {
+    for frag in all_frags.iter_mut() {
+        if error_rates.len() == 1 {
+            frag.library = 0;
+        } else {
+            frag.library = frag.source_file;
+        }
+        frag.epsilon = error_rates[frag.library];
+    }
+}
+
+//Error rate of a set of fragments, i.e. the mean of the fragments' error rates weighted by their
+//number of alleles. This is the expected fraction of allele call errors when libraries are mixed.
+pub fn mean_error_rate<'a, I>(frags: I) -> f64
+where
+    I: IntoIterator<Item = &'a Frag>,
+{
+    let mut num_alleles = 0.0;
+    let mut num_errors = 0.0;
+    let mut error_rates = FxHashSet::default();
+    for frag in frags {
+        num_alleles += frag.num_calls() as f64;
+        num_errors += frag.num_calls() as f64 * frag.epsilon;
+        error_rates.insert(frag.epsilon.to_bits());
+    }
+    //Avoid rounding errors for a single library.
+    if error_rates.len() == 1 {
+        return f64::from_bits(*error_rates.iter().next().unwrap());
+    }
+    if num_alleles == 0.0 {
+        return 0.0;
+    }
+    num_errors / num_alleles
+}
+
 pub fn get_length_gn(all_frags: &Vec<Frag>) -> usize {
     let mut last_pos = 0;
     for frag in all_frags.iter() {
@@ -328,15 +423,17 @@ pub fn get_length_gn(all_frags: &Vec<Frag>) -> usize {
 //
 //I'm currently using this implementation. We can still mess around with using different approximations.
 pub fn stable_binom_cdf_p_rev(n: usize, k: usize, p: f64, div_factor: f64) -> f64 {
-    if n == 0 {
+    stable_binom_cdf_p_rev_f64(n as f64, k as f64, p, div_factor)
+}
+
+//Same bound but for fractional counts, e.g. quality weighted alleles.
+pub fn stable_binom_cdf_p_rev_f64(n64: f64, k64: f64, p: f64, div_factor: f64) -> f64 {
+    if n64 == 0.0 {
         return 0.0;
     }
 
     //    return norm_approx(n,k,p,div_factor);
 
-    let n64 = n as f64;
-    let k64 = k as f64;
-
     //In this case, the relative entropy is bigger than the minimum of 0 which we don't want.
     let mut a = k64 / n64;
 
@@ -470,17 +567,15 @@ pub fn err_correlations(
     seq_err_corr_map: &mut FxHashMap<usize, FxHashMap<(usize, usize), usize>>,
     gap: usize,
 ) {
-    for pos in r1.positions.iter() {
-        if !hap.contains_key(pos) {
+    for (pos, current_var) in r1.calls() {
+        if !hap.contains_key(&pos) {
             continue;
         }
-        let current_var = *r1.seq_dict.get(pos).unwrap();
 
-        if r1.seq_dict.contains_key(&(*pos + gap)) {
-            let next_var = *r1.seq_dict.get(&(*pos + gap)).unwrap();
+        if let Some(next_var) = r1.get_allele(pos + gap) {
             //last_pos-1 because positions are 1-indexed
             let index = seq_err_corr_map
-                .entry(*pos - 1)
+                .entry(pos - 1)
                 .or_insert(FxHashMap::default());
             let count = index.entry((current_var, next_var)).or_insert(0);
             *count += 1;
@@ -555,23 +650,21 @@ pub fn get_range_with_lengths(
 }
 
 pub fn add_read_to_block(block: &mut HapBlock, frag: &Frag, part: usize) {
-    for pos in frag.positions.iter() {
-        let var_at_pos = frag.seq_dict.get(pos).unwrap();
+    for (pos, var_at_pos) in frag.calls() {
         let sites = block.blocks[part]
-            .entry(*pos)
+            .entry(pos)
             .or_insert(FxHashMap::default());
-        let site_counter = sites.entry(*var_at_pos).or_insert(0);
+        let site_counter = sites.entry(var_at_pos).or_insert(0);
         *site_counter += 1;
     }
 }
 
 pub fn remove_read_from_block(block: &mut HapBlock, frag: &Frag, part: usize) {
-    for pos in frag.positions.iter() {
-        let var_at_pos = frag.seq_dict.get(pos).unwrap();
+    for (pos, var_at_pos) in frag.calls() {
         let sites = block.blocks[part]
-            .entry(*pos)
+            .entry(pos)
             .or_insert(FxHashMap::default());
-        let site_counter = sites.entry(*var_at_pos).or_insert(0);
+        let site_counter = sites.entry(var_at_pos).or_insert(0);
         *site_counter -= 1;
     }
 }
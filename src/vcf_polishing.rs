@@ -1,7 +1,7 @@
 use crate::local_clustering;
 use std::io::LineWriter;
 use std::cell::RefCell;
-use crate::types_structs::Frag;
+use crate::types_structs::{Frag, FragIndex};
 use crate::types_structs::HapBlock;
 use crate::utils_frags;
 use fxhash::{FxHashMap, FxHashSet};
@@ -273,10 +273,9 @@ fn get_mec_positions_hap(new_reads : &FxHashSet<&Frag>, hap_block : &mut HapBloc
 
     //Add reads to hap_block
     for read in new_reads.iter(){
-        for pos in read.positions.iter(){
-            let var_at_pos = read.seq_dict.get(pos).unwrap();
-            let sites = hap.entry(*pos).or_insert(FxHashMap::default());
-            let site_counter = sites.entry(*var_at_pos).or_insert(0);
+        for (pos, var_at_pos) in read.calls(){
+            let sites = hap.entry(pos).or_insert(FxHashMap::default());
+            let site_counter = sites.entry(var_at_pos).or_insert(0);
             *site_counter += 1;
         }
     }
@@ -300,10 +299,9 @@ fn get_mec_positions_hap(new_reads : &FxHashSet<&Frag>, hap_block : &mut HapBloc
 
     //Remove reads from hap_block
     for read in new_reads.iter(){
-        for pos in read.positions.iter(){
-            let var_at_pos = read.seq_dict.get(pos).unwrap();
-            let sites = hap.entry(*pos).or_insert(FxHashMap::default());
-            let site_counter = sites.entry(*var_at_pos).or_insert(0);
+        for (pos, var_at_pos) in read.calls(){
+            let sites = hap.entry(pos).or_insert(FxHashMap::default());
+            let site_counter = sites.entry(var_at_pos).or_insert(0);
             *site_counter -= 1;
         }
     }
@@ -377,6 +375,7 @@ pub fn replace_with_filled_blocks<'a>(
 ) -> Vec<Vec<FxHashSet<&'a Frag>>> {
     let mut corrected_vec = Vec::new();
     let outlier_score = get_iqr(all_scores, factor);
+    let frag_index = FragIndex::new(all_frags);
 
     //Assume the leftmost block is good. Not a great assumption but
     //otherwise the algorithm would be a bit more painful.
@@ -399,6 +398,7 @@ pub fn replace_with_filled_blocks<'a>(
             i * length_of_block,
             (i + 1) * length_of_block,
             all_frags,
+            &frag_index,
             usize::MAX
         )
         .into_iter()
@@ -0,0 +1,8 @@
+//Helpers shared by the test files.
+
+//Directory for the output files of a test, created under the system temp dir.
+pub fn test_out_dir(name: &str) -> String {
+    let out_dir = std::env::temp_dir().join(name);
+    std::fs::create_dir_all(&out_dir).unwrap();
+    out_dir.to_str().unwrap().to_string()
+}
@@ -1,10 +1,15 @@
 use std::time::Instant;
 use flopp::file_reader;
+use flopp::frag_cache;
 use flopp::local_clustering;
 use flopp::utils_frags;
+use flopp::types_structs::{build_frag, FragIndex, PackedSeq, SiteFilter};
 use fxhash::{FxHashSet,FxHashMap};
 use std::collections::{HashSet,BTreeMap};
 
+mod common;
+use common::test_out_dir;
+
 #[test]
 fn frag_reader_test() {
     let flopp_dir = "/home/jshaw/practical_prob_2020_paper/flopp/";
@@ -13,9 +18,9 @@ fn frag_reader_test() {
     assert_eq!(frags.len(),3);
     assert_eq!(frags[0].id,"t1");
     assert_eq!(frags[0].counter_id,0);
-    assert_eq!(frags[0].positions.len(),2);
+    assert_eq!(frags[0].num_calls(),2);
     assert_eq!(frags[2].id,"t3");
-    assert_eq!(frags[2].positions.len(),4);
+    assert_eq!(frags[2].num_calls(),4);
 }
 
 #[test]
@@ -55,11 +60,130 @@ fn local_cluster_test(){
     let frags_map = file_reader::get_frags_container(flopp_dir.to_owned() + "/tests/test_file.txt");
     let frags = frags_map.get("frag_contig").unwrap();
     let indexed_reads = utils_frags::get_all_overlaps(frags);
-    let interval_reads_all  = local_clustering::find_reads_in_interval(1,100,frags, usize::MAX);
-    let interval_reads_5= local_clustering::find_reads_in_interval(5,6,frags, usize::MAX);
+    let frag_index = FragIndex::new(frags);
+    let interval_reads_all  = local_clustering::find_reads_in_interval(1,100,frags, &frag_index, usize::MAX);
+    let interval_reads_5= local_clustering::find_reads_in_interval(5,6,frags, &frag_index, usize::MAX);
 
     assert_eq!(interval_reads_all.len(),3);
     assert_eq!(interval_reads_5.len(),1);
 
 }
 
+
+#[test]
+fn glopp_frag_reader_test() {
+    let frag_file = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/test_file_glopp.txt");
+    let frags_map = file_reader::get_frags_container(frag_file);
+    let frags = frags_map.get("c1").unwrap();
+    assert_eq!(frags.len(), 2);
+    assert_eq!(frags[0].id, "r1");
+    assert_eq!(frags[0].get_allele(2), Some(12));
+    assert_eq!(frags[0].get_qual(2), Some(30));
+    assert_eq!(frags[1].source_file, 1);
+    assert!(frags[1].is_paired);
+    assert_eq!(frags[1].first_position, 2);
+    assert_eq!(frags[1].last_position, 4);
+    assert_eq!(frags[1].get_seq_pos(4).map(|x| x.0), Some(1));
+    let molecules = frags_map.get("c2").unwrap();
+    assert_eq!(molecules[0].molecule_reads, vec!["a", "b"]);
+
+    let snp_to_genome_pos = file_reader::get_snp_to_genome_pos_from_frags_file(frag_file);
+    assert_eq!(snp_to_genome_pos["c1"], vec![100, 200, 300, 400]);
+    assert!(!snp_to_genome_pos.contains_key("c2"));
+}
+
+#[test]
+fn vcf_record_indices_test() {
+    //The indel at c1:20 is filtered but still counts for HapCUT2's variant numbering.
+    let vcf_file = test_out_dir("vcf_record_indices_test") + "/var.vcf";
+    std::fs::write(
+        &vcf_file,
+        "##fileformat=VCFv4.2\n##contig=<ID=c1>\n##contig=<ID=c2>\n\
+         ##FORMAT=<ID=GT,Number=1,Type=String,Description=\"Genotype\">\n\
+         #CHROM\tPOS\tID\tREF\tALT\tQUAL\tFILTER\tINFO\tFORMAT\tS1\n\
+         c1\t10\t.\tA\tC\t50\tPASS\t.\tGT\t0/1\n\
+         c1\t20\t.\tAC\tA\t50\tPASS\t.\tGT\t0/1\n\
+         c1\t30\t.\tG\tT\t50\tPASS\t.\tGT\t0/1\n\
+         c2\t5\t.\tT\tA\t50\tPASS\t.\tGT\t0/1\n",
+    )
+    .unwrap();
+    let site_filter = SiteFilter::default();
+    let (snp_to_genome_pos_map, _, _) =
+        file_reader::get_genotypes_from_vcf_hts(&vcf_file, &vec![], &site_filter);
+    assert_eq!(snp_to_genome_pos_map["c1"], vec![10, 30]);
+    let record_indices =
+        file_reader::get_vcf_record_indices(&vcf_file, &snp_to_genome_pos_map, &site_filter);
+    assert_eq!(record_indices["c1"], vec![1, 3]);
+    assert_eq!(record_indices["c2"], vec![4]);
+}
+
+#[test]
+fn frag_cache_test() {
+    let mut frag = build_frag(String::from("r1"), 0, true, 1);
+    frag.add_call(3, 12, 30, 0, 5);
+    frag.add_call(4, 0, 20, 1, 7);
+    let mut molecule = build_frag(String::from("BC_mol0"), 1, false, 0);
+    molecule.add_call(1, 1, 40, 0, 0);
+    molecule.molecule_reads = vec![String::from("a"), String::from("b")];
+    let mut frags_map = FxHashMap::default();
+    frags_map.insert(String::from("c1"), vec![frag]);
+    frags_map.insert(String::from("c2"), vec![molecule]);
+
+    let cache_file = test_out_dir("frag_cache_test") + "/frags.bin";
+    frag_cache::write_frag_cache(&frags_map, "key1", &cache_file);
+    assert!(!std::path::Path::new(&(cache_file.clone() + ".tmp")).exists());
+    let cached_frags_map = frag_cache::read_frag_cache("key1", &cache_file).unwrap();
+    assert_eq!(cached_frags_map.len(), 2);
+    for (contig, frags) in frags_map.iter() {
+        assert_eq!(format!("{:?}", frags), format!("{:?}", cached_frags_map[contig]));
+    }
+    assert!(frag_cache::read_frag_cache("key2", &cache_file).is_none());
+}
+
+#[test]
+fn frag_layout_test() {
+    let mut frag = build_frag(String::from("r1"), 0, true, 0);
+    frag.add_call(5, 1, 30, 0, 50);
+    frag.add_call(9, 0, 20, 1, 10);
+    frag.add_call(2, 2, 40, 0, 20);
+    frag.add_call(5, 0, 35, 0, 51);
+    assert_eq!(frag.num_calls(), 3);
+    assert_eq!(frag.calls().collect::<Vec<_>>(), vec![(2, 2), (5, 0), (9, 0)]);
+    assert_eq!(frag.get_qual(5), Some(35));
+    assert_eq!(frag.get_seq_pos(9), Some((1, 10)));
+    assert_eq!(frag.get_allele(3), None);
+    assert_eq!(frag.first_position, 2);
+    assert_eq!(frag.last_position, 9);
+
+    let mut other = build_frag(String::from("r2"), 1, false, 0);
+    other.add_call(5, 0, 30, 0, 0);
+    other.add_call(9, 1, 30, 0, 0);
+    assert_eq!(frag.shared_positions(&other), vec![5, 9]);
+    assert_eq!(utils_frags::distance(&frag, &other), (1, 1));
+
+    let seq = PackedSeq::from_bytes(b"ACGTNacgtA");
+    assert_eq!(seq.len(), 10);
+    assert_eq!(seq.to_bytes(), b"ACGTNACGTA".to_vec());
+    assert_eq!(seq.slice(3, 6), b"TNA".to_vec());
+}
+
+#[test]
+fn frag_index_test() {
+    let mut frags = vec![];
+    for i in 0..500 {
+        let mut frag = build_frag(format!("r{}", i), i, false, 0);
+        let first = 1 + (i * 37) % 400;
+        frag.add_call(first, 0, 30, 0, 0);
+        frag.add_call(first + (i * 13) % 60, 1, 30, 0, 0);
+        frags.push(frag);
+    }
+    frags.sort_by_key(|frag| frag.first_position);
+    let frag_index = FragIndex::new(&frags);
+    for (start, end) in [(1, 1), (50, 80), (0, 1000), (399, 470), (460, 500), (30, 20)] {
+        let expected: Vec<usize> = (0..frags.len())
+            .filter(|i| frags[*i].first_position <= end && frags[*i].last_position >= start)
+            .collect();
+        assert_eq!(frag_index.query(start, end), expected);
+    }
+    assert!(FragIndex::new(&vec![]).query(1, 10).is_empty());
+}
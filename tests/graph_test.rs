@@ -0,0 +1,311 @@
+use flopp::abundance::{self, AbundanceSettings};
+use flopp::flow_solver;
+use flopp::graph_processing;
+use flopp::types_structs::{build_frag, ExactPathsSettings, Frag, HapNode};
+use fxhash::FxHashSet;
+use std::ops::Range;
+
+mod common;
+use common::test_out_dir;
+
+//Haplotype graph where block i has a node for each range of columns[i], made of those fragments.
+//The nodes of block i cover SNPs 2i+1 and 2i+2. Edges are left to the test.
+fn build_hap_graph(frags: &Vec<Frag>, columns: Vec<Vec<Range<usize>>>) -> Vec<Vec<HapNode<'_>>> {
+    let mut hap_graph = vec![];
+    let mut id = 0;
+    for (column, rows) in columns.into_iter().enumerate() {
+        let mut block = vec![];
+        for (row, range) in rows.into_iter().enumerate() {
+            let frag_set: FxHashSet<&Frag> = frags[range].iter().collect();
+            let mut node = HapNode::new(frag_set, (2 * column + 1, 2 * column + 2));
+            node.column = column;
+            node.row = row;
+            node.id = id;
+            id += 1;
+            block.push(node);
+        }
+        hap_graph.push(block);
+    }
+    hap_graph
+}
+
+fn collapsed_frags() -> Vec<Frag> {
+    let mut frags = vec![];
+    for i in 0..60 {
+        let mut frag = build_frag(format!("r{}", i), i, false, 0);
+        let column = if i < 20 { 0 } else if i < 40 { 1 } else { 2 };
+        let allele = if column == 1 { 0 } else { (i / 10) % 2 };
+        frag.add_call(2 * column + 1, allele, 30, 0, 0);
+        frag.add_call(2 * column + 2, allele, 30, 0, 0);
+        frag.epsilon = 0.04;
+        frags.push(frag);
+    }
+    frags
+}
+
+//Column 0 has two nodes that collapse into the single node of column 1, which splits again into
+//the two nodes of column 2.
+fn collapsed_hap_graph(frags: &Vec<Frag>) -> Vec<Vec<HapNode<'_>>> {
+    build_hap_graph(frags, vec![vec![0..10, 10..20], vec![20..40], vec![40..50, 50..60]])
+}
+
+fn collapsed_flows() -> Vec<((usize, usize), (usize, usize), f64)> {
+    vec![
+        ((0, 0), (1, 0), 20.),
+        ((0, 1), (1, 0), 20.),
+        ((1, 0), (2, 0), 20.),
+        ((1, 0), (2, 1), 20.),
+    ]
+}
+
+#[test]
+fn shared_node_paths_test() {
+    let frags = collapsed_frags();
+    let flows = collapsed_flows();
+    let out_dir = test_out_dir("glopp_shared_node_paths_test");
+
+    let mut hap_graph = collapsed_hap_graph(&frags);
+    let (haplotigs, _) = graph_processing::get_disjoint_paths_rewrite(
+        &mut hap_graph,
+        flows.clone(),
+        out_dir.clone(),
+        "test",
+        &vec![],
+        false,
+        false,
+        None,
+        graph_processing::get_flow_cutoff(false),
+        &AbundanceSettings::default(),
+    );
+    assert_eq!(haplotigs.len(), 3);
+
+    let mut hap_graph = collapsed_hap_graph(&frags);
+    let (haplotigs, snp_endpoints) = graph_processing::get_disjoint_paths_rewrite(
+        &mut hap_graph,
+        flows,
+        out_dir,
+        "test",
+        &vec![],
+        false,
+        true,
+        None,
+        graph_processing::get_flow_cutoff(false),
+        &AbundanceSettings::default(),
+    );
+    assert_eq!(haplotigs.len(), 2);
+    assert_eq!(snp_endpoints, vec![(1, 6), (1, 6)]);
+    //Each read of the shared node is in one haplotig.
+    for frag in frags[20..40].iter() {
+        assert_eq!(haplotigs.iter().filter(|part| part.contains(frag)).count(), 1);
+    }
+    assert!(haplotigs.iter().all(|part| part.len() > 20));
+}
+
+#[test]
+fn exact_paths_test() {
+    let frags = collapsed_frags();
+    let out_dir = test_out_dir("glopp_exact_paths_test");
+    let settings = ExactPathsSettings::default();
+    let mut hap_graph = collapsed_hap_graph(&frags);
+    let (haplotigs, snp_endpoints) = graph_processing::get_disjoint_paths_rewrite(
+        &mut hap_graph,
+        collapsed_flows(),
+        out_dir,
+        "test",
+        &vec![],
+        false,
+        false,
+        Some(&settings),
+        graph_processing::get_flow_cutoff(false),
+        &AbundanceSettings::default(),
+    );
+    assert_eq!(haplotigs.len(), 2);
+    assert_eq!(snp_endpoints, vec![(1, 6), (1, 6)]);
+    let reads: usize = haplotigs.iter().map(|part| part.len()).sum();
+    assert_eq!(reads, frags.len());
+}
+
+#[test]
+fn flow_solver_test() {
+    //A -> B0 -> C and A -> B1 -> C, where the read counts into and out of B0 and B1 don't agree.
+    let frags = vec![];
+    let mut hap_graph = build_hap_graph(&frags, vec![vec![0..0], vec![0..0, 0..0], vec![0..0]]);
+    hap_graph[0][0].out_edges = vec![(0, 10.), (1, 20.)];
+    hap_graph[1][0].in_edges = vec![(0, 10.)];
+    hap_graph[1][1].in_edges = vec![(0, 20.)];
+    hap_graph[1][0].out_edges = vec![(0, 14.)];
+    hap_graph[1][1].out_edges = vec![(0, 18.)];
+    hap_graph[2][0].in_edges = vec![(0, 14.), (1, 18.)];
+    let weights = vec![10., 20., 14., 18.];
+
+    for name in flow_solver::FLOW_SOLVERS.iter() {
+        let flows = flow_solver::get_flow_solver(name).solve(&hap_graph);
+        let flows: Vec<f64> = flows.iter().map(|x| x.2).collect();
+        assert_eq!(flows.len(), 4);
+        assert!((flows[0] - flows[2]).abs() < 1e-3, "{} {:?}", name, flows);
+        assert!((flows[1] - flows[3]).abs() < 1e-3, "{} {:?}", name, flows);
+        let l1_error: f64 = flows.iter().zip(weights.iter()).map(|(x, w)| (x - w).abs()).sum();
+        match *name {
+            "l1" | "min-cost-flow" => assert!((l1_error - 6.).abs() < 1e-6, "{} {}", name, l1_error),
+            "l2" => assert!(l1_error >= 6. - 1e-3, "{} {}", name, l1_error),
+            _ => unreachable!(),
+        }
+    }
+}
+
+//Strain A with 20 reads and strain B with 3 reads over two blocks (SNPs 1-2 and 3-4). The last read
+//of A has the alleles of B at SNPs 2 and 3, so it matches A and B equally well.
+fn low_coverage_frags() -> Vec<Frag> {
+    let mut frags = vec![];
+    for i in 0..23 {
+        let mut frag = build_frag(format!("r{}", i), i, false, 0);
+        let allele = if i < 20 { 0 } else { 1 };
+        for pos in 1..5 {
+            let call = if i == 19 && (pos == 2 || pos == 3) { 1 } else { allele };
+            frag.add_call(pos, call, 30, 0, 0);
+        }
+        frag.epsilon = 0.04;
+        frags.push(frag);
+    }
+    frags
+}
+
+fn low_coverage_hap_graph(frags: &Vec<Frag>) -> Vec<Vec<HapNode<'_>>> {
+    build_hap_graph(frags, vec![vec![0..20, 20..23], vec![0..20, 20..23]])
+}
+
+#[test]
+fn edge_posteriors_test() {
+    let frags = low_coverage_frags();
+
+    let mut hap_graph = low_coverage_hap_graph(&frags);
+    graph_processing::update_hap_graph(&mut hap_graph, false, false);
+    assert_eq!(hap_graph[0][0].out_edges, vec![(0, 19.)]);
+    assert!(hap_graph[0][1].out_edges.is_empty());
+    assert_eq!(hap_graph[0][0].out_edge_variances, vec![0.]);
+
+    let mut hap_graph = low_coverage_hap_graph(&frags);
+    graph_processing::update_hap_graph(&mut hap_graph, false, true);
+    assert_eq!(hap_graph[0][0].out_edges.len(), 1);
+    assert_eq!(hap_graph[0][0].out_edges[0].0, 0);
+    assert!((hap_graph[0][0].out_edges[0].1 - 19.5).abs() < 0.1);
+    assert!(hap_graph[0][0].out_edge_variances[0] > 0.25);
+    assert!(hap_graph[0][0].out_edge_variances[0] < 0.3);
+    //The low-coverage strain keeps its edge.
+    assert_eq!(hap_graph[0][1].out_edges.len(), 1);
+    assert_eq!(hap_graph[0][1].out_edges[0].0, 1);
+    assert!((hap_graph[0][1].out_edges[0].1 - 3.).abs() < 0.1);
+    assert_eq!(hap_graph[1][1].in_edges.len(), 1);
+}
+
+#[test]
+fn low_coverage_strain_paths_test() {
+    //Strain A with 20 reads and strain B with 2 reads over three blocks.
+    let mut frags = vec![];
+    for i in 0..22 {
+        let mut frag = build_frag(format!("r{}", i), i, false, 0);
+        for pos in 1..7 {
+            frag.add_call(pos, if i < 20 { 0 } else { 1 }, 30, 0, 0);
+        }
+        frag.epsilon = 0.04;
+        frags.push(frag);
+    }
+    let out_dir = test_out_dir("glopp_low_coverage_strain_paths_test");
+    let flow_solver = flow_solver::get_flow_solver("l1");
+    let flow_cutoff = graph_processing::get_flow_cutoff(true);
+
+    let mut hap_graph = build_hap_graph(&frags, vec![vec![0..20, 20..22]; 3]);
+    graph_processing::update_hap_graph(&mut hap_graph, false, true);
+    let flows = graph_processing::solve_flow_graph(
+        &hap_graph,
+        flow_solver.as_ref(),
+        out_dir.clone(),
+        flow_cutoff,
+    );
+    let (haplotigs, snp_endpoints) = graph_processing::get_disjoint_paths_rewrite(
+        &mut hap_graph,
+        flows,
+        out_dir,
+        "test",
+        &vec![],
+        false,
+        false,
+        None,
+        flow_cutoff,
+        &AbundanceSettings::default(),
+    );
+    assert_eq!(haplotigs.len(), 2);
+    assert_eq!(snp_endpoints, vec![(1, 6), (1, 6)]);
+    let strain_b: FxHashSet<&Frag> = frags[20..22].iter().collect();
+    assert!(haplotigs.contains(&strain_b));
+}
+
+#[test]
+fn haplotig_abundance_test() {
+    let frags = low_coverage_frags();
+    let haplotigs: Vec<FxHashSet<&Frag>> = vec![
+        frags[0..20].iter().collect(),
+        frags[20..23].iter().collect(),
+    ];
+    let snp_endpoints = vec![(1, 4), (1, 4)];
+    let path_flows = vec![vec![10., 20.], vec![3.]];
+    let path_coverages = vec![vec![20., 20.], vec![3., 3.]];
+    let abundances = abundance::get_haplotig_abundances(
+        &haplotigs,
+        &snp_endpoints,
+        &path_flows,
+        &path_coverages,
+        false,
+        &AbundanceSettings::default(),
+    );
+    assert_eq!(abundances.len(), 2);
+    assert!((abundances[0].mean_flow - 15.).abs() < 1e-9);
+    assert!((abundances[0].flow_variance - 25.).abs() < 1e-9);
+    assert!((abundances[1].mean_flow - 3.).abs() < 1e-9);
+    assert_eq!(abundances[1].flow_variance, 0.);
+    assert!((abundances[0].coverage - 20.).abs() < 1e-9);
+    assert_eq!(abundances[0].num_reads, 20);
+    //The ambiguous read is split by abundance: a = (19 + a) / 23.
+    assert!((abundances[0].abundance - 19. / 22.).abs() < 1e-3);
+    assert!((abundances[1].abundance - 3. / 22.).abs() < 1e-3);
+    for x in abundances.iter() {
+        assert!(x.abundance_ci.0 <= x.abundance && x.abundance <= x.abundance_ci.1);
+        assert!(x.abundance_ci.0 < x.abundance_ci.1);
+    }
+
+    //Same intervals on every run.
+    let again = abundance::get_haplotig_abundances(
+        &haplotigs,
+        &snp_endpoints,
+        &path_flows,
+        &path_coverages,
+        false,
+        &AbundanceSettings::default(),
+    );
+    assert_eq!(again[1].abundance_ci, abundances[1].abundance_ci);
+}
+
+#[test]
+fn read_log_likelihoods_test() {
+    //r5 is the only read of haplotig 1, so its own alleles leave haplotig 1 without a consensus.
+    let mut frags = vec![];
+    for i in 0..6 {
+        let mut frag = build_frag(format!("r{}", i), i, false, 0);
+        for pos in 1..5 {
+            frag.add_call(pos, if i < 5 { 0 } else { 1 }, 30, 0, 0);
+        }
+        frag.epsilon = 0.04;
+        frags.push(frag);
+    }
+    let haplotigs: Vec<FxHashSet<&Frag>> =
+        vec![frags[0..5].iter().collect(), frags[5..6].iter().collect()];
+    let log_likelihoods =
+        abundance::get_read_log_likelihoods(&haplotigs, &vec![(1, 4), (1, 4)], false);
+    let epsilon: f64 = 0.04;
+    //Haplotig 0 still has four other reads.
+    assert_eq!(log_likelihoods[0][0].0, 0);
+    assert!((log_likelihoods[0][0].1 - 4. * (1. - epsilon).ln()).abs() < 1e-9);
+    assert_eq!(log_likelihoods[5][1].0, 1);
+    assert!((log_likelihoods[5][1].1 - 4. * epsilon * epsilon.ln()).abs() < 1e-9);
+    assert!((log_likelihoods[5][0].1 - 4. * epsilon.ln()).abs() < 1e-9);
+}
@@ -1,6 +1,10 @@
 use assert_cmd::prelude::*; // Add methods on commands
 use predicates::prelude::*; // Used for writing assertions
 use std::process::Command; // Run programs
+use rust_htslib::{bam, bcf, bcf::Read};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
 
 #[test]
 fn three_ploidy_standard_test() {
@@ -66,3 +70,61 @@ fn multiple_ref_test(){
 
 }
 
+//Phased VCF of every contig in a glopp output directory.
+fn read_phased_vcfs(out_dir: &Path) -> BTreeMap<String, String> {
+    let mut phased_vcfs = BTreeMap::new();
+    for entry in fs::read_dir(out_dir).unwrap() {
+        let contig_dir = entry.unwrap().path();
+        let phased_vcf = contig_dir.join("phased.vcf");
+        if phased_vcf.exists() {
+            let contig = contig_dir.file_name().unwrap().to_str().unwrap().to_string();
+            phased_vcfs.insert(contig, fs::read_to_string(phased_vcf).unwrap());
+        }
+    }
+    phased_vcfs
+}
+
+#[test]
+fn stream_parallel_contigs_test(){
+    let test_dir = std::env::temp_dir().join("glopp_stream_parallel_contigs_test");
+    let _ = fs::remove_dir_all(&test_dir);
+    fs::create_dir_all(&test_dir).unwrap();
+
+    //--stream needs an indexed BAM and a bgzipped, indexed VCF.
+    let bam_file = test_dir.join("long.bam");
+    fs::copy("./tests/long-10305070.bam", &bam_file).unwrap();
+    bam::index::build(&bam_file, None, bam::index::Type::Bai, 1).unwrap();
+    let vcf_file = test_dir.join("long.vcf.gz");
+    {
+        let mut vcf = bcf::Reader::from_path("./tests/10kcutoff-lofreq_500k_unif.vcfc_header").unwrap();
+        let header = bcf::Header::from_template(vcf.header());
+        let mut writer = bcf::Writer::from_path(&vcf_file, &header, false, bcf::Format::Vcf).unwrap();
+        for rec in vcf.records() {
+            writer.write(&rec.unwrap()).unwrap();
+        }
+    }
+    let c_vcf_file = std::ffi::CString::new(vcf_file.to_str().unwrap()).unwrap();
+    assert_eq!(unsafe { rust_htslib::htslib::bcf_index_build(c_vcf_file.as_ptr(), 14) }, 0);
+
+    let mut phased_vcfs = vec![];
+    let options: [&[&str]; 4] = [&[], &["--stream"], &["--parallel-contigs"], &["--stream", "--parallel-contigs"]];
+    for (i, option) in options.iter().enumerate() {
+        let out_dir = test_dir.join(format!("out{}", i));
+        let mut cmd = Command::cargo_bin("glopp").unwrap();
+        cmd.arg("-b")
+            .arg(&bam_file)
+            .arg("-c")
+            .arg(&vcf_file)
+            .arg("-o")
+            .arg(&out_dir)
+            .args(*option)
+            .assert()
+            .success()
+            .code(0);
+        phased_vcfs.push(read_phased_vcfs(&out_dir));
+    }
+    assert!(!phased_vcfs[0].is_empty());
+    for i in 1..options.len() {
+        assert_eq!(phased_vcfs[0], phased_vcfs[i], "phased VCFs differ with {:?}", options[i]);
+    }
+}